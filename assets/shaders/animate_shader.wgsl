@@ -1,14 +1,16 @@
-#import bevy_pbr::forward_io::VertexOutput
-#import bevy_pbr::forward_io::Vertex
-#import bevy_pbr::mesh_functions::{get_world_from_local, mesh_position_local_to_clip}
+#import bevy_pbr::forward_io::{VertexOutput, FragmentOutput}
+#import bevy_pbr::mesh_functions::{get_world_from_local, mesh_position_local_to_world, mesh_normal_local_to_world}
+#import bevy_pbr::view_transformations::position_world_to_clip
+#import bevy_pbr::pbr_fragment::pbr_input_from_standard_material
+#import bevy_pbr::pbr_functions::{apply_pbr_lighting, main_pass_post_lighting_processing}
 #import bevy_shader_utils::simplex_noise_3d::simplex_noise_3d
 
 fn linear_conversion(
-    value: f32, 
+    value: f32,
     old_min: f32,
-    old_max: f32, 
+    old_max: f32,
     new_min: f32,
-    new_max: f32     
+    new_max: f32
 ) -> f32 {
     return (((value - old_min) * (new_max - new_min)) / (old_max - old_min)) + new_min;
 }
@@ -20,51 +22,124 @@ struct Material {
 @group(2) @binding(0)
 var<uniform> material: Material;
 
+// forward_io Vertex and VertexOutput plus the ground material of the trianglet, when the mesh has it.
+// chunk meshes always do, super chunk meshes only when every chunk merged into them did
+struct TerrainVertex {
+    @builtin(instance_index) instance_index: u32,
+    @location(0) position: vec3<f32>,
+#ifdef VERTEX_NORMALS
+    @location(1) normal: vec3<f32>,
+#endif
+#ifdef VERTEX_COLORS
+    @location(5) color: vec4<f32>,
+#endif
+#ifdef GROUND_MATERIAL
+    @location(8) ground_material: u32,
+#endif
+};
+
+struct TerrainVertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) world_position: vec4<f32>,
+    @location(1) world_normal: vec3<f32>,
+#ifdef VERTEX_COLORS
+    @location(5) color: vec4<f32>,
+#endif
+#ifdef VERTEX_OUTPUT_INSTANCE_INDEX
+    @location(6) @interpolate(flat) instance_index: u32,
+#endif
+#ifdef GROUND_MATERIAL
+    @location(8) @interpolate(flat) ground_material: u32,
+#endif
+};
+
 @vertex
-fn vertex(input: Vertex) -> VertexOutput {
+fn vertex(input: TerrainVertex) -> TerrainVertexOutput {
     // Initialize the output structure
-    var output: VertexOutput;
+    var output: TerrainVertexOutput;
 
-    output.position = mesh_position_local_to_clip(
-        get_world_from_local(input.instance_index),
-        vec4<f32>(input.position, 1.0)
-    );
-    output.world_normal = input.normal;
+    let world_from_local = get_world_from_local(input.instance_index);
+    output.world_position = mesh_position_local_to_world(world_from_local, vec4<f32>(input.position, 1.0));
+    output.position = position_world_to_clip(output.world_position.xyz);
+    // indexed meshes have no normals, the fragment shader works them out anyway
+#ifdef VERTEX_NORMALS
+    output.world_normal = mesh_normal_local_to_world(input.normal, input.instance_index);
+#else
+    output.world_normal = vec3<f32>(0.0, 1.0, 0.0);
+#endif
+
+#ifdef VERTEX_COLORS
+    output.color = input.color;
+#endif
+#ifdef VERTEX_OUTPUT_INSTANCE_INDEX
+    output.instance_index = input.instance_index;
+#endif
+#ifdef GROUND_MATERIAL
+    output.ground_material = input.ground_material;
+#endif
+
+    return output;
+}
 
+fn slope_color(normal: vec3<f32>) -> vec4<f32> {
     let grass = vec4<f32>(0.08, 0.2, 0.05, 1.0);
     let rock = vec4<f32>(0.1, 0.1, 0.1, 1.0);
 
-    let normalized_factor = 1.0 - linear_conversion(input.normal.y, 0.7, 0.8, 0.0, 1.0);
+    let normalized_factor = 1.0 - linear_conversion(normal.y, 0.7, 0.8, 0.0, 1.0);
 
-    if input.normal.y > 0.8 {
-        output.color = grass;
-    } else  if input.normal.y < 0.7 {
-        output.color = rock;
-    } else {
-        output.color = mix(grass, rock, normalized_factor);
+    if normal.y > 0.8 {
+        return grass;
+    } else if normal.y < 0.7 {
+        return rock;
     }
+    return mix(grass, rock, normalized_factor);
+}
 
-    // output.color = input.color;
-
-    // if angle <= 45.0 {
-    //     output.color = vec4<f32>(0.5, 1.0, 0.5, 1.0);
-    // } else {
-    //     output.color = vec4<f32>(1.0, 0.5, 0.5, 1.0);
-    // }
-
-    return output;
+// the ids of GroundMaterial: grass, rock, sand, snow, path
+fn ground_color(ground_material: u32, normal: vec3<f32>) -> vec4<f32> {
+    switch ground_material {
+        case 1u: { return vec4<f32>(0.1, 0.1, 0.1, 1.0); }
+        case 2u: { return vec4<f32>(0.45, 0.38, 0.22, 1.0); }
+        case 3u: { return vec4<f32>(0.85, 0.87, 0.9, 1.0); }
+        case 4u: { return vec4<f32>(0.22, 0.15, 0.08, 1.0); }
+        default: { return slope_color(normal); }
+    }
 }
 
 @fragment
 fn fragment(
-    in: VertexOutput
-) -> @location(0) vec4<f32> {
-    // let f: f32 = simplex_noise_3d(material.scale * in.world_position.xyz);
+    terrain_in: TerrainVertexOutput,
+    @builtin(front_facing) is_front: bool,
+) -> FragmentOutput {
+    var in: VertexOutput;
+    in.position = terrain_in.position;
+    in.world_position = terrain_in.world_position;
+    in.world_normal = terrain_in.world_normal;
+#ifdef VERTEX_COLORS
+    in.color = terrain_in.color;
+#endif
+#ifdef VERTEX_OUTPUT_INSTANCE_INDEX
+    in.instance_index = terrain_in.instance_index;
+#endif
+
+    // indexed chunk meshes share vertices between trianglets, so the flat face normal comes from
+    // the screen space derivatives of the position instead of the vertex normal.
+    // the terrain is a heightfield, so the normal always points up (lod skirts end up sideways).
+    var normal = normalize(cross(dpdy(in.world_position.xyz), dpdx(in.world_position.xyz)));
+    normal = select(normal, -normal, normal.y < 0.0);
 
-    // let color_a = vec3(1.0, 0.0, 0.0);
-    // let color_b = vec3(0.0, 1.0, 0.0);
-    // let mixed = mix(color_a, color_b, f);
-    // return vec4(mixed, 1.0);
+    var pbr_input = pbr_input_from_standard_material(in, is_front);
+#ifdef GROUND_MATERIAL
+    pbr_input.material.base_color = ground_color(terrain_in.ground_material, normal);
+#else
+    pbr_input.material.base_color = slope_color(normal);
+#endif
+    pbr_input.world_normal = normal;
+    pbr_input.N = normal;
 
-    return vec4(0.5, 0.5, 0.5, 1.0);
-}
\ No newline at end of file
+    var out: FragmentOutput;
+    out.color = apply_pbr_lighting(pbr_input);
+    out.color = main_pass_post_lighting_processing(pbr_input, out.color);
+
+    return out;
+}
@@ -0,0 +1,381 @@
+// headless world generator. runs the terrain generation for a region without a window, a gpu or a bevy app
+// and writes the chunk cache, map images and generation stats to a directory
+//
+//   cargo run --release --bin worldgen -- --seed 42 --center 0,0 --radius 256 --out worlds/42 --config worldgen.cfg
+//
+// pointing --out at the directory the game runs in pregenerates its chunk cache
+
+use std::{collections::HashMap, fmt::Write as _, fs, path::{Path, PathBuf}, sync::Arc, thread, time::{Duration, Instant}};
+
+use bevy::math::{DVec2, DVec3};
+
+use bevy_game::ingame::environment::{
+    chunk_cache::{ChunkCache, CACHE_DIR},
+    chunk_mesh::ChunkLattice,
+    ground_material::{classify_ground, GROUND_MATERIALS},
+    heightmap::{decode_heightmap, HeightmapFilter, HeightmapTerrain, OutsideHeightmap},
+    terrain::{fnv1a, generate_lattice, TerrainConfig},
+    terrain_export::{ExportChunk, ExportRegion},
+    terrain_maps::{map_size, write_map_images, MapSettings, RegionSampler},
+};
+use bevy_game::ingame::tricoord::*;
+
+const USAGE:&str = "usage: worldgen [options]
+  --seed <u32>              noise seed, 0 by default
+  --center <x,z>            middle of the region, 0,0 by default
+  --radius <units>          every chunk within this distance of the center, 128 by default
+  --chunks <a,b,c;a,b,c>    exactly these chunks instead of center and radius
+  --out <dir>               output directory, worldgen by default
+  --config <file>           key = value settings, the options above override them
+  --threads <n>             generation threads, all cores by default
+  --no-images               skip the map images
+
+config keys: seed, center, radius, threads, assets, heightmap, heightmap_origin, heightmap_pixel_size,
+  heightmap_height_min, heightmap_height_max, heightmap_filter (bilinear, bicubic),
+  heightmap_outside (noise or a height), map_pixel_size, map_height_min, map_height_max,
+  map_chunk_grid, histogram_bin";
+
+// height units per histogram bar
+const DEFAULT_HISTOGRAM_BIN:f32 = 5.0;
+const HISTOGRAM_WIDTH:usize = 50;
+// how many of the slowest chunks the stats list
+const SLOWEST_CHUNKS:usize = 5;
+
+struct WorldgenSettings {
+    seed: u32,
+    center: DVec2,
+    radius: f32,
+    chunks: Option<Vec<TriCoord<i32>>>,
+    out: PathBuf,
+    threads: usize,
+    images: bool,
+    // heightmap paths are asset paths like in the game, read from this directory
+    assets: PathBuf,
+    heightmap: Option<HeightmapTerrain>,
+    map: MapSettings,
+    histogram_bin: f32,
+}
+
+impl Default for WorldgenSettings {
+    fn default() -> Self {
+        WorldgenSettings {
+            seed: 0,
+            center: DVec2::ZERO,
+            radius: 128.0,
+            chunks: None,
+            out: PathBuf::from("worldgen"),
+            threads: thread::available_parallelism().map_or(1, |threads| threads.get()),
+            images: true,
+            assets: PathBuf::from("assets"),
+            heightmap: None,
+            map: MapSettings::default(),
+            histogram_bin: DEFAULT_HISTOGRAM_BIN,
+        }
+    }
+}
+
+struct GeneratedChunk {
+    tricoord: TriCoord<i32>,
+    lattice: ChunkLattice,
+    materials: Vec<u8>,
+    time: Duration,
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", USAGE);
+        return;
+    }
+    if let Err(error) = run(&args) {
+        eprintln!("worldgen: {}", error);
+        std::process::exit(1);
+    }
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let settings = parse_settings(args)?;
+
+    let mut terrain_config = TerrainConfig { seed: settings.seed, ..Default::default() };
+    if let Some(heightmap) = &settings.heightmap {
+        let path = settings.assets.join(&heightmap.path);
+        let bytes = fs::read(&path).map_err(|error| format!("could not read {}: {}", path.display(), error))?;
+        let grid = decode_heightmap(&bytes).map_err(|error| format!("{}: {}", path.display(), error))?;
+        terrain_config.heightmap = Some(HeightmapTerrain { grid: Some(Arc::new(grid)), ..heightmap.clone() });
+    }
+
+    let region = match &settings.chunks {
+        Some(chunks) => ExportRegion::Chunks(chunks.clone()),
+        None => ExportRegion::Around { center: DVec3::new(settings.center.x, 0.0, settings.center.y), radius: settings.radius },
+    };
+    let mut tricoords = region.tricoords();
+    tricoords.sort_by_key(|tricoord| (tricoord.a, tricoord.b, tricoord.c));
+    tricoords.dedup();
+    if tricoords.is_empty() {
+        return Err("the region has no chunks".to_string());
+    }
+    fs::create_dir_all(&settings.out).map_err(|error| format!("could not create {}: {}", settings.out.display(), error))?;
+
+    println!("generating {} chunks of seed {} on {} threads", tricoords.len(), settings.seed, settings.threads);
+    let started = Instant::now();
+    let generated = generate_chunks(&tricoords, &terrain_config, settings.threads);
+    let wall_time = started.elapsed();
+
+    // the same directory layout and files the game reads, keyed by the same generator hash
+    let generator_hash = terrain_config.generator_hash();
+    let mut chunk_cache = ChunkCache::open_in(&settings.out.join(CACHE_DIR), settings.seed, generator_hash);
+    if !chunk_cache.is_open() {
+        return Err(format!("could not open the chunk cache in {}", settings.out.display()));
+    }
+    for chunk in generated.iter() {
+        chunk_cache.store(chunk.tricoord, &chunk.lattice);
+    }
+    chunk_cache.close();
+
+    if settings.images {
+        let export_chunks: Vec<ExportChunk> = generated.iter()
+        .map(|chunk| ExportChunk { tricoord: chunk.tricoord, lattice: &chunk.lattice, materials: &chunk.materials })
+        .collect();
+        let sampler = RegionSampler::new(&export_chunks);
+        let (width, height, _) = map_size(&sampler, &settings.map);
+        let paths = write_map_images(&sampler, &settings.map, &settings.out.join("map"))
+        .map_err(|error| format!("could not write the map images: {}", error))?;
+        println!("wrote {} x {} map images: {}", width, height, paths.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", "));
+    }
+
+    let stats = format_stats(&settings, &generated, generator_hash, wall_time);
+    write_file(&settings.out.join("stats.txt"), stats.as_bytes())?;
+    write_file(&settings.out.join("chunk_times.csv"), format_chunk_times(&generated).as_bytes())?;
+    println!("{}", stats);
+    return Ok(());
+}
+
+// every thread takes every threads-th chunk, so the slow heightmap and fast noise chunks spread out.
+// the result is in the order of tricoords, whatever the thread count
+fn generate_chunks(tricoords: &[TriCoord<i32>], terrain_config: &TerrainConfig, threads: usize) -> Vec<GeneratedChunk> {
+    let threads = threads.clamp(1, tricoords.len());
+    let mut slots: Vec<Option<GeneratedChunk>> = (0..tricoords.len()).map(|_| None).collect();
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads).map(|worker| {
+            scope.spawn(move || {
+                tricoords.iter().enumerate().skip(worker).step_by(threads).map(|(index, tricoord)| {
+                    let chunk_started = Instant::now();
+                    let lattice = generate_lattice(*tricoord, terrain_config);
+                    let materials = classify_ground(&lattice);
+                    (index, GeneratedChunk { tricoord: *tricoord, lattice, materials, time: chunk_started.elapsed() })
+                })
+                .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+        for worker in workers {
+            for (index, chunk) in worker.join().expect("a generation thread panicked") {
+                slots[index] = Some(chunk);
+            }
+        }
+    });
+    return slots.into_iter().flatten().collect();
+}
+
+fn format_stats(settings: &WorldgenSettings, generated: &[GeneratedChunk], generator_hash: u64, wall_time: Duration) -> String {
+    let mut stats = String::new();
+    let milliseconds = |time: Duration| time.as_secs_f64() * 1000.0;
+
+    let _ = writeln!(stats, "seed {}, generator hash {:016x}", settings.seed, generator_hash);
+    match &settings.chunks {
+        Some(chunks) => { let _ = writeln!(stats, "region: {} listed chunks", chunks.len()); }
+        None => { let _ = writeln!(stats, "region: radius {} around {}, {}", settings.radius, settings.center.x, settings.center.y); }
+    }
+    if let Some(heightmap) = &settings.heightmap {
+        let _ = writeln!(stats, "heightmap: {}", heightmap.path);
+    }
+
+    // changes whenever any generated height does, for regression checks between builds
+    let mut digest_bytes = Vec::new();
+    for chunk in generated {
+        for value in [chunk.tricoord.a, chunk.tricoord.b, chunk.tricoord.c] {
+            digest_bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        for height in chunk.lattice.heights.iter() {
+            digest_bytes.extend_from_slice(&height.to_le_bytes());
+        }
+    }
+    let _ = writeln!(stats, "heights digest {:016x}", fnv1a(&digest_bytes));
+
+    let mut times: Vec<Duration> = generated.iter().map(|chunk| chunk.time).collect();
+    times.sort();
+    let total: Duration = times.iter().sum();
+    let _ = writeln!(stats);
+    let _ = writeln!(stats, "chunks {}, wall time {:.1} ms on {} threads", generated.len(), milliseconds(wall_time), settings.threads);
+    let _ = writeln!(stats, "generation time per chunk: mean {:.3} ms, median {:.3} ms, min {:.3} ms, max {:.3} ms",
+        milliseconds(total) / generated.len().max(1) as f64,
+        times.get(times.len() / 2).map_or(0.0, |time| milliseconds(*time)),
+        times.first().map_or(0.0, |time| milliseconds(*time)),
+        times.last().map_or(0.0, |time| milliseconds(*time)),
+    );
+    let mut slowest: Vec<&GeneratedChunk> = generated.iter().collect();
+    slowest.sort_by(|a, b| b.time.cmp(&a.time));
+    for chunk in slowest.iter().take(SLOWEST_CHUNKS) {
+        let _ = writeln!(stats, "  {} {} {}: {:.3} ms", chunk.tricoord.a, chunk.tricoord.b, chunk.tricoord.c, milliseconds(chunk.time));
+    }
+
+    // every lattice vertex counts once per chunk, so border vertices are in it two or three times.
+    // a NaN or infinite height has no bin, it is counted apart so a broken generator still gets its stats
+    let (heights, non_finite): (Vec<f32>, Vec<f32>) = generated.iter()
+    .flat_map(|chunk| chunk.lattice.heights.iter().copied())
+    .partition(|height| height.is_finite());
+    let min = heights.iter().copied().fold(f32::MAX, f32::min);
+    let max = heights.iter().copied().fold(f32::MIN, f32::max);
+    let mean = heights.iter().map(|height| *height as f64).sum::<f64>() / heights.len().max(1) as f64;
+    let _ = writeln!(stats);
+    let _ = writeln!(stats, "heights: {} vertices, min {:.2}, max {:.2}, mean {:.2}", heights.len(), min, max, mean);
+    if !non_finite.is_empty() {
+        let _ = writeln!(stats, "  {} vertices are NaN or infinite and left out", non_finite.len());
+    }
+
+    let bin = settings.histogram_bin.max(0.01);
+    if !heights.is_empty() {
+        let first_bin = (min / bin).floor() as i64;
+        let mut bins: Vec<usize> = vec![0; ((max / bin).floor() as i64 - first_bin + 1).max(1) as usize];
+        for height in heights.iter() {
+            bins[((height / bin).floor() as i64 - first_bin) as usize] += 1;
+        }
+        let largest = bins.iter().copied().max().unwrap_or(1).max(1);
+        for (index, count) in bins.iter().enumerate() {
+            let low = (first_bin + index as i64) as f32 * bin;
+            let bar = "#".repeat((count * HISTOGRAM_WIDTH).div_ceil(largest));
+            let _ = writeln!(stats, "  {:>8.1} .. {:>8.1} {:>9} {}", low, low + bin, count, bar);
+        }
+    }
+
+    let mut ground: HashMap<u8, usize> = HashMap::new();
+    for id in generated.iter().flat_map(|chunk| chunk.materials.iter()) {
+        *ground.entry(*id).or_default() += 1;
+    }
+    let trianglets = ground.values().sum::<usize>().max(1);
+    let _ = writeln!(stats);
+    let _ = writeln!(stats, "ground:");
+    for material in GROUND_MATERIALS {
+        let count = ground.get(&material.id()).copied().unwrap_or(0);
+        let _ = writeln!(stats, "  {:<6} {:>9} {:>5.1}%", format!("{:?}", material), count, count as f64 * 100.0 / trianglets as f64);
+    }
+    return stats;
+}
+
+fn format_chunk_times(generated: &[GeneratedChunk]) -> String {
+    let mut csv = String::from("a,b,c,milliseconds,min_height,max_height\n");
+    for chunk in generated {
+        let min = chunk.lattice.heights.iter().copied().fold(f32::MAX, f32::min);
+        let max = chunk.lattice.heights.iter().copied().fold(f32::MIN, f32::max);
+        let _ = writeln!(csv, "{},{},{},{:.3},{:.3},{:.3}", chunk.tricoord.a, chunk.tricoord.b, chunk.tricoord.c, chunk.time.as_secs_f64() * 1000.0, min, max);
+    }
+    return csv;
+}
+
+fn write_file(path: &Path, bytes: &[u8]) -> Result<(), String> {
+    fs::write(path, bytes).map_err(|error| format!("could not write {}: {}", path.display(), error))
+}
+
+// the config file first, then the command line on top of it
+fn parse_settings(args: &[String]) -> Result<WorldgenSettings, String> {
+    let mut settings = WorldgenSettings::default();
+
+    let mut options: Vec<(String, String)> = Vec::new();
+    let mut config_path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let Some(key) = arg.strip_prefix("--") else {
+            return Err(format!("unexpected argument {}\n{}", arg, USAGE));
+        };
+        if key == "no-images" {
+            settings.images = false;
+            continue;
+        }
+        let value = args.next().ok_or_else(|| format!("--{} needs a value\n{}", key, USAGE))?;
+        match key {
+            "config" => config_path = Some(PathBuf::from(value)),
+            "seed" | "center" | "radius" | "chunks" | "out" | "threads" => options.push((key.to_string(), value.clone())),
+            _ => return Err(format!("unknown option --{}\n{}", key, USAGE)),
+        }
+    }
+
+    if let Some(path) = config_path {
+        let text = fs::read_to_string(&path).map_err(|error| format!("could not read {}: {}", path.display(), error))?;
+        for (line_number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or_else(|| format!("{}:{}: expected key = value", path.display(), line_number + 1))?;
+            apply_setting(&mut settings, key.trim(), value.trim())
+            .map_err(|error| format!("{}:{}: {}", path.display(), line_number + 1, error))?;
+        }
+    }
+    for (key, value) in options {
+        apply_setting(&mut settings, &key, &value).map_err(|error| format!("--{}: {}", key, error))?;
+    }
+    if settings.heightmap.as_ref().is_some_and(|heightmap| heightmap.path.is_empty()) {
+        return Err("the heightmap settings need a heightmap path".to_string());
+    }
+    return Ok(settings);
+}
+
+fn apply_setting(settings: &mut WorldgenSettings, key: &str, value: &str) -> Result<(), String> {
+    match key {
+        "seed" => settings.seed = parse(value)?,
+        "center" => settings.center = parse_pair(value)?,
+        "radius" => settings.radius = parse(value)?,
+        "chunks" => settings.chunks = Some(value.split(';').map(parse_tricoord).collect::<Result<_, _>>()?),
+        "out" => settings.out = PathBuf::from(value),
+        "threads" => settings.threads = parse::<usize>(value)?.max(1),
+        "assets" => settings.assets = PathBuf::from(value),
+        "heightmap" => heightmap(settings).path = value.to_string(),
+        "heightmap_origin" => heightmap(settings).origin = parse_pair(value)?,
+        "heightmap_pixel_size" => heightmap(settings).pixel_size = parse(value)?,
+        "heightmap_height_min" => heightmap(settings).height_min = parse(value)?,
+        "heightmap_height_max" => heightmap(settings).height_max = parse(value)?,
+        "heightmap_filter" => heightmap(settings).filter = match value {
+            "bilinear" => HeightmapFilter::Bilinear,
+            "bicubic" => HeightmapFilter::Bicubic,
+            _ => return Err(format!("unknown filter {}, bilinear or bicubic", value)),
+        },
+        "heightmap_outside" => heightmap(settings).outside = match value {
+            "noise" => OutsideHeightmap::Noise,
+            height => OutsideHeightmap::Flat(parse(height)?),
+        },
+        "map_pixel_size" => settings.map.pixel_size = parse(value)?,
+        "map_height_min" => settings.map.height_min = parse(value)?,
+        "map_height_max" => settings.map.height_max = parse(value)?,
+        "map_chunk_grid" => settings.map.chunk_grid = parse(value)?,
+        "histogram_bin" => settings.histogram_bin = parse(value)?,
+        _ => return Err(format!("unknown setting {}", key)),
+    }
+    return Ok(());
+}
+
+// the heightmap settings need a heightmap to go on, the path can come after them
+fn heightmap(settings: &mut WorldgenSettings) -> &mut HeightmapTerrain {
+    settings.heightmap.get_or_insert_with(|| HeightmapTerrain::new(""))
+}
+
+fn parse<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value.trim().parse().map_err(|_| format!("could not parse {}", value))
+}
+
+fn parse_pair(value: &str) -> Result<DVec2, String> {
+    let (x, z) = value.split_once(',').ok_or_else(|| format!("expected x,z, got {}", value))?;
+    return Ok(DVec2::new(parse(x)?, parse(z)?));
+}
+
+fn parse_tricoord(value: &str) -> Result<TriCoord<i32>, String> {
+    let parts: Vec<i32> = value.split(',').map(parse).collect::<Result<_, _>>()?;
+    let [a, b, c] = parts[..] else {
+        return Err(format!("expected a,b,c, got {}", value));
+    };
+    let tricoord = TriCoord { a, b, c };
+    // even chunks add up to 0 and odd chunks to 1
+    if !(0..=1).contains(&tricoord.sum()) {
+        return Err(format!("{},{},{} is not a chunk, a + b + c has to be 0 or 1", a, b, c));
+    }
+    return Ok(tricoord);
+}
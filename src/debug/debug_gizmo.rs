@@ -1,8 +1,7 @@
 use bevy::{color::palettes::css::*, prelude::*};
-use bevy_fps_controller::controller::LogicalPlayer;
 use std::f32::consts::PI;
 
-use crate::ingame::{environment::terrain::{Chunks, TerrainConfig}, tricoord::{trichunk_to_coord, tricoord_vec_gen_distance, Coord}};
+use crate::ingame::{environment::terrain::{ChunkStatus, Chunks, TerrainConfig}, floating_origin::FloatingOrigin};
 
 use super::TriBool;
 
@@ -58,30 +57,27 @@ fn origin_gizmo(
 
 fn chunks_gizmo(
     mut gizmos: Gizmos, 
-    query: Query<&Transform, With<LogicalPlayer>>,
     chunks: Res<Chunks>,
-    terrain_config: Res<TerrainConfig>
-) /* -> Vec<TriCoord<i16>> */ {
-    let player_transform = query.get_single().unwrap();
-
-    let gen_origin = player_transform.translation; 
-
-    gizmos.circle(gen_origin.with_y(0.0), Dir3::Y, terrain_config.chunk_gen_radius, BLACK);
-
-    let in_radius_tricoords = tricoord_vec_gen_distance(Coord {x:gen_origin.x, z:gen_origin.z}, terrain_config.chunk_gen_radius);
-
-    for tricoord in chunks.generating.clone() {
-        let chunk_coord = trichunk_to_coord(tricoord, 0);
-        gizmos.cuboid(Transform::from_xyz(chunk_coord.x as f32, 0.0, chunk_coord.z as f32), ORANGE);
+    terrain_config: Res<TerrainConfig>,
+    floating_origin: Res<FloatingOrigin>
+) {
+    for anchor in chunks.anchors.iter() {
+        let anchor_position = floating_origin.to_local(anchor.position).with_y(0.0);
+        gizmos.circle(anchor_position, Dir3::Y, anchor.radius, BLACK);
+        gizmos.circle(anchor_position, Dir3::Y, anchor.radius + terrain_config.unload_hysteresis, GRAY);
     }
 
-    for tricoord in chunks.generated.clone() {
-        let chunk_coord = trichunk_to_coord(tricoord, 0);
-        if in_radius_tricoords.contains(&tricoord) {
-            gizmos.cuboid(Transform::from_xyz(chunk_coord.x as f32, 0.0, chunk_coord.z as f32), RED);
-        } else {
-            gizmos.cuboid(Transform::from_xyz(chunk_coord.x as f32, 0.0, chunk_coord.z as f32), BLUE);
-        }
+    for (tricoord, state) in chunks.states.iter() {
+        let chunk_position = floating_origin.chunk_to_local(*tricoord);
+        let color = match state.status {
+            ChunkStatus::Queued => YELLOW,
+            ChunkStatus::Generating | ChunkStatus::Meshed => ORANGE,
+            // spawned chunks outside the radius are kept until they pass the unload hysteresis
+            ChunkStatus::Spawned if state.in_range => RED,
+            ChunkStatus::Spawned => BLUE,
+            ChunkStatus::Unloading => BLACK,
+        };
+        gizmos.cuboid(Transform::from_translation(chunk_position), color);
     }
 
 }
\ No newline at end of file
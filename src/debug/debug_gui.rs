@@ -3,7 +3,7 @@ use bevy_egui::{egui::{self, panel, FontId, RichText}, EguiContext, EguiContexts
 use bevy_fps_controller::controller::LogicalPlayer;
 use bevy_inspector_egui::{inspector_options::ReflectInspectorOptions, InspectorOptions};
 
-use crate::ingame::{environment::terrain::{SelectedTerrainMat, TerrainConfig, TerrainHandles}, tricoord::{halfsides_altitude_to_tricoord, Coord, TriCoord, CHUNK_ALTITUDE, CHUNK_HALFSIDE, CHUNK_SIDE}};
+use crate::ingame::{floating_origin::{AbsolutePosition, FloatingOrigin}, environment::{chunk_cache::ChunkCache, chunk_mesh::MeshLayout, ground_material::{GroundContact, GroundMaterial, GROUND_MATERIALS}, heightmap::{HeightmapFilter, HeightmapImport, HeightmapTerrain, OutsideHeightmap}, sculpt::{Brush, SculptConfig, BRUSHES}, terrain_export::{ExportFormat, ExportRegion, ExportTerrain, TerrainExportConfig}, terrain_maps::{ExportMapImages, MapSettings}, terrain_edits::{TerrainEdits, TERRAIN_EDITS_PATH}, super_chunk::SuperChunks, terrain::{ChunkStatus, Chunks, RemeshQueue, TerrainConfig, TerrainStats, MAX_REFINE_DEPTH}, terrain_render::{SelectedTerrainMat, TerrainHandles}}, tricoord::{Coord, TriCoord, CHUNK_ALTITUDE, CHUNK_HALFSIDE, CHUNK_SIDE}};
 use crate::debug::debug_gizmo::GizmoConfig;
 
 use super::{debug_oneshots::OneShotSystems, TriBool};
@@ -34,9 +34,12 @@ impl Default for DebugPanelConfig {
 #[derive(Reflect, Resource, InspectorOptions)]
 #[reflect(Resource, InspectorOptions)]
 struct DebugToolsData {
-    player_coord: Coord<f32>,
-    player_halfside_altitude: (i16, i16),
-    player_chunk_tricoord: TriCoord<i16>,
+    // absolute, the transform is relative to the floating origin
+    player_coord: Coord<f64>,
+    player_local_coord: Coord<f32>,
+    player_halfside_altitude: (i32, i32),
+    player_chunk_tricoord: TriCoord<i32>,
+    player_ground: Option<GroundMaterial>,
 }
 
 impl Default for DebugToolsData {
@@ -44,7 +47,9 @@ impl Default for DebugToolsData {
         DebugToolsData { 
             player_halfside_altitude: (0,0),
             player_coord: Coord { z: 0.0, x: 0.0 },
+            player_local_coord: Coord { z: 0.0, x: 0.0 },
             player_chunk_tricoord: TriCoord { a:0, b:0, c:0 },
+            player_ground: None,
         }
     }
 }
@@ -119,13 +124,21 @@ fn right_panel(
     tools_data: Res<DebugToolsData>,
     // mut wireframe_config: ResMut<WireframeConfig>,
     mut terrain_config: ResMut<TerrainConfig>,
+    terrain_stats: Res<TerrainStats>,
+    super_chunks: Res<SuperChunks>,
+    chunks: Res<Chunks>,
+    chunk_cache: Res<ChunkCache>,
+    mut floating_origin: ResMut<FloatingOrigin>,
     mut selected_mat: ResMut<SelectedTerrainMat>,
     mut terrain_hdls: ResMut<TerrainHandles>,
     debug_oneshots: Res<OneShotSystems>,
     mut commands: Commands,
-    mut gizmo_config: ResMut<GizmoConfig>
+    mut gizmo_config: ResMut<GizmoConfig>,
+    // grouped, systems take at most 16 parameters
+    (mut sculpt_config, remesh_queue, mut terrain_edits, mut heightmap_import, mut export_config, mut export_events, mut map_settings, mut map_events): (ResMut<SculptConfig>, Res<RemeshQueue>, ResMut<TerrainEdits>, ResMut<HeightmapImport>, ResMut<TerrainExportConfig>, EventWriter<ExportTerrain>, ResMut<MapSettings>, EventWriter<ExportMapImages>)
 ) {
     if panel_config.hidden {
+        sculpt_config.pointer_over_ui = false;
         return;
     }
 
@@ -142,6 +155,21 @@ fn right_panel(
                 ui.code(format!("({:.02}, {:.02})",tools_data.player_coord.z, tools_data.player_coord.x));
             });
 
+            ui.horizontal(|ui| {
+                ui.label("Local (z,x)");
+                ui.code(format!("({:.02}, {:.02})",tools_data.player_local_coord.z, tools_data.player_local_coord.x));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Floating origin (z,x)");
+                ui.code(format!("({:.01}, {:.01}), {} recenters", floating_origin.offset.z, floating_origin.offset.x, floating_origin.recenters));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Recenter distance");
+                ui.add(egui::Slider::new(&mut floating_origin.recenter_distance, 64.0..=8192.0));
+            });
+
             ui.horizontal(|ui| {
                 ui.label("Chunk coordinates, (halfsides & altitude)");
                 ui.code(format!("({}, {})",tools_data.player_halfside_altitude.0, tools_data.player_halfside_altitude.1));
@@ -151,7 +179,14 @@ fn right_panel(
                 ui.label("Chunk coordinates, tricoord (a,b,c)");
                 ui.code(format!("({}, {}, {})",tools_data.player_chunk_tricoord.a, tools_data.player_chunk_tricoord.b, tools_data.player_chunk_tricoord.c));
             });
-            ui.label(RichText::new("*tricoords are not chunk-border accurate for chunks in horizontal direction").font(FontId::proportional(10.0)));
+
+            ui.horizontal(|ui| {
+                ui.label("Ground (friction, footsteps)");
+                match tools_data.player_ground {
+                    Some(ground) => ui.code(format!("{:?} ({:.01}, {})", ground, ground.friction(), ground.footstep())),
+                    None => ui.code("none"),
+                };
+            });
             
 
             ui.separator();
@@ -167,6 +202,375 @@ fn right_panel(
                 ui.add(egui::Slider::new(&mut terrain_config.chunk_gen_radius, 0.0..=400.0));
             });
 
+            ui.horizontal(|ui| {
+                ui.label("Terrain anchors");
+                ui.code(format!("{}", chunks.anchors.len()));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Chunk unload hysteresis");
+                ui.add(egui::Slider::new(&mut terrain_config.unload_hysteresis, 0.0..=64.0));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Max generation tasks");
+                ui.add(egui::Slider::new(&mut terrain_config.max_generation_tasks, 1..=64));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Max chunk spawns per frame");
+                ui.add(egui::Slider::new(&mut terrain_config.max_spawns_per_frame, 1..=64));
+            });
+
+            #[cfg(target_arch = "wasm32")]
+            ui.horizontal(|ui| {
+                ui.label("Generation budget per frame (ms)");
+                ui.add(egui::Slider::new(&mut terrain_config.wasm_frame_budget_ms, 1.0..=32.0));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("View direction priority");
+                ui.add(egui::Slider::new(&mut terrain_config.view_priority, 0.0..=1.0));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Chunk mesh layout");
+                egui::ComboBox::from_id_source("mesh_layout")
+                .selected_text(format!("{:?}", terrain_config.mesh_layout))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut terrain_config.mesh_layout, MeshLayout::Flat, "Flat");
+                    ui.selectable_value(&mut terrain_config.mesh_layout, MeshLayout::Indexed, "Indexed");
+                });
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Mesh memory per chunk");
+                ui.code(format!("{:.02} KiB", terrain_stats.bytes_per_chunk() as f32 / 1024.0));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Mesh memory total");
+                ui.code(format!("{:.02} MiB ({} chunks)", terrain_stats.mesh_bytes as f32 / (1024.0 * 1024.0), terrain_stats.meshed_chunks));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Chunks queued / generating / meshed / spawned / unloading");
+            });
+            ui.code(format!("{} / {} / {} / {} / {}",
+                chunks.count(ChunkStatus::Queued),
+                chunks.count(ChunkStatus::Generating),
+                chunks.count(ChunkStatus::Meshed),
+                chunks.count(ChunkStatus::Spawned),
+                chunks.count(ChunkStatus::Unloading)
+            ));
+
+            ui.horizontal(|ui| {
+                ui.label("Chunks unloaded");
+                ui.code(format!("{}", terrain_stats.unloaded_chunks));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Chunk cache");
+                ui.add(toggle(&mut terrain_config.chunk_cache));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Chunk cache hits / misses");
+                ui.code(format!("{} / {}", chunk_cache.hits, chunk_cache.misses));
+            });
+            ui.label(RichText::new("*the layout applies to chunks generated after it is changed").font(FontId::proportional(10.0)));
+
+            for (level, lod_distance) in terrain_config.lod_distances.iter_mut().enumerate() {
+                ui.horizontal(|ui| {
+                    ui.label(format!("LOD {} distance ({} trianglets)", level + 1, CHUNK_SIDE >> (level + 1)));
+                    ui.add(egui::Slider::new(lod_distance, 0.0..=400.0));
+                });
+            }
+
+            ui.horizontal(|ui| {
+                ui.label("LOD hysteresis");
+                ui.add(egui::Slider::new(&mut terrain_config.lod_hysteresis, 0.0..=32.0));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Refine radius");
+                ui.add(egui::Slider::new(&mut terrain_config.refine_radius, 0.0..=64.0));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Refine depth");
+                ui.add(egui::Slider::new(&mut terrain_config.refine_depth, 0..=MAX_REFINE_DEPTH));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Detail amplitude");
+                ui.add(egui::Slider::new(&mut terrain_config.detail_amplitude, 0.0..=2.0));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Super chunk distance");
+                ui.add(egui::Slider::new(&mut terrain_config.superchunk_distance, 0.0..=400.0));
+            });
+
+            ui.horizontal(|ui| {
+                let (groups, batched_chunks) = super_chunks.drawn();
+                ui.label("Super chunks drawn");
+                ui.code(format!("{} ({} chunks)", groups, batched_chunks));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Simplify flat regions");
+                ui.add(toggle(&mut terrain_config.simplify));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Simplify error");
+                ui.add(egui::Slider::new(&mut terrain_config.simplify_error, 0.0..=2.0));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Vertex reduction");
+                let vertices = terrain_stats.vertices;
+                ui.code(format!("{:.01}% ({} -> {} vertices)", vertices.reduction() * 100.0, vertices.full, vertices.simplified));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Player chunk reduction");
+                let vertices = chunks.states.get(&tools_data.player_chunk_tricoord).map(|state| state.vertices).unwrap_or_default();
+                ui.code(format!("{:.01}% ({} -> {} vertices)", vertices.reduction() * 100.0, vertices.full, vertices.simplified));
+            });
+
+            ui.separator();
+            ui.heading("Sculpting");
+
+            ui.horizontal(|ui| {
+                ui.label("Edit mode");
+                ui.add(toggle(&mut sculpt_config.active));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Brush");
+                egui::ComboBox::from_id_source("sculpt_brush")
+                .selected_text(format!("{:?}", sculpt_config.brush))
+                .show_ui(ui, |ui| {
+                    for brush in BRUSHES {
+                        ui.selectable_value(&mut sculpt_config.brush, brush, format!("{:?}", brush));
+                    }
+                });
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Brush radius");
+                ui.add(egui::Slider::new(&mut sculpt_config.radius, 0.5..=32.0));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Brush strength");
+                ui.add(egui::Slider::new(&mut sculpt_config.strength, 0.1..=32.0));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Target height");
+                ui.add(egui::Slider::new(&mut sculpt_config.target_height, 0.0..=100.0));
+            });
+
+            ui.add_enabled_ui(sculpt_config.brush == Brush::Paint, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Paint material");
+                    egui::ComboBox::from_id_source("paint_material")
+                    .selected_text(format!("{:?}", sculpt_config.paint_material))
+                    .show_ui(ui, |ui| {
+                        for ground in GROUND_MATERIALS {
+                            ui.selectable_value(&mut sculpt_config.paint_material, ground, format!("{:?}", ground));
+                        }
+                    });
+                });
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Remesh budget per frame (ms)");
+                ui.add(egui::Slider::new(&mut terrain_config.remesh_budget_ms, 0.5..=16.0));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Chunks waiting for a remesh");
+                ui.code(format!("{}", remesh_queue.len()));
+            });
+            ui.label(RichText::new("*hold the left mouse button to sculpt, under the crosshair while the mouse is locked").font(FontId::proportional(10.0)));
+
+            ui.horizontal(|ui| {
+                if ui.add_enabled(terrain_edits.can_undo(), egui::Button::new("Undo")).clicked() {
+                    terrain_edits.undo();
+                }
+                if ui.add_enabled(terrain_edits.can_redo(), egui::Button::new("Redo")).clicked() {
+                    terrain_edits.redo();
+                }
+                if ui.button("Save edits").clicked() {
+                    match terrain_edits.save(std::path::Path::new(TERRAIN_EDITS_PATH), &terrain_config) {
+                        Ok(()) => info!("saved terrain edits to {}", TERRAIN_EDITS_PATH),
+                        Err(error) => warn!("could not save terrain edits: {}", error),
+                    }
+                }
+                if ui.button("Load edits").clicked() {
+                    match terrain_edits.load(std::path::Path::new(TERRAIN_EDITS_PATH), &terrain_config) {
+                        Ok(()) => info!("loaded terrain edits from {}", TERRAIN_EDITS_PATH),
+                        Err(error) => warn!("could not load terrain edits: {}", error),
+                    }
+                }
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Edited chunks / vertices / painted trianglets");
+                ui.code(format!("{} / {} / {}", terrain_edits.edited_chunks(), terrain_edits.edited_vertices(), terrain_edits.painted_trianglets()));
+            });
+            ui.label(RichText::new("*ctrl+z undoes a stroke, ctrl+y or ctrl+shift+z redoes it").font(FontId::proportional(10.0)));
+
+            ui.separator();
+            ui.heading("Heightmap");
+
+            let settings = &mut heightmap_import.settings;
+            ui.horizontal(|ui| {
+                ui.label("Image (in assets)");
+                ui.text_edit_singleline(&mut settings.path);
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Origin x, z");
+                ui.add(egui::DragValue::new(&mut settings.origin.x).speed(1.0));
+                ui.add(egui::DragValue::new(&mut settings.origin.y).speed(1.0));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Pixel size");
+                ui.add(egui::Slider::new(&mut settings.pixel_size, 0.1..=32.0).logarithmic(true));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Height range");
+                ui.add(egui::DragValue::new(&mut settings.height_min).speed(1.0));
+                ui.add(egui::DragValue::new(&mut settings.height_max).speed(1.0));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Filter");
+                egui::ComboBox::from_id_source("heightmap_filter")
+                .selected_text(format!("{:?}", settings.filter))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut settings.filter, HeightmapFilter::Bilinear, "Bilinear");
+                    ui.selectable_value(&mut settings.filter, HeightmapFilter::Bicubic, "Bicubic");
+                });
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Outside the image");
+                let mut flat = matches!(settings.outside, OutsideHeightmap::Flat(_));
+                ui.radio_value(&mut flat, false, "Noise");
+                ui.radio_value(&mut flat, true, "Flat");
+                settings.outside = match (flat, settings.outside) {
+                    (true, OutsideHeightmap::Flat(height)) => OutsideHeightmap::Flat(height),
+                    (true, OutsideHeightmap::Noise) => OutsideHeightmap::Flat(settings.height_min),
+                    (false, _) => OutsideHeightmap::Noise,
+                };
+                if let OutsideHeightmap::Flat(height) = &mut settings.outside {
+                    ui.add(egui::DragValue::new(height).speed(1.0));
+                }
+            });
+
+            ui.horizontal(|ui| {
+                if ui.button("Use heightmap").clicked() {
+                    // the same image is not loaded again
+                    let grid = terrain_config.heightmap.as_ref()
+                    .filter(|current| current.path == heightmap_import.settings.path)
+                    .and_then(|current| current.grid.clone());
+                    terrain_config.heightmap = Some(HeightmapTerrain { grid, ..heightmap_import.settings.clone() });
+                }
+                if ui.add_enabled(terrain_config.heightmap.is_some(), egui::Button::new("Use noise")).clicked() {
+                    terrain_config.heightmap = None;
+                }
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Square tiling next to it");
+                ui.add(toggle(&mut heightmap_import.square_reference));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Current");
+                match &terrain_config.heightmap {
+                    Some(heightmap) => match &heightmap.grid {
+                        Some(grid) => ui.code(format!("{} ({} x {})", heightmap.path, grid.width, grid.height)),
+                        None => ui.code(format!("{} (loading)", heightmap.path)),
+                    },
+                    None => ui.code("noise"),
+                };
+            });
+            ui.label(RichText::new("*8 or 16 bit grayscale png, applying it generates every chunk again").font(FontId::proportional(10.0)));
+
+            ui.separator();
+            ui.heading("Export");
+
+            ui.horizontal(|ui| {
+                ui.label("Format");
+                egui::ComboBox::from_id_source("export_format")
+                .selected_text(format!("{:?}", export_config.format))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut export_config.format, ExportFormat::Glb, "Glb");
+                    ui.selectable_value(&mut export_config.format, ExportFormat::Obj, "Obj");
+                });
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Radius");
+                ui.add(egui::Slider::new(&mut export_config.radius, CHUNK_SIDE as f32..=1024.0));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Submesh per chunk");
+                ui.add(toggle(&mut export_config.chunk_submeshes));
+            });
+
+            ui.horizontal(|ui| {
+                let mut region = None;
+                if ui.button("Export around player").clicked() {
+                    let center = bevy::math::DVec3::new(tools_data.player_coord.x, 0.0, tools_data.player_coord.z);
+                    region = Some(ExportRegion::Around { center, radius: export_config.radius });
+                }
+                if ui.button("Export player chunk").clicked() {
+                    if let Ok(tricoord) = crate::ingame::tricoord::coord_to_trichunk(Coord { x: tools_data.player_coord.x, z: tools_data.player_coord.z }) {
+                        region = Some(ExportRegion::Chunks(vec![tricoord]));
+                    }
+                }
+                if let Some(region) = region {
+                    export_events.send(ExportTerrain { region, format: export_config.format, chunk_submeshes: export_config.chunk_submeshes });
+                }
+            });
+            ui.label(RichText::new("*spawned chunks only, written to exports/ at full resolution").font(FontId::proportional(10.0)));
+
+            ui.horizontal(|ui| {
+                ui.label("Map pixel size");
+                ui.add(egui::Slider::new(&mut map_settings.pixel_size, 0.05..=4.0).logarithmic(true));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Map height range");
+                ui.add(egui::DragValue::new(&mut map_settings.height_min).speed(1.0));
+                ui.add(egui::DragValue::new(&mut map_settings.height_max).speed(1.0));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Chunk grid on maps");
+                ui.add(toggle(&mut map_settings.chunk_grid));
+            });
+
+            if ui.button("Map images around player").clicked() {
+                let center = bevy::math::DVec3::new(tools_data.player_coord.x, 0.0, tools_data.player_coord.z);
+                map_events.send(ExportMapImages {
+                    region: ExportRegion::Around { center, radius: export_config.radius },
+                    settings: map_settings.clone(),
+                });
+            }
+            ui.label(RichText::new("*height, relief and colored png, rendered on the cpu").font(FontId::proportional(10.0)));
+
             ui.separator();
             ui.heading("Gizmos");
 
@@ -197,6 +601,8 @@ fn right_panel(
                     }
                 }
             });
+            // the standard material lights with the vertex normals, which only the flat layout has
+            ui.label("shiny needs the flat mesh layout");
 
             ui.separator();
             ui.heading("Inspector panel");
@@ -216,6 +622,8 @@ fn right_panel(
         .response
         .rect
         .width();
+
+    sculpt_config.pointer_over_ui = ctx.is_pointer_over_area();
 }
 
 fn toggle(on: &mut bool) -> impl egui::Widget + '_ {
@@ -254,13 +662,16 @@ fn debug_inputs(key: Res<ButtonInput<KeyCode>>, mut debug_panels: ResMut<DebugPa
     }
 }
 
-fn update_tools_data(mut tools_data: ResMut<DebugToolsData>, query: Query<& Transform, With<LogicalPlayer>>) {
-    let transform = query.single();
-    let (x,z) = (transform.translation.x, transform.translation.z);
+fn update_tools_data(mut tools_data: ResMut<DebugToolsData>, query: Query<(&Transform, &AbsolutePosition, Option<&GroundContact>), With<LogicalPlayer>>) {
+    let Ok((transform, absolute, ground_contact)) = query.get_single() else {
+        return;
+    };
+    tools_data.player_local_coord = Coord { z: transform.translation.z, x: transform.translation.x };
+    let (x,z) = (absolute.position.x, absolute.position.z);
     tools_data.player_coord = Coord { z: z, x: x };
     // tools_data.player_chunk_tricoord = Coord { 
-    //     z: flooring_division(transform.translation.z as i16, CHUNK_SIDE as i16), 
-    //     x: flooring_division(transform.translation.x as i16, CHUNK_SIDE as i16) 
+    //     z: flooring_division(transform.translation.z as i32, CHUNK_SIDE as i32), 
+    //     x: flooring_division(transform.translation.x as i32, CHUNK_SIDE as i32) 
     // };
     let floor_or_ceil = |value:f64| {
         if value > 0.0 { // positive so floor it
@@ -290,12 +701,14 @@ fn update_tools_data(mut tools_data: ResMut<DebugToolsData>, query: Query<& Tran
         ) / CHUNK_ALTITUDE 
     ) as i32;
     
-    tools_data.player_halfside_altitude = (halfsides as i16, altitudes as i16);
+    tools_data.player_halfside_altitude = (halfsides, altitudes);
 
-    tools_data.player_chunk_tricoord = halfsides_altitude_to_tricoord(halfsides, altitudes);
+    // exact on the chunk borders, unlike the rounded halfsides
+    tools_data.player_chunk_tricoord = absolute.tricoord;
+    tools_data.player_ground = ground_contact.and_then(|contact| contact.material);
 }
 
-fn flooring_division(dividend: i16, divisor: i16) -> i16 {
+fn flooring_division(dividend: i32, divisor: i32) -> i32 {
     // Perform the division
     let quotient = dividend / divisor;
     let remainder = dividend % divisor;
@@ -2,7 +2,7 @@ use std::any::TypeId;
 
 use bevy::{ecs::system::SystemId, pbr::ExtendedMaterial, prelude::*, utils::HashMap};
 
-use crate::ingame::environment::terrain::{MyMaterial, SelectedTerrainMat, TerrainHandles, TerrainMesh};
+use crate::ingame::environment::{super_chunk::SuperChunkMesh, terrain::TerrainMesh, terrain_render::{MyMaterial, SelectedTerrainMat, TerrainHandles}};
 
 pub struct DebugOneShotsPlugin;
 
@@ -33,7 +33,7 @@ fn change_material(
     mut commands: Commands,
     terrain_hdls: Res<TerrainHandles>,
     selected_mat: Res<SelectedTerrainMat>,
-    mut query: Query<Entity, With<TerrainMesh>>,
+    mut query: Query<Entity, Or<(With<TerrainMesh>, With<SuperChunkMesh>)>>,
 ) {
     for entity in query.iter_mut() {
         commands.entity(entity).remove::<Handle<StandardMaterial>>();
@@ -0,0 +1,418 @@
+use std::{collections::{HashMap, HashSet}, fs, io, path::{Path, PathBuf}, task::Poll, time::{Duration, SystemTime}};
+
+use bevy::{prelude::*, time::common_conditions::on_timer};
+use bevy::tasks::futures_lite::future;
+use bevy::tasks::{block_on, AsyncComputeTaskPool, Task};
+
+use crate::ingame::tricoord::*;
+
+use super::chunk_format::{decode_chunk, encode_chunk, ByteReader, ChunkRecord};
+use super::chunk_mesh::ChunkLattice;
+use super::terrain::TerrainConfig;
+
+pub struct ChunkCachePlugin;
+
+impl Plugin for ChunkCachePlugin {
+    fn build(&self, app: &mut App) {
+        app
+        .init_resource::<ChunkCache>()
+        // before the generation systems look anything up this frame
+        .add_systems(PreUpdate, reopen_chunk_cache)
+        .add_systems(Update, save_chunk_cache.run_if(on_timer(Duration::from_secs(5))))
+        .add_systems(Last, close_chunk_cache.run_if(on_event::<AppExit>()))
+        ;
+    }
+}
+
+// region files hold REGION_SIZE x REGION_SIZE chunks along the a and b axes
+const REGION_SIZE:i32 = 16;
+// clean regions are dropped from memory past this many, they are read again from disk when needed
+const MAX_LOADED_REGIONS:usize = 64;
+
+// cache directories of other seeds and generator configs are kept for switching back to them,
+// the ones not opened for this long go, and then the least recently opened until the rest fits
+const MAX_UNUSED_AGE:Duration = Duration::from_secs(30 * 24 * 60 * 60);
+const MAX_CACHE_BYTES:u64 = 512 * 1024 * 1024;
+// touched every time a cache directory is opened
+const LAST_USED_FILE:&str = "last_used";
+
+const REGION_MAGIC:&[u8; 4] = b"TRCH";
+const REGION_VERSION:u16 = 2;
+
+type RegionKey = (i32, i32);
+type Region = HashMap<TriCoord<i32>, ChunkLattice>;
+
+fn region_key(tricoord: TriCoord<i32>) -> RegionKey {
+    (tricoord.a.div_euclid(REGION_SIZE), tricoord.b.div_euclid(REGION_SIZE))
+}
+
+// generated chunk heights on disk, so chunks that were visited before are loaded instead of generated again.
+// every seed and generator config hash gets its own directory of region files.
+// native only, there is no file system on wasm and the cache just stays closed there.
+// the chunk format quantizes the heights, so a cached chunk can sit a fraction of a millimeter off
+// a freshly generated neighbor. the skirts cover that.
+#[derive(Resource, Default)]
+pub struct ChunkCache {
+    // the directory of the open cache, None when it is closed
+    dir: Option<PathBuf>,
+    config_hash: u64,
+    regions: HashMap<RegionKey, Region>,
+    // region files being read and decoded in the background, so a cold region never stalls a frame
+    reading: HashMap<RegionKey, Task<Region>>,
+    // chunks stored while their region was not in memory, they go into it once it is read
+    stored: HashMap<RegionKey, Region>,
+    // regions with chunks that are not on disk yet
+    dirty: HashSet<RegionKey>,
+    pub hits: usize,
+    pub misses: usize,
+}
+
+// relative to the directory the game runs in
+pub const CACHE_DIR:&str = "cache/terrain";
+
+#[cfg(not(target_arch = "wasm32"))]
+fn cache_root() -> Option<PathBuf> {
+    Some(PathBuf::from(CACHE_DIR))
+}
+
+#[cfg(target_arch = "wasm32")]
+fn cache_root() -> Option<PathBuf> {
+    None
+}
+
+impl ChunkCache {
+    pub fn open(seed: u32, config_hash: u64) -> Self {
+        let Some(root) = cache_root() else {
+            return ChunkCache::default();
+        };
+        return ChunkCache::open_in(&root, seed, config_hash);
+    }
+
+    // a cache under any directory, the world generator writes into the cache/terrain of the game it generates for
+    pub fn open_in(root: &Path, seed: u32, config_hash: u64) -> Self {
+        let dir = root.join(format!("{}-{:016x}", seed, config_hash));
+        if let Err(error) = fs::create_dir_all(&dir).and_then(|_| fs::write(dir.join(LAST_USED_FILE), [])) {
+            warn!("chunk cache disabled, could not create {}: {}", dir.display(), error);
+            return ChunkCache::default();
+        }
+        evict_unused_caches(root, &dir, MAX_CACHE_BYTES);
+        return ChunkCache { dir: Some(dir), config_hash, ..default() };
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.dir.is_some()
+    }
+
+    fn region_path(&self, key: RegionKey) -> Option<PathBuf> {
+        self.dir.as_ref().map(|dir| dir.join(format!("r.{}.{}.bin", key.0, key.1)))
+    }
+
+    // true once the region is in memory, otherwise it starts reading it in the background or checks on the read.
+    // without a task pool, like in the world generator, the region is read right away
+    fn poll_region(&mut self, key: RegionKey) -> bool {
+        if !self.regions.contains_key(&key) {
+            let Some(path) = self.region_path(key) else {
+                return false;
+            };
+            if let Some(task) = self.reading.get_mut(&key) {
+                let Some(region) = block_on(future::poll_once(task)) else {
+                    return false;
+                };
+                self.reading.remove(&key);
+                self.regions.insert(key, region);
+            } else if let Some(task_pool) = AsyncComputeTaskPool::try_get() {
+                let config_hash = self.config_hash;
+                self.reading.insert(key, task_pool.spawn(async move {
+                    read_region(&path, config_hash)
+                }));
+                return false;
+            } else {
+                self.regions.insert(key, read_region(&path, self.config_hash));
+            }
+        }
+        self.merge_stored(key);
+        return true;
+    }
+
+    // the stored chunks are newer than the ones read from disk
+    fn merge_stored(&mut self, key: RegionKey) {
+        let (Some(stored), Some(region)) = (self.stored.remove(&key), self.regions.get_mut(&key)) else {
+            return;
+        };
+        region.extend(stored);
+        self.dirty.insert(key);
+    }
+
+    // Pending while the region of the chunk is read in the background, the caller asks again in a later frame
+    pub fn load(&mut self, tricoord: TriCoord<i32>) -> Poll<Option<ChunkLattice>> {
+        if !self.is_open() {
+            return Poll::Ready(None);
+        }
+        let key = region_key(tricoord);
+        if !self.poll_region(key) {
+            return Poll::Pending;
+        }
+        let lattice = self.regions.get(&key).and_then(|region| region.get(&tricoord)).cloned();
+        match lattice {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1,
+        }
+        return Poll::Ready(lattice);
+    }
+
+    // a region that is not in memory is read in the background first, the chunk waits in stored until then
+    pub fn store(&mut self, tricoord: TriCoord<i32>, lattice: &ChunkLattice) {
+        if !self.is_open() {
+            return;
+        }
+        let key = region_key(tricoord);
+        self.stored.entry(key).or_default().insert(tricoord, lattice.clone());
+        self.poll_region(key);
+    }
+
+    // writes the dirty regions to disk. chunks whose region is still being read are written by a later save
+    pub fn save(&mut self) {
+        let waiting: Vec<RegionKey> = self.stored.keys().copied().collect();
+        for key in waiting {
+            self.poll_region(key);
+        }
+
+        for key in std::mem::take(&mut self.dirty) {
+            let (Some(path), Some(region)) = (self.region_path(key), self.regions.get(&key)) else {
+                continue;
+            };
+            if let Err(error) = write_region(&path, self.config_hash, region) {
+                warn!("could not write chunk cache region {}: {}", path.display(), error);
+            }
+        }
+
+        if self.regions.len() > MAX_LOADED_REGIONS {
+            self.regions.clear();
+        }
+    }
+
+    // waits for the regions stored chunks are waiting on and saves everything, before the cache is closed
+    pub fn close(&mut self) {
+        let waiting: Vec<RegionKey> = self.stored.keys().copied().collect();
+        for key in waiting {
+            if !self.regions.contains_key(&key) {
+                let region = match (self.reading.remove(&key), self.region_path(key)) {
+                    (Some(task), _) => block_on(task),
+                    (None, Some(path)) => read_region(&path, self.config_hash),
+                    (None, None) => Region::new(),
+                };
+                self.regions.insert(key, region);
+            }
+            self.merge_stored(key);
+        }
+        self.save();
+    }
+}
+
+// removes the cache directories under root that were not opened for MAX_UNUSED_AGE, then the least
+// recently opened ones until all of them fit in max_bytes. the one just opened always stays
+fn evict_unused_caches(root: &Path, current: &Path, max_bytes: u64) {
+    let Ok(entries) = fs::read_dir(root) else {
+        return;
+    };
+    let mut caches: Vec<(PathBuf, SystemTime, u64)> = entries.flatten()
+    .map(|entry| entry.path())
+    .filter(|path| path.is_dir())
+    .map(|path| {
+        let last_used = fs::metadata(path.join(LAST_USED_FILE)).or_else(|_| fs::metadata(&path))
+        .and_then(|metadata| metadata.modified())
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+        let bytes = directory_bytes(&path);
+        (path, last_used, bytes)
+    })
+    .collect();
+    caches.sort_by_key(|(_, last_used, _)| *last_used);
+
+    let mut total_bytes: u64 = caches.iter().map(|(_, _, bytes)| bytes).sum();
+    let now = SystemTime::now();
+    for (path, last_used, bytes) in caches {
+        if path == current {
+            continue;
+        }
+        let unused = now.duration_since(last_used).unwrap_or_default() > MAX_UNUSED_AGE;
+        if !unused && total_bytes <= max_bytes {
+            continue;
+        }
+        info!("removing unused chunk cache {}", path.display());
+        if fs::remove_dir_all(&path).is_ok() {
+            total_bytes -= bytes;
+        }
+    }
+}
+
+fn directory_bytes(path: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(path) else {
+        return 0;
+    };
+    entries.flatten()
+    .filter_map(|entry| entry.metadata().ok())
+    .filter(|metadata| metadata.is_file())
+    .map(|metadata| metadata.len())
+    .sum()
+}
+
+// region file, little endian:
+// magic, version u16, config hash u64, chunk count u32, then the chunks back to back in the chunk format
+fn write_region(path: &PathBuf, config_hash: u64, region: &Region) -> io::Result<()> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(REGION_MAGIC);
+    bytes.extend_from_slice(&REGION_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&config_hash.to_le_bytes());
+    bytes.extend_from_slice(&(region.len() as u32).to_le_bytes());
+
+    for (tricoord, lattice) in region.iter() {
+        bytes.extend(encode_chunk(&ChunkRecord { tricoord: *tricoord, lattice: lattice.clone(), materials: None, biomes: None }));
+    }
+
+    // written next to it and renamed, so a crash never leaves half a region behind
+    let temp_path = path.with_extension("tmp");
+    fs::write(&temp_path, bytes)?;
+    return fs::rename(temp_path, path);
+}
+
+// a missing, broken or stale region file is read as an empty region, its chunks are generated again
+fn read_region(path: &PathBuf, config_hash: u64) -> Region {
+    let Ok(bytes) = fs::read(path) else {
+        return Region::new();
+    };
+    match parse_region(&bytes, config_hash) {
+        Some(region) => region,
+        None => {
+            info!("ignoring stale chunk cache region {}", path.display());
+            Region::new()
+        }
+    }
+}
+
+fn parse_region(bytes: &[u8], config_hash: u64) -> Option<Region> {
+    let mut reader = ByteReader::new(bytes);
+    if reader.take(4)? != REGION_MAGIC || reader.u16()? != REGION_VERSION || reader.u64()? != config_hash {
+        return None;
+    }
+
+    let count = reader.u32()?;
+    let mut chunk_bytes = &bytes[reader.position..];
+    let mut region = Region::new();
+    for _ in 0..count {
+        let (record, read) = match decode_chunk(chunk_bytes) {
+            Ok(decoded) => decoded,
+            Err(error) => {
+                warn!("chunk cache region: {}", error);
+                return None;
+            }
+        };
+        region.insert(record.tricoord, record.lattice);
+        chunk_bytes = &chunk_bytes[read..];
+    }
+    return Some(region);
+}
+
+// opens the cache for the current seed and generator config, and again whenever they change
+fn reopen_chunk_cache(
+    mut chunk_cache: ResMut<ChunkCache>,
+    terrain_config: Res<TerrainConfig>
+) {
+    if !terrain_config.is_changed() {
+        return;
+    }
+    let config_hash = terrain_config.generator_hash();
+    if chunk_cache.is_open() == terrain_config.chunk_cache && (!chunk_cache.is_open() || chunk_cache.config_hash == config_hash) {
+        return;
+    }
+
+    chunk_cache.close();
+    let (hits, misses) = (chunk_cache.hits, chunk_cache.misses);
+    *chunk_cache = if terrain_config.chunk_cache {
+        ChunkCache::open(terrain_config.seed, config_hash)
+    } else {
+        ChunkCache::default()
+    };
+    chunk_cache.hits = hits;
+    chunk_cache.misses = misses;
+}
+
+fn save_chunk_cache(mut chunk_cache: ResMut<ChunkCache>) {
+    chunk_cache.save();
+}
+
+fn close_chunk_cache(mut chunk_cache: ResMut<ChunkCache>) {
+    chunk_cache.close();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::chunk_mesh::test_lattice;
+
+    fn test_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("chunk_cache_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        root
+    }
+
+    #[test]
+    fn opening_another_hash_keeps_the_old_cache() {
+        let root = test_root("hashes");
+        let tricoord = TriCoord { a: 2, b: -1, c: 0 };
+
+        let mut chunk_cache = ChunkCache::open_in(&root, 1, 0xaa);
+        let stored = test_lattice(false);
+        chunk_cache.store(tricoord, &stored);
+        chunk_cache.close();
+        ChunkCache::open_in(&root, 1, 0xbb).close();
+
+        let mut chunk_cache = ChunkCache::open_in(&root, 1, 0xaa);
+        let loaded = chunk_cache.load(tricoord);
+        let _ = fs::remove_dir_all(&root);
+
+        let Poll::Ready(Some(lattice)) = loaded else {
+            panic!("the chunk of the first hash is gone");
+        };
+        // within the quantization of the chunk format
+        assert!(lattice.heights.iter().zip(stored.heights.iter()).all(|(loaded, stored)| (loaded - stored).abs() < 0.001));
+    }
+
+    #[test]
+    fn stored_chunks_go_into_regions_read_later() {
+        let root = test_root("stored");
+        let (first, second) = (TriCoord { a: 0, b: 0, c: 0 }, TriCoord { a: 1, b: 0, c: 0 });
+
+        let mut chunk_cache = ChunkCache::open_in(&root, 1, 0xaa);
+        chunk_cache.store(first, &test_lattice(false));
+        chunk_cache.close();
+
+        // the region is on disk but not in memory, the new chunk has to end up next to the old one
+        let mut chunk_cache = ChunkCache::open_in(&root, 1, 0xaa);
+        chunk_cache.store(second, &test_lattice(true));
+        chunk_cache.close();
+
+        let mut chunk_cache = ChunkCache::open_in(&root, 1, 0xaa);
+        let loaded = (chunk_cache.load(first), chunk_cache.load(second));
+        let _ = fs::remove_dir_all(&root);
+
+        assert!(matches!(loaded, (Poll::Ready(Some(_)), Poll::Ready(Some(_)))));
+    }
+
+    #[test]
+    fn least_recently_opened_caches_are_evicted_past_the_size_limit() {
+        let root = test_root("evict");
+        for name in ["1-oldest", "2-older", "1-current"] {
+            fs::create_dir_all(root.join(name)).unwrap();
+            fs::write(root.join(name).join("r.0.0.bin"), vec![0; 100]).unwrap();
+            fs::write(root.join(name).join(LAST_USED_FILE), []).unwrap();
+            // far enough apart for any file system's timestamps
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        evict_unused_caches(&root, &root.join("1-current"), 250);
+        let exists = ["1-oldest", "2-older", "1-current"].map(|name| root.join(name).exists());
+        let _ = fs::remove_dir_all(&root);
+
+        assert_eq!(exists, [false, true, true]);
+    }
+}
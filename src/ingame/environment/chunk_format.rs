@@ -0,0 +1,418 @@
+use crate::ingame::tricoord::*;
+
+use super::chunk_mesh::{lattice_vertex_count, ChunkLattice};
+
+// the serialized form of a chunk, shared by the chunk cache, save files and network transfer.
+//
+// little endian:
+// magic "TRCK", format version u16, tricoord a b c as i32, flags u8, lattice side u16,
+// height offset f32, height scale f32, payload length u32, payload.
+// the flags are bit 0 odd chunk, bit 1 has a material layer, bit 2 has a biome layer.
+// the payload is deflate compressed and holds the heights quantized to u16 as offset + q * scale,
+// delta coded row by row like the lattice and written as zigzag varints,
+// then one byte per trianglet for every layer the flags say is there.
+//
+// the quantization is lossy, decoded heights are within half a scale step of the encoded ones.
+const CHUNK_MAGIC:&[u8; 4] = b"TRCK";
+pub const CHUNK_FORMAT_VERSION:u16 = 1;
+
+const FLAG_ODD:u8 = 1;
+const FLAG_MATERIALS:u8 = 1 << 1;
+const FLAG_BIOMES:u8 = 1 << 2;
+
+// larger lattices than this are rejected instead of allocated, refined chunks are 64 trianglets per side
+const MAX_SIDE:u16 = 1024;
+
+#[derive(Clone, Debug)]
+pub struct ChunkRecord {
+    pub tricoord: TriCoord<i32>,
+    pub lattice: ChunkLattice,
+    // one id per trianglet, side * side of them, in the order the lattice triangles are walked
+    pub materials: Option<Vec<u8>>,
+    pub biomes: Option<Vec<u8>>,
+}
+
+// why a serialized chunk could not be read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChunkFormatError {
+    // does not start with the chunk magic, it is not a chunk at all
+    BadMagic,
+    // written by a newer build with a format this one does not know
+    UnsupportedVersion(u16),
+    // the data ends before the chunk does
+    Truncated,
+    // a lattice side of 0 or one too large to be a chunk
+    InvalidSide(u16),
+    // the payload does not inflate, or its size does not match the header
+    CorruptPayload,
+}
+
+impl std::fmt::Display for ChunkFormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChunkFormatError::BadMagic => write!(f, "not a serialized chunk"),
+            ChunkFormatError::UnsupportedVersion(version) => write!(f, "unsupported chunk format version {}", version),
+            ChunkFormatError::Truncated => write!(f, "chunk data is truncated"),
+            ChunkFormatError::InvalidSide(side) => write!(f, "invalid chunk lattice side {}", side),
+            ChunkFormatError::CorruptPayload => write!(f, "chunk payload is corrupt"),
+        }
+    }
+}
+
+impl std::error::Error for ChunkFormatError {}
+
+pub fn trianglet_count(side: usize) -> usize {
+    side * side
+}
+
+pub fn encode_chunk(record: &ChunkRecord) -> Vec<u8> {
+    let lattice = &record.lattice;
+    let (offset, scale) = quantization(&lattice.heights);
+
+    let mut payload = Vec::with_capacity(lattice.heights.len() * 2);
+    let mut previous:i32 = 0;
+    for height in lattice.heights.iter() {
+        let quantized = if scale > 0.0 {
+            ((height - offset) / scale).round().clamp(0.0, u16::MAX as f32) as i32
+        } else {
+            0
+        };
+        write_varint(&mut payload, zigzag(quantized - previous));
+        previous = quantized;
+    }
+
+    let mut flags = 0;
+    if lattice.odd {
+        flags |= FLAG_ODD;
+    }
+    for (layer, flag) in [(&record.materials, FLAG_MATERIALS), (&record.biomes, FLAG_BIOMES)] {
+        if let Some(layer) = layer {
+            // a layer of the wrong size would not decode, so it is padded or cut to the trianglet count
+            let mut layer = layer.clone();
+            layer.resize(trianglet_count(lattice.side), 0);
+            payload.extend_from_slice(&layer);
+            flags |= flag;
+        }
+    }
+    let payload = miniz_oxide::deflate::compress_to_vec(&payload, 6);
+
+    let mut bytes = Vec::with_capacity(payload.len() + 36);
+    bytes.extend_from_slice(CHUNK_MAGIC);
+    bytes.extend_from_slice(&CHUNK_FORMAT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&record.tricoord.a.to_le_bytes());
+    bytes.extend_from_slice(&record.tricoord.b.to_le_bytes());
+    bytes.extend_from_slice(&record.tricoord.c.to_le_bytes());
+    bytes.push(flags);
+    bytes.extend_from_slice(&(lattice.side as u16).to_le_bytes());
+    bytes.extend_from_slice(&offset.to_le_bytes());
+    bytes.extend_from_slice(&scale.to_le_bytes());
+    bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    return bytes;
+}
+
+// reads one chunk from the start of bytes, returns it and how many bytes it took
+pub fn decode_chunk(bytes: &[u8]) -> Result<(ChunkRecord, usize), ChunkFormatError> {
+    let mut reader = ByteReader::new(bytes);
+    if reader.take(4).ok_or(ChunkFormatError::Truncated)? != CHUNK_MAGIC {
+        return Err(ChunkFormatError::BadMagic);
+    }
+    let version = reader.u16().ok_or(ChunkFormatError::Truncated)?;
+    if version != CHUNK_FORMAT_VERSION {
+        return Err(ChunkFormatError::UnsupportedVersion(version));
+    }
+
+    let header = (|| Some((
+        TriCoord { a: reader.i32()?, b: reader.i32()?, c: reader.i32()? },
+        reader.u8()?,
+        reader.u16()?,
+        reader.f32()?,
+        reader.f32()?,
+        reader.u32()? as usize,
+    )))();
+    let (tricoord, flags, side, offset, scale, payload_length) = header.ok_or(ChunkFormatError::Truncated)?;
+    if side == 0 || side > MAX_SIDE {
+        return Err(ChunkFormatError::InvalidSide(side));
+    }
+    let payload = reader.take(payload_length).ok_or(ChunkFormatError::Truncated)?;
+
+    let side = side as usize;
+    let vertex_count = lattice_vertex_count(side);
+    let layer_count = [FLAG_MATERIALS, FLAG_BIOMES].iter().filter(|flag| flags & **flag != 0).count();
+    // varints are at most 3 bytes for a u16 delta, anything past that is not a chunk
+    let max_payload = vertex_count * 3 + layer_count * trianglet_count(side);
+    let payload = miniz_oxide::inflate::decompress_to_vec_with_limit(payload, max_payload)
+    .map_err(|_| ChunkFormatError::CorruptPayload)?;
+
+    let mut payload_reader = ByteReader::new(&payload);
+    let mut heights = Vec::with_capacity(vertex_count);
+    let mut previous:i32 = 0;
+    for _ in 0..vertex_count {
+        let delta = payload_reader.varint().ok_or(ChunkFormatError::CorruptPayload)?;
+        let quantized = previous + unzigzag(delta);
+        if !(0..=u16::MAX as i32).contains(&quantized) {
+            return Err(ChunkFormatError::CorruptPayload);
+        }
+        heights.push(offset + quantized as f32 * scale);
+        previous = quantized;
+    }
+
+    let mut read_layer = |flag: u8| -> Result<Option<Vec<u8>>, ChunkFormatError> {
+        if flags & flag == 0 {
+            return Ok(None);
+        }
+        let layer = payload_reader.take(trianglet_count(side)).ok_or(ChunkFormatError::CorruptPayload)?;
+        Ok(Some(layer.to_vec()))
+    };
+    let materials = read_layer(FLAG_MATERIALS)?;
+    let biomes = read_layer(FLAG_BIOMES)?;
+    if payload_reader.position != payload.len() {
+        return Err(ChunkFormatError::CorruptPayload);
+    }
+
+    let lattice = ChunkLattice { side, odd: flags & FLAG_ODD != 0, heights };
+    return Ok((ChunkRecord { tricoord, lattice, materials, biomes }, reader.position));
+}
+
+// the lowest height and the step between two quantized heights
+fn quantization(heights: &[f32]) -> (f32, f32) {
+    let min = heights.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = heights.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if !min.is_finite() || !max.is_finite() {
+        return (0.0, 0.0);
+    }
+    return (min, (max - min) / u16::MAX as f32);
+}
+
+// small negative deltas become small numbers too, so they fit in one varint byte
+fn zigzag(value: i32) -> u32 {
+    ((value << 1) ^ (value >> 31)) as u32
+}
+
+fn unzigzag(value: u32) -> i32 {
+    (value >> 1) as i32 ^ -((value & 1) as i32)
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        bytes.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+// reads little endian numbers off the front of a byte slice, None once it runs out
+pub struct ByteReader<'a> {
+    bytes: &'a [u8],
+    pub position: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        ByteReader { bytes, position: 0 }
+    }
+
+    pub fn take(&mut self, count: usize) -> Option<&'a [u8]> {
+        let slice = self.bytes.get(self.position..self.position.checked_add(count)?)?;
+        self.position += count;
+        return Some(slice);
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.take(2)?.try_into().ok()?))
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    pub fn i32(&mut self) -> Option<i32> {
+        Some(i32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    pub fn f32(&mut self) -> Option<f32> {
+        Some(f32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn varint(&mut self) -> Option<u32> {
+        let mut value:u32 = 0;
+        for shift in (0..32).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as u32) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::chunk_mesh::test_lattice;
+
+    // magic, version, tricoord, flags, side, offset, scale and payload length
+    const HEADER_LENGTH:usize = 33;
+
+    fn test_record() -> ChunkRecord {
+        let lattice = test_lattice(true);
+        let count = trianglet_count(lattice.side);
+        ChunkRecord {
+            tricoord: TriCoord { a: -70000, b: 12, c: 69989 },
+            lattice,
+            materials: Some((0..count).map(|index| (index % 5) as u8).collect()),
+            biomes: None,
+        }
+    }
+
+    // the chunk of test_record with its payload swapped for another one, compressed like encode_chunk does it
+    fn with_payload(raw_payload: &[u8]) -> Vec<u8> {
+        let mut bytes = encode_chunk(&test_record());
+        bytes.truncate(HEADER_LENGTH);
+        let payload = miniz_oxide::deflate::compress_to_vec(raw_payload, 6);
+        bytes[HEADER_LENGTH - 4..].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+        bytes.extend(payload);
+        bytes
+    }
+
+    fn raw_payload() -> Vec<u8> {
+        let bytes = encode_chunk(&test_record());
+        miniz_oxide::inflate::decompress_to_vec(&bytes[HEADER_LENGTH..]).unwrap()
+    }
+
+    #[test]
+    fn round_trip_keeps_header_and_layers() {
+        let record = test_record();
+        let bytes = encode_chunk(&record);
+        let (decoded, read) = decode_chunk(&bytes).unwrap();
+
+        assert_eq!(read, bytes.len());
+        assert_eq!(decoded.tricoord, record.tricoord);
+        assert_eq!(decoded.lattice.side, record.lattice.side);
+        assert_eq!(decoded.lattice.odd, record.lattice.odd);
+        assert_eq!(decoded.materials, record.materials);
+        assert_eq!(decoded.biomes, None);
+    }
+
+    #[test]
+    fn round_trip_heights_within_quantization_step() {
+        let record = test_record();
+        let (_, scale) = quantization(&record.lattice.heights);
+        let (decoded, _) = decode_chunk(&encode_chunk(&record)).unwrap();
+
+        assert_eq!(decoded.lattice.heights.len(), record.lattice.heights.len());
+        for (decoded, original) in decoded.lattice.heights.iter().zip(record.lattice.heights.iter()) {
+            assert!((decoded - original).abs() <= scale * 0.5 + f32::EPSILON * original.abs());
+        }
+    }
+
+    #[test]
+    fn round_trip_flat_chunk() {
+        let mut record = test_record();
+        record.lattice.heights.iter_mut().for_each(|height| *height = 12.5);
+        let (decoded, _) = decode_chunk(&encode_chunk(&record)).unwrap();
+
+        assert!(decoded.lattice.heights.iter().all(|height| *height == 12.5));
+    }
+
+    #[test]
+    fn is_smaller_than_raw_heights() {
+        let record = test_record();
+        let bytes = encode_chunk(&record);
+        assert!(bytes.len() < record.lattice.heights.len() * 4);
+    }
+
+    #[test]
+    fn decodes_chunks_back_to_back() {
+        let mut bytes = encode_chunk(&test_record());
+        let first_length = bytes.len();
+        let lod_lattice = test_lattice(false).downsampled(2);
+        bytes.extend(encode_chunk(&ChunkRecord { tricoord: TriCoord { a: 0, b: 0, c: 0 }, lattice: lod_lattice, materials: None, biomes: None }));
+
+        let (_, read) = decode_chunk(&bytes).unwrap();
+        assert_eq!(read, first_length);
+        let (second, _) = decode_chunk(&bytes[read..]).unwrap();
+        assert_eq!(second.lattice.side, 8);
+        assert!(!second.lattice.odd);
+    }
+
+    #[test]
+    fn rejects_other_data() {
+        let mut bytes = encode_chunk(&test_record());
+        bytes[0] = b'X';
+        assert_eq!(decode_chunk(&bytes).unwrap_err(), ChunkFormatError::BadMagic);
+        assert_eq!(decode_chunk(b"nope and more").unwrap_err(), ChunkFormatError::BadMagic);
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let mut bytes = encode_chunk(&test_record());
+        bytes[4..6].copy_from_slice(&(CHUNK_FORMAT_VERSION + 1).to_le_bytes());
+        assert_eq!(decode_chunk(&bytes).unwrap_err(), ChunkFormatError::UnsupportedVersion(CHUNK_FORMAT_VERSION + 1));
+    }
+
+    #[test]
+    fn every_cut_short_chunk_is_truncated() {
+        let bytes = encode_chunk(&test_record());
+        for length in 0..bytes.len() {
+            assert_eq!(decode_chunk(&bytes[..length]).unwrap_err(), ChunkFormatError::Truncated, "cut at {}", length);
+        }
+    }
+
+    #[test]
+    fn rejects_impossible_sides() {
+        for side in [0, MAX_SIDE + 1, u16::MAX] {
+            let mut bytes = encode_chunk(&test_record());
+            bytes[19..21].copy_from_slice(&side.to_le_bytes());
+            assert_eq!(decode_chunk(&bytes).unwrap_err(), ChunkFormatError::InvalidSide(side));
+        }
+    }
+
+    #[test]
+    fn rejects_payloads_that_do_not_inflate() {
+        let mut bytes = encode_chunk(&test_record());
+        bytes[HEADER_LENGTH..].iter_mut().for_each(|byte| *byte = 0xff);
+        assert_eq!(decode_chunk(&bytes).unwrap_err(), ChunkFormatError::CorruptPayload);
+    }
+
+    #[test]
+    fn rejects_payloads_of_the_wrong_size() {
+        let payload = raw_payload();
+        let layer_start = payload.len() - trianglet_count(CHUNK_SIDE as usize);
+
+        // heights cut off in the middle of the lattice
+        assert_eq!(decode_chunk(&with_payload(&payload[..layer_start / 2])).unwrap_err(), ChunkFormatError::CorruptPayload);
+        // the flags promise a material layer that is not there
+        assert_eq!(decode_chunk(&with_payload(&payload[..layer_start])).unwrap_err(), ChunkFormatError::CorruptPayload);
+        // bytes after the last layer
+        let mut longer = payload.clone();
+        longer.push(0);
+        assert_eq!(decode_chunk(&with_payload(&longer)).unwrap_err(), ChunkFormatError::CorruptPayload);
+        // far more than any lattice of this side could inflate to
+        assert_eq!(decode_chunk(&with_payload(&vec![0; payload.len() * 4])).unwrap_err(), ChunkFormatError::CorruptPayload);
+    }
+
+    #[test]
+    fn rejects_heights_outside_the_quantization_range() {
+        let mut payload = raw_payload();
+        // the first delta goes below zero
+        payload[0] = zigzag(-1) as u8;
+        assert_eq!(decode_chunk(&with_payload(&payload)).unwrap_err(), ChunkFormatError::CorruptPayload);
+    }
+
+    #[test]
+    fn zigzag_round_trip() {
+        for value in [0, 1, -1, 300, -300, u16::MAX as i32, -(u16::MAX as i32)] {
+            assert_eq!(unzigzag(zigzag(value)), value);
+        }
+    }
+}
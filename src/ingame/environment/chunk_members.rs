@@ -0,0 +1,99 @@
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::RigidBodyDisabled;
+
+use crate::ingame::floating_origin::FloatingOrigin;
+use crate::ingame::tricoord::*;
+
+use super::terrain::{ChunkStatus, Chunks};
+
+pub struct ChunkMemberPlugin;
+
+impl Plugin for ChunkMemberPlugin {
+    fn build(&self, app: &mut App) {
+        app
+        .register_type::<ChunkMember>()
+        .add_systems(PostUpdate, assign_chunk_members.after(TransformSystem::TransformPropagate))
+        ;
+    }
+}
+
+#[derive(Reflect, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MemberUnload {
+    // goes away with its chunk
+    #[default]
+    Despawn,
+    // hidden and frozen under the TerrainRoot until its chunk is spawned again
+    Persist,
+}
+
+// props, pickups and npcs with this are moved under the chunk they stand on, so they stream with the terrain
+#[derive(Component, Reflect, Clone, Copy, Debug, Default)]
+#[reflect(Component)]
+pub struct ChunkMember {
+    pub on_unload: MemberUnload,
+}
+
+// a persisted member whose chunk was unloaded
+#[derive(Component, Clone, Copy, Debug)]
+pub struct ParkedMember {
+    pub tricoord: TriCoord<i32>,
+}
+
+// reparents members into the chunk under their position, keeping their world transform
+fn assign_chunk_members(
+    member_query: Query<(Entity, &GlobalTransform, Option<&Parent>, Option<&ParkedMember>), With<ChunkMember>>,
+    chunks: Res<Chunks>,
+    floating_origin: Res<FloatingOrigin>,
+    mut commands: Commands
+) {
+    for (entity, transform, parent, parked) in member_query.iter() {
+        let position = floating_origin.to_absolute(transform.translation());
+        let Ok(tricoord) = coord_to_trichunk(Coord { x: position.x, z: position.z }) else {
+            continue;
+        };
+        let Some(chunk_entity) = chunks.states.get(&tricoord)
+        .filter(|state| state.status == ChunkStatus::Spawned)
+        .and_then(|state| state.entity) else {
+            continue;
+        };
+        if parent.map(|parent| parent.get()) == Some(chunk_entity) {
+            continue;
+        }
+
+        let mut member_commands = commands.entity(entity);
+        member_commands.set_parent_in_place(chunk_entity);
+        if parked.is_some() {
+            member_commands
+            .remove::<(ParkedMember, RigidBodyDisabled)>()
+            .insert(Visibility::Inherited);
+        }
+    }
+}
+
+// called before a chunk entity is despawned. persisted members are taken out of it, the rest go with it
+pub fn park_chunk_members(
+    chunk_entity: Entity,
+    tricoord: TriCoord<i32>,
+    children_query: &Query<&Children>,
+    member_query: &Query<&ChunkMember>,
+    terrain_root: Option<Entity>,
+    commands: &mut Commands
+) {
+    let Ok(children) = children_query.get(chunk_entity) else {
+        return;
+    };
+    for child in children.iter() {
+        let Ok(member) = member_query.get(*child) else {
+            continue;
+        };
+        if member.on_unload != MemberUnload::Persist {
+            continue;
+        }
+        let mut member_commands = commands.entity(*child);
+        match terrain_root {
+            Some(root) => member_commands.set_parent_in_place(root),
+            None => member_commands.remove_parent_in_place(),
+        };
+        member_commands.insert((ParkedMember { tricoord }, Visibility::Hidden, RigidBodyDisabled));
+    }
+}
@@ -0,0 +1,719 @@
+use std::collections::HashMap;
+
+use bevy::{prelude::*, render::{mesh::{Indices, PrimitiveTopology, VertexAttributeValues}, render_asset::RenderAssetUsages}};
+use noise::utils::NoiseMap;
+
+use crate::ingame::tricoord::*;
+
+use super::ground_material::ATTRIBUTE_GROUND_MATERIAL;
+
+// how the vertices of a chunk mesh are laid out in the vertex buffer
+#[derive(Reflect, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MeshLayout {
+    // three unique vertices per trianglet, with a duplicated face normal and color
+    Flat,
+    // one shared vertex per lattice point and no normals, the terrain shader recovers the flat normals from derivatives
+    Indexed,
+}
+
+const NOISE_MIN:f32 = 0.0;
+const NOISE_MAX:f32 = 1.0;
+const HEIGHT_MIN:f32 = 0.0;
+const HEIGHT_MAX:f32 = 100.0;
+fn map_noise_to_height(value: f32) -> f32 {
+    HEIGHT_MIN + (value - NOISE_MIN) * (HEIGHT_MAX - HEIGHT_MIN) / (NOISE_MAX - NOISE_MIN)
+}
+
+// the heights of every vertex of a chunk, stored row by row.
+// row 0 is the base of the chunk (side + 1 vertices), the last row is the single apex vertex.
+// even chunks have their base at +z and the apex at -z, odd chunks are flipped.
+#[derive(Clone, Debug)]
+pub struct ChunkLattice {
+    pub side: usize,
+    pub odd: bool,
+    pub heights: Vec<f32>,
+}
+
+impl ChunkLattice {
+    // samples the 33 x 33 noise map at the lattice points of a CHUNK_SIDE chunk
+    pub fn from_noise(noise_map: &NoiseMap, odd: bool) -> Self {
+        let side = CHUNK_SIDE as usize;
+
+        // the noise map rows are walked bottom up for even chunks and top down for odd chunks
+        let (z_noise_start, z_noise_augmenter) = if !odd {
+            (32, -2)
+        } else {
+            (0, 2)
+        };
+
+        let mut heights = Vec::with_capacity(lattice_vertex_count(side));
+        for row in 0..=side {
+            let z_noise = (z_noise_start + z_noise_augmenter * row as i32) as usize;
+            for col in 0..=(side - row) {
+                // every row is shifted half a trianglet to the right, which is one noise pixel
+                let x_noise = row + col * 2;
+                heights.push(map_noise_to_height(noise_map.get_value(x_noise, z_noise) as f32));
+            }
+        }
+
+        return ChunkLattice { side, odd, heights };
+    }
+
+    pub fn index(&self, row: usize, col: usize) -> usize {
+        lattice_row_offset(self.side, row) + col
+    }
+
+    pub fn height(&self, row: usize, col: usize) -> f32 {
+        self.heights[self.index(row, col)]
+    }
+
+    // local position of a lattice vertex, relative to the chunk origin from trichunk_to_coord
+    pub fn position(&self, row: usize, col: usize) -> Vec3 {
+        let unit = CHUNK_SIDE as f32 / self.side as f32;
+        let x = -CHUNK_HALFSIDE as f32 * TRI_SIDE + (row as f32 * TRI_HALFSIDE + col as f32 * TRI_SIDE) * unit;
+        let z_sign = if self.odd { 1.0 } else { -1.0 };
+        let z = z_sign * (row as f32 * unit - CHUNK_HALFSIDE as f32) * TRI_ALTITUDE;
+        return Vec3::new(x, self.height(row, col), z);
+    }
+
+    // the corners of every trianglet, in the order ChunkGeometry::from_lattice walks them.
+    // per trianglet layers like the ground materials are indexed the same way
+    pub fn trianglets(&self) -> Vec<[(usize, usize); 3]> {
+        let side = self.side;
+        let mut trianglets = Vec::with_capacity(side * side);
+        for row in 0..side {
+            for col in 0..(side - row) {
+                trianglets.push([(row, col), (row, col + 1), (row + 1, col)]);
+                if col + 1 < side - row {
+                    trianglets.push([(row, col + 1), (row + 1, col + 1), (row + 1, col)]);
+                }
+            }
+        }
+        return trianglets;
+    }
+
+    // the index of the trianglet under a local position, positions outside the chunk get the nearest border trianglet.
+    // only the side and parity matter, so it works for the positions of refined and simplified meshes too
+    pub fn trianglet_at(&self, position: Vec3) -> usize {
+        let (row_base, col_base, odd_trianglet) = self.cell_at(position);
+        // every row before has side - row even and side - row - 1 odd trianglets
+        return row_base * (2 * self.side - row_base) + 2 * col_base + odd_trianglet as usize;
+    }
+
+    // the lattice row and col of the trianglet under a local position, and whether it is the odd one
+    fn cell_at(&self, position: Vec3) -> (usize, usize, bool) {
+        let side = self.side;
+        let unit = CHUNK_SIDE as f32 / side as f32;
+        let z_sign = if self.odd { 1.0 } else { -1.0 };
+        let row = ((position.z * z_sign / TRI_ALTITUDE + CHUNK_HALFSIDE as f32) / unit).clamp(0.0, side as f32);
+        let col = (((position.x + CHUNK_HALFSIDE as f32 * TRI_SIDE) / unit - row * TRI_HALFSIDE) / TRI_SIDE).max(0.0);
+
+        let row_base = (row.floor() as usize).min(side - 1);
+        let col_base = (col.floor() as usize).min(side - row_base - 1);
+        let (row_t, col_t) = (row - row_base as f32, col - col_base as f32);
+        // the odd trianglet of (row, col) sits between the even ones of col and col + 1
+        let odd_trianglet = row_t + col_t > 1.0 && col_base + 1 < side - row_base;
+        return (row_base, col_base, odd_trianglet);
+    }
+
+    // the height of the flat trianglet under a local position, positions outside the chunk get the height at its border
+    pub fn height_at(&self, position: Vec3) -> f32 {
+        let (row, col, odd_trianglet) = self.cell_at(position);
+        let corners = if odd_trianglet {
+            [(row, col + 1), (row + 1, col + 1), (row + 1, col)]
+        } else {
+            [(row, col), (row, col + 1), (row + 1, col)]
+        };
+        let [v0, v1, v2] = corners.map(|(row, col)| self.position(row, col));
+        // barycentric weights on the xz plane, clamped so outside positions do not extrapolate
+        let (e1, e2, p) = ((v1 - v0).xz(), (v2 - v0).xz(), (position - v0).xz());
+        let area = e1.perp_dot(e2);
+        let w1 = (p.perp_dot(e2) / area).clamp(0.0, 1.0);
+        let w2 = (e1.perp_dot(p) / area).clamp(0.0, 1.0 - w1);
+        return v0.y * (1.0 - w1 - w2) + v1.y * w1 + v2.y * w2;
+    }
+
+    // keeps every step-th lattice point, so the chunk is drawn with side / step trianglets per side
+    pub fn downsampled(&self, step: usize) -> ChunkLattice {
+        let side = self.side / step;
+        let mut heights = Vec::with_capacity(lattice_vertex_count(side));
+        for row in 0..=side {
+            for col in 0..=(side - row) {
+                heights.push(self.height(row * step, col * step));
+            }
+        }
+        return ChunkLattice { side, odd: self.odd, heights };
+    }
+
+    // subdivides every trianglet into 4, depth times. the new vertices sit on the flat trianglet they split,
+    // offset by whatever detail returns for their local position
+    pub fn refined(&self, depth: u8, detail: impl Fn(Vec3) -> f32) -> ChunkLattice {
+        let factor = 1 << depth;
+        let side = self.side * factor;
+        let mut refined = ChunkLattice { side, odd: self.odd, heights: Vec::with_capacity(lattice_vertex_count(side)) };
+        for row in 0..=side {
+            for col in 0..=(side - row) {
+                refined.heights.push(self.interpolated_height(row as f32 / factor as f32, col as f32 / factor as f32));
+            }
+        }
+        for row in 0..=side {
+            for col in 0..=(side - row) {
+                let index = refined.index(row, col);
+                refined.heights[index] += detail(refined.position(row, col));
+            }
+        }
+        return refined;
+    }
+
+    // height on the flat trianglets between lattice points, for fractional rows and cols
+    pub fn interpolated_height(&self, row: f32, col: f32) -> f32 {
+        let (row_base, col_base) = (row.floor() as usize, col.floor() as usize);
+        let (row_t, col_t) = (row - row_base as f32, col - col_base as f32);
+        if row_t == 0.0 && col_t == 0.0 {
+            return self.height(row_base, col_base);
+        }
+
+        if row_t + col_t <= 1.0 {
+            // inside the even trianglet: left, right, altitude
+            return self.height(row_base, col_base) * (1.0 - row_t - col_t)
+                + self.height(row_base, col_base + 1) * col_t
+                + self.height(row_base + 1, col_base) * row_t;
+        }
+        // inside the odd trianglet: altitude, right, left
+        return self.height(row_base, col_base + 1) * (1.0 - row_t)
+            + self.height(row_base + 1, col_base + 1) * (row_t + col_t - 1.0)
+            + self.height(row_base + 1, col_base) * (1.0 - col_t);
+    }
+
+    // the three chunk borders as lattice (row, col) points: base, left side, right side
+    fn border_paths(&self) -> [Vec<(usize, usize)>; 3] {
+        let side = self.side;
+        [
+            (0..=side).map(|col| (0, col)).collect(),
+            (0..=side).map(|row| (row, 0)).collect(),
+            (0..=side).map(|row| (row, side - row)).collect(),
+        ]
+    }
+
+    // how far the border of this chunk can move vertically when it is drawn at a coarser step.
+    // the shared border of two neighbors has the same heights on both sides, so a skirt this deep
+    // covers the crack between any two lod levels.
+    pub fn skirt_depth(&self) -> f32 {
+        let mut max_error:f32 = 0.0;
+        for path in self.border_paths() {
+            let mut step = 2;
+            while step < self.side {
+                for (index, (row, col)) in path.iter().enumerate() {
+                    let start = index / step * step;
+                    let end = (start + step).min(path.len() - 1);
+                    if start == end {
+                        continue;
+                    }
+                    let t = (index - start) as f32 / (end - start) as f32;
+                    let interpolated = self.height(path[start].0, path[start].1).lerp(self.height(path[end].0, path[end].1), t);
+                    max_error = max_error.max((self.height(*row, *col) - interpolated).abs());
+                }
+                step *= 2;
+            }
+        }
+        return max_error + SKIRT_MARGIN;
+    }
+}
+
+const SKIRT_MARGIN:f32 = 0.5;
+
+pub fn lattice_vertex_count(side: usize) -> usize {
+    (side + 1) * (side + 2) / 2
+}
+
+fn lattice_row_offset(side: usize, row: usize) -> usize {
+    // vertices in the rows before this one: (side + 1) + side + ... + (side + 2 - row)
+    row * (side + 1) - row * row.saturating_sub(1) / 2
+}
+
+// indexed triangles over arbitrary positions, before they are packed into a bevy Mesh
+#[derive(Clone, Debug, Default)]
+pub struct ChunkGeometry {
+    pub positions: Vec<Vec3>,
+    pub triangles: Vec<[u32; 3]>,
+}
+
+impl ChunkGeometry {
+    pub fn from_lattice(lattice: &ChunkLattice) -> Self {
+        let side = lattice.side;
+        let mut positions = Vec::with_capacity(lattice_vertex_count(side));
+        for row in 0..=side {
+            for col in 0..=(side - row) {
+                positions.push(lattice.position(row, col));
+            }
+        }
+
+        let mut triangles = Vec::with_capacity(side * side);
+        for row in 0..side {
+            for col in 0..(side - row) {
+                // even trianglet: left, right, altitude
+                triangles.push(orient([
+                    lattice.index(row, col) as u32,
+                    lattice.index(row, col + 1) as u32,
+                    lattice.index(row + 1, col) as u32,
+                ], lattice.odd));
+
+                // odd trianglet: altitude, right, left
+                if col + 1 < side - row {
+                    triangles.push(orient([
+                        lattice.index(row, col + 1) as u32,
+                        lattice.index(row + 1, col + 1) as u32,
+                        lattice.index(row + 1, col) as u32,
+                    ], lattice.odd));
+                }
+            }
+        }
+
+        return ChunkGeometry { positions, triangles };
+    }
+
+    // merges groups of 4 near coplanar trianglets into one, recursively, as long as every lattice point the bigger
+    // triangle covers is within max_error of it. the side of the lattice has to be a power of two.
+    // border vertices are always kept so the seams with the neighbor chunks stay intact.
+    pub fn simplified(lattice: &ChunkLattice, max_error: f32) -> Self {
+        let side = lattice.side;
+        let mut leaves: Vec<LatticeTriangle> = Vec::new();
+        collect_flat_triangles(lattice, LatticeTriangle { up: true, row: 0, col: 0, size: side }, max_error, &mut leaves);
+
+        // lattice points that stay in the mesh
+        let mut kept = vec![false; lattice_vertex_count(side)];
+        for row in 0..=side {
+            kept[lattice.index(row, 0)] = true;
+            kept[lattice.index(row, side - row)] = true;
+        }
+        for col in 0..=side {
+            kept[lattice.index(0, col)] = true;
+        }
+        for leaf in leaves.iter() {
+            for (row, col) in leaf.corners() {
+                kept[lattice.index(row, col)] = true;
+            }
+        }
+
+        let mut geometry = ChunkGeometry::default();
+        let mut new_index: HashMap<usize, u32> = HashMap::new();
+        let mut vertex = |geometry: &mut ChunkGeometry, row: usize, col: usize| *new_index.entry(lattice.index(row, col)).or_insert_with(|| {
+            geometry.positions.push(lattice.position(row, col));
+            (geometry.positions.len() - 1) as u32
+        });
+
+        for leaf in leaves.iter() {
+            // walk the edges of the leaf and pick up the kept points that neighbouring leaves or the border put on them
+            let corners = leaf.corners();
+            let mut outline: Vec<u32> = Vec::new();
+            for edge in 0..3 {
+                let (start, end) = (corners[edge], corners[(edge + 1) % 3]);
+                for step in 0..leaf.size {
+                    let row = (start.0 as isize + (end.0 as isize - start.0 as isize) * step as isize / leaf.size as isize) as usize;
+                    let col = (start.1 as isize + (end.1 as isize - start.1 as isize) * step as isize / leaf.size as isize) as usize;
+                    if step == 0 || kept[lattice.index(row, col)] {
+                        outline.push(vertex(&mut geometry, row, col));
+                    }
+                }
+            }
+
+            if outline.len() == 3 {
+                geometry.triangles.push(orient([outline[0], outline[1], outline[2]], lattice.odd));
+                continue;
+            }
+
+            // points on the edges would make a T-junction, so the leaf is fanned out from its centroid instead
+            let centroid = corners.iter().map(|(row, col)| lattice.position(*row, *col)).sum::<Vec3>() / 3.0;
+            geometry.positions.push(centroid);
+            let center = (geometry.positions.len() - 1) as u32;
+            for index in 0..outline.len() {
+                geometry.triangles.push(orient([center, outline[index], outline[(index + 1) % outline.len()]], lattice.odd));
+            }
+        }
+
+        return geometry;
+    }
+
+    // hangs a vertical strip below every border edge, hiding the cracks between chunks drawn at different lod levels
+    pub fn with_skirt(mut self, depth: f32) -> Self {
+        let mut edge_count: HashMap<(u32, u32), usize> = HashMap::new();
+        for triangle in self.triangles.iter() {
+            for (a, b) in triangle_edges(triangle) {
+                *edge_count.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+            }
+        }
+
+        let mut lowered: HashMap<u32, u32> = HashMap::new();
+        let mut skirt_triangles = Vec::new();
+        for triangle in self.triangles.iter() {
+            for (a, b) in triangle_edges(triangle) {
+                // edges that only belong to one trianglet are on the border
+                if edge_count[&(a.min(b), a.max(b))] != 1 {
+                    continue;
+                }
+                let mut lower = |index: u32| *lowered.entry(index).or_insert_with(|| {
+                    self.positions.push(self.positions[index as usize] - Vec3::Y * depth);
+                    (self.positions.len() - 1) as u32
+                });
+                let (a_low, b_low) = (lower(a), lower(b));
+                // wound against the edge so the skirt faces away from the chunk
+                skirt_triangles.push([b, a, a_low]);
+                skirt_triangles.push([b, a_low, b_low]);
+            }
+        }
+        self.triangles.extend(skirt_triangles);
+
+        return self;
+    }
+
+    pub fn face_normal(&self, triangle: &[u32; 3]) -> Vec3 {
+        let [v0, v1, v2] = triangle.map(|index| self.positions[index as usize]);
+        calculate_normal(v0, v1, v2)
+    }
+}
+
+// a triangle on the lattice, made up of size * size trianglets. up triangles have their base on row,
+// down triangles have their single corner on row and the base on row + size.
+#[derive(Clone, Copy, Debug)]
+struct LatticeTriangle {
+    up: bool,
+    row: usize,
+    col: usize,
+    size: usize,
+}
+
+impl LatticeTriangle {
+    // in the same order as the trianglets in ChunkGeometry::from_lattice
+    fn corners(&self) -> [(usize, usize); 3] {
+        let (row, col, size) = (self.row, self.col, self.size);
+        if self.up {
+            [(row, col), (row, col + size), (row + size, col)]
+        } else {
+            [(row, col + size), (row + size, col + size), (row + size, col)]
+        }
+    }
+
+    fn children(&self) -> [LatticeTriangle; 4] {
+        let (row, col, half) = (self.row, self.col, self.size / 2);
+        if self.up {
+            [
+                LatticeTriangle { up: true, row, col, size: half },
+                LatticeTriangle { up: true, row, col: col + half, size: half },
+                LatticeTriangle { up: true, row: row + half, col, size: half },
+                LatticeTriangle { up: false, row, col, size: half },
+            ]
+        } else {
+            [
+                LatticeTriangle { up: false, row, col: col + half, size: half },
+                LatticeTriangle { up: false, row: row + half, col: col + half, size: half },
+                LatticeTriangle { up: false, row: row + half, col, size: half },
+                LatticeTriangle { up: true, row: row + half, col: col + half, size: half },
+            ]
+        }
+    }
+
+    // every lattice point inside or on the triangle, with its barycentric weights for the three corners
+    fn points(&self) -> Vec<((usize, usize), [f32; 3])> {
+        let size = self.size;
+        let mut points = Vec::new();
+        for i in 0..=size {
+            for j in 0..=size {
+                let (row_t, col_t) = (i as f32 / size as f32, j as f32 / size as f32);
+                if self.up && i + j <= size {
+                    points.push(((self.row + i, self.col + j), [1.0 - row_t - col_t, col_t, row_t]));
+                } else if !self.up && i + j >= size {
+                    points.push(((self.row + i, self.col + j), [1.0 - row_t, row_t + col_t - 1.0, 1.0 - col_t]));
+                }
+            }
+        }
+        return points;
+    }
+}
+
+fn collect_flat_triangles(lattice: &ChunkLattice, triangle: LatticeTriangle, max_error: f32, leaves: &mut Vec<LatticeTriangle>) {
+    let corner_heights = triangle.corners().map(|(row, col)| lattice.height(row, col));
+    let flat = triangle.points().iter().all(|((row, col), weights)| {
+        let plane_height = corner_heights[0] * weights[0] + corner_heights[1] * weights[1] + corner_heights[2] * weights[2];
+        (lattice.height(*row, *col) - plane_height).abs() <= max_error
+    });
+
+    if flat || triangle.size == 1 {
+        leaves.push(triangle);
+        return;
+    }
+    for child in triangle.children() {
+        collect_flat_triangles(lattice, child, max_error, leaves);
+    }
+}
+
+fn triangle_edges(triangle: &[u32; 3]) -> [(u32, u32); 3] {
+    [(triangle[0], triangle[1]), (triangle[1], triangle[2]), (triangle[2], triangle[0])]
+}
+
+// odd chunks are mirrored on the z axis, so their winding has to be flipped to keep facing up
+fn orient(triangle: [u32; 3], odd: bool) -> [u32; 3] {
+    if odd {
+        [triangle[1], triangle[0], triangle[2]]
+    } else {
+        triangle
+    }
+}
+
+fn calculate_normal(v0: Vec3, v1: Vec3, v2: Vec3) -> Vec3 {
+    let edge1 = v1 - v0;
+    let edge2 = v2 - v0;
+
+    let normal = edge1.cross(edge2);
+
+    normal.normalize()
+}
+
+// r min max = -7, 9
+// g min max = -1.2, 4
+// b min max = -6, 8
+fn position_color(position: &Vec3) -> [f32; 4] {
+    let min = 0.0;
+    let clamp_min = |value: f32| value.max(min);
+    [clamp_min(1. - position.z), clamp_min(1. - position.x), clamp_min(1. + position.x), 1.]
+}
+
+// the ground material of every trianglet of a chunk, looked up by position so any mesh of the chunk can use it
+pub struct GroundLayer<'a> {
+    pub lattice: &'a ChunkLattice,
+    pub materials: &'a [u8],
+}
+
+impl GroundLayer<'_> {
+    fn material_of(&self, geometry: &ChunkGeometry, triangle: &[u32; 3]) -> u32 {
+        let centroid = triangle.iter().map(|index| geometry.positions[*index as usize]).sum::<Vec3>() / 3.0;
+        self.materials.get(self.lattice.trianglet_at(centroid)).copied().unwrap_or(0) as u32
+    }
+}
+
+// every layout carries the ground material, so painted trianglets look the same whichever one is picked
+pub fn build_chunk_mesh(geometry: &ChunkGeometry, layout: MeshLayout, ground: &GroundLayer) -> Mesh {
+    let mesh = Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD);
+
+    match layout {
+        MeshLayout::Flat => {
+            let mut vertices = Vec::with_capacity(geometry.triangles.len() * 3);
+            let mut normals = Vec::with_capacity(geometry.triangles.len() * 3);
+            let mut materials = Vec::with_capacity(geometry.triangles.len() * 3);
+            for triangle in geometry.triangles.iter() {
+                let normal = geometry.face_normal(triangle);
+                let material = ground.material_of(geometry, triangle);
+                for index in triangle {
+                    vertices.push(geometry.positions[*index as usize]);
+                    normals.push(normal);
+                    materials.push(material);
+                }
+            }
+            let colors: Vec<[f32; 4]> = vertices.iter().map(position_color).collect();
+            let assignments = (0..vertices.len() as u32).collect();
+
+            mesh
+            .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, vertices)
+            .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
+            .with_inserted_attribute(Mesh::ATTRIBUTE_COLOR, colors)
+            .with_inserted_attribute(ATTRIBUTE_GROUND_MATERIAL, materials)
+            .with_inserted_indices(indices_for(assignments))
+        }
+        MeshLayout::Indexed => {
+            // the material is per trianglet, so a lattice point is split into one vertex per material around it.
+            // inside an area of one material the vertices stay shared
+            let mut split: HashMap<(u32, u32), u32> = HashMap::new();
+            let (mut positions, mut materials) = (Vec::new(), Vec::new());
+            let mut assignments = Vec::with_capacity(geometry.triangles.len() * 3);
+            for triangle in geometry.triangles.iter() {
+                let material = ground.material_of(geometry, triangle);
+                for index in triangle {
+                    let vertex = *split.entry((*index, material)).or_insert_with(|| {
+                        positions.push(geometry.positions[*index as usize]);
+                        materials.push(material);
+                        (positions.len() - 1) as u32
+                    });
+                    assignments.push(vertex);
+                }
+            }
+
+            mesh
+            .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+            .with_inserted_attribute(ATTRIBUTE_GROUND_MATERIAL, materials)
+            .with_inserted_indices(indices_for(assignments))
+        }
+    }
+}
+
+// u16 indices whenever they fit, which is always the case for a single chunk
+fn indices_for(assignments: Vec<u32>) -> Indices {
+    if assignments.iter().all(|index| *index <= u16::MAX as u32) {
+        Indices::U16(assignments.into_iter().map(|index| index as u16).collect())
+    } else {
+        Indices::U32(assignments)
+    }
+}
+
+// joins chunk meshes into one, each moved by its offset. the normals and ground materials are kept when every
+// part has them, so chunks meshed with different layouts can still be merged
+pub fn merge_chunk_meshes(parts: &[(Mesh, Vec3)]) -> Mesh {
+    let mut positions: Vec<Vec3> = Vec::new();
+    // indexed meshes have no normals, the terrain shader does not need them
+    let mut normals: Option<Vec<Vec3>> = Some(Vec::new());
+    // None once a part without ground materials shows up, the shader then falls back to the slope colors
+    let mut materials: Option<Vec<u32>> = Some(Vec::new());
+    let mut assignments: Vec<u32> = Vec::new();
+
+    for (mesh, offset) in parts {
+        let Some(VertexAttributeValues::Float32x3(part_positions)) = mesh.attribute(Mesh::ATTRIBUTE_POSITION) else {
+            continue;
+        };
+        let first = positions.len() as u32;
+        positions.extend(part_positions.iter().map(|position| Vec3::from(*position) + *offset));
+        normals = match (normals, mesh.attribute(Mesh::ATTRIBUTE_NORMAL)) {
+            (Some(mut normals), Some(VertexAttributeValues::Float32x3(part_normals))) => {
+                normals.extend(part_normals.iter().map(|normal| Vec3::from(*normal)));
+                Some(normals)
+            }
+            _ => None,
+        };
+        materials = match (materials, mesh.attribute(ATTRIBUTE_GROUND_MATERIAL)) {
+            (Some(mut materials), Some(VertexAttributeValues::Uint32(part_materials))) => {
+                materials.extend_from_slice(part_materials);
+                Some(materials)
+            }
+            _ => None,
+        };
+        match mesh.indices() {
+            Some(indices) => assignments.extend(indices.iter().map(|index| first + index as u32)),
+            None => assignments.extend(first..positions.len() as u32),
+        }
+    }
+
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::RENDER_WORLD)
+    .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+    .with_inserted_indices(indices_for(assignments));
+    if let Some(normals) = normals {
+        mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    }
+    if let Some(materials) = materials {
+        mesh.insert_attribute(ATTRIBUTE_GROUND_MATERIAL, materials);
+    }
+    return mesh;
+}
+
+// bytes the mesh takes up in its vertex and index buffers
+pub fn mesh_memory_bytes(mesh: &Mesh) -> usize {
+    let vertex_bytes = mesh.get_vertex_size() as usize * mesh.count_vertices();
+    let index_bytes = mesh.get_index_buffer_bytes().map_or(0, |bytes| bytes.len());
+    vertex_bytes + index_bytes
+}
+
+// a full resolution chunk of flat ground at 10 with a hill inside. the border is flat, so any two of them
+// meet without a step. the tests of every module working on lattices start from it
+#[cfg(test)]
+pub fn test_lattice(odd: bool) -> ChunkLattice {
+    let side = CHUNK_SIDE as usize;
+    let mut lattice = ChunkLattice { side, odd, heights: vec![10.0; lattice_vertex_count(side)] };
+    for row in 4..8 {
+        for col in 4..8 {
+            let index = lattice.index(row, col);
+            lattice.heights[index] = 10.0 + (row * col) as f32 * 0.3;
+        }
+    }
+    lattice
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    // a bumpy base border on top, so only part of the chunk can be simplified
+    fn bumpy_border_lattice(odd: bool) -> ChunkLattice {
+        let mut lattice = test_lattice(odd);
+        for col in (1..lattice.side).step_by(2) {
+            let index = lattice.index(0, col);
+            lattice.heights[index] = 11.0;
+        }
+        lattice
+    }
+
+    fn border_points(lattice: &ChunkLattice) -> Vec<(usize, usize)> {
+        let side = lattice.side;
+        let mut points: Vec<(usize, usize)> = (0..=side).map(|col| (0, col)).collect();
+        points.extend((1..=side).map(|row| (row, 0)));
+        points.extend((1..side).map(|row| (row, side - row)));
+        points
+    }
+
+    fn position_key(position: Vec3) -> (u32, u32, u32) {
+        (position.x.to_bits(), position.y.to_bits(), position.z.to_bits())
+    }
+
+    // the edges that only one triangle uses, by the positions at their ends
+    fn border_edges(geometry: &ChunkGeometry) -> HashSet<((u32, u32, u32), (u32, u32, u32))> {
+        let mut edge_count: HashMap<((u32, u32, u32), (u32, u32, u32)), usize> = HashMap::new();
+        for triangle in geometry.triangles.iter() {
+            for (a, b) in triangle_edges(triangle) {
+                let (a, b) = (position_key(geometry.positions[a as usize]), position_key(geometry.positions[b as usize]));
+                *edge_count.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+            }
+        }
+        edge_count.into_iter().filter(|(_, count)| *count == 1).map(|(edge, _)| edge).collect()
+    }
+
+    // the area the triangles cover seen from above
+    fn covered_area(geometry: &ChunkGeometry) -> f32 {
+        geometry.triangles.iter().map(|triangle| {
+            let [v0, v1, v2] = triangle.map(|index| geometry.positions[index as usize].xz());
+            (v1 - v0).perp_dot(v2 - v0).abs() * 0.5
+        })
+        .sum()
+    }
+
+    #[test]
+    fn simplified_keeps_every_border_vertex() {
+        for odd in [false, true] {
+            let lattice = bumpy_border_lattice(odd);
+            let simplified = ChunkGeometry::simplified(&lattice, 0.25);
+            let positions: HashSet<_> = simplified.positions.iter().map(|position| position_key(*position)).collect();
+
+            for (row, col) in border_points(&lattice) {
+                assert!(positions.contains(&position_key(lattice.position(row, col))), "border vertex {} {} is gone", row, col);
+            }
+        }
+    }
+
+    #[test]
+    fn simplified_border_matches_full_resolution() {
+        for odd in [false, true] {
+            let lattice = bumpy_border_lattice(odd);
+            let full = ChunkGeometry::from_lattice(&lattice);
+            let simplified = ChunkGeometry::simplified(&lattice, 0.25);
+
+            assert_eq!(border_edges(&simplified), border_edges(&full));
+        }
+    }
+
+    #[test]
+    fn simplified_covers_the_whole_chunk() {
+        let lattice = bumpy_border_lattice(false);
+        let full = covered_area(&ChunkGeometry::from_lattice(&lattice));
+        let simplified = covered_area(&ChunkGeometry::simplified(&lattice, 0.25));
+
+        assert!((full - simplified).abs() < full * 1e-4);
+    }
+
+    #[test]
+    fn simplified_drops_flat_interior_vertices() {
+        let lattice = bumpy_border_lattice(true);
+        let full = ChunkGeometry::from_lattice(&lattice);
+        let simplified = ChunkGeometry::simplified(&lattice, 0.25);
+
+        assert!(simplified.positions.len() < full.positions.len());
+        // with no error allowed the hill can not be simplified, the flat parts still can
+        let exact = ChunkGeometry::simplified(&lattice, 0.0);
+        assert!(exact.positions.len() >= simplified.positions.len());
+    }
+}
@@ -0,0 +1,152 @@
+use bevy::{math::DVec3, prelude::*, render::{mesh::MeshVertexAttribute, render_resource::VertexFormat}};
+
+use crate::ingame::floating_origin::AbsolutePosition;
+use crate::ingame::tricoord::*;
+
+use super::chunk_mesh::ChunkLattice;
+use super::terrain::{ChunkHeights, ChunkStatus, Chunks};
+
+pub struct GroundMaterialPlugin;
+
+impl Plugin for GroundMaterialPlugin {
+    fn build(&self, app: &mut App) {
+        app
+        .register_type::<GroundContact>()
+        .add_systems(PostUpdate, update_ground_contacts)
+        ;
+    }
+}
+
+// what the ground of a trianglet is made of. the ids are stored per trianglet in the chunk data,
+// so existing variants keep their number
+#[derive(Reflect, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum GroundMaterial {
+    #[default]
+    Grass = 0,
+    Rock = 1,
+    Sand = 2,
+    Snow = 3,
+    // never generated, only painted
+    Path = 4,
+}
+
+pub const GROUND_MATERIALS:[GroundMaterial; 5] = [GroundMaterial::Grass, GroundMaterial::Rock, GroundMaterial::Sand, GroundMaterial::Snow, GroundMaterial::Path];
+
+impl GroundMaterial {
+    // unknown ids from newer files are read as grass
+    pub fn from_id(id: u8) -> Self {
+        GROUND_MATERIALS.get(id as usize).copied().unwrap_or_default()
+    }
+
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+
+    // friction coefficient for walking on it
+    pub fn friction(self) -> f32 {
+        match self {
+            GroundMaterial::Grass => 0.8,
+            GroundMaterial::Rock => 0.9,
+            GroundMaterial::Sand => 0.6,
+            GroundMaterial::Snow => 0.3,
+            GroundMaterial::Path => 1.0,
+        }
+    }
+
+    // linear rgba, the same as ground_color in the terrain shader. flat grass, the shader blends it towards rock on slopes
+    pub fn color(self) -> [f32; 4] {
+        match self {
+            GroundMaterial::Grass => [0.08, 0.2, 0.05, 1.0],
+            GroundMaterial::Rock => [0.1, 0.1, 0.1, 1.0],
+            GroundMaterial::Sand => [0.45, 0.38, 0.22, 1.0],
+            GroundMaterial::Snow => [0.85, 0.87, 0.9, 1.0],
+            GroundMaterial::Path => [0.22, 0.15, 0.08, 1.0],
+        }
+    }
+
+    // the footstep sound set to play on it
+    pub fn footstep(self) -> &'static str {
+        match self {
+            GroundMaterial::Grass => "grass",
+            GroundMaterial::Rock => "rock",
+            GroundMaterial::Sand => "sand",
+            GroundMaterial::Snow => "snow",
+            GroundMaterial::Path => "gravel",
+        }
+    }
+}
+
+// the ground material of every vertex of a chunk mesh, the same for the three vertices of a triangle.
+// the terrain shader picks it up at location 8 when the mesh has it
+pub const ATTRIBUTE_GROUND_MATERIAL: MeshVertexAttribute = MeshVertexAttribute::new("GroundMaterial", 988_540_917, VertexFormat::Uint32);
+pub const GROUND_MATERIAL_SHADER_LOCATION:u32 = 8;
+
+const SNOW_HEIGHT:f32 = 75.0;
+const SAND_HEIGHT:f32 = 12.0;
+// trianglets steeper than this are rock, 1 is flat
+const ROCK_SLOPE:f32 = 0.75;
+
+// the procedural ground of every trianglet, from its height and slope. it only depends on the heights,
+// so it is computed again whenever a chunk is generated or edited and only painted trianglets are stored
+pub fn classify_ground(lattice: &ChunkLattice) -> Vec<u8> {
+    lattice.trianglets().iter().map(|corners| {
+        let [v0, v1, v2] = corners.map(|(row, col)| lattice.position(row, col));
+        let normal = (v1 - v0).cross(v2 - v0).normalize_or(Vec3::Y);
+        let height = (v0.y + v1.y + v2.y) / 3.0;
+
+        let material = if height > SNOW_HEIGHT {
+            GroundMaterial::Snow
+        } else if normal.y.abs() < ROCK_SLOPE {
+            GroundMaterial::Rock
+        } else if height < SAND_HEIGHT {
+            GroundMaterial::Sand
+        } else {
+            GroundMaterial::Grass
+        };
+        material.id()
+    })
+    .collect()
+}
+
+// the ground material of every trianglet of a spawned chunk, in ChunkLattice::trianglets order
+#[derive(Component)]
+pub struct ChunkMaterials(pub Vec<u8>);
+
+// the ground material at an absolute position, None when the chunk there is not spawned
+pub fn ground_material_at(
+    position: DVec3,
+    chunks: &Chunks,
+    query: &Query<(&ChunkHeights, &ChunkMaterials)>
+) -> Option<GroundMaterial> {
+    let tricoord = coord_to_trichunk(Coord { x: position.x, z: position.z }).ok()?;
+    let entity = chunks.states.get(&tricoord)
+    .filter(|state| state.status == ChunkStatus::Spawned)
+    .and_then(|state| state.entity)?;
+    let (heights, materials) = query.get(entity).ok()?;
+
+    let chunk_coord = trichunk_to_coord(tricoord, 0);
+    let local = (position - DVec3::new(chunk_coord.x, 0.0, chunk_coord.z)).as_vec3();
+    let id = *materials.0.get(heights.0.trianglet_at(local))?;
+    return Some(GroundMaterial::from_id(id));
+}
+
+// the ground under an entity with an AbsolutePosition, for footsteps and friction
+#[derive(Component, Reflect, Default)]
+#[reflect(Component)]
+pub struct GroundContact {
+    pub material: Option<GroundMaterial>,
+}
+
+fn update_ground_contacts(
+    mut contact_query: Query<(&AbsolutePosition, &mut GroundContact)>,
+    chunk_query: Query<(&ChunkHeights, &ChunkMaterials)>,
+    chunks: Res<Chunks>
+) {
+    for (absolute, mut contact) in contact_query.iter_mut() {
+        let material = ground_material_at(absolute.position, &chunks, &chunk_query);
+        if contact.material != material {
+            contact.material = material;
+        }
+    }
+}
@@ -0,0 +1,524 @@
+use std::{fmt, io, sync::Arc};
+
+use bevy::{asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext, LoadState}, math::{DVec2, DVec3}, pbr::ExtendedMaterial, prelude::*, render::{mesh::{Indices, PrimitiveTopology}, render_asset::RenderAssetUsages}};
+
+use crate::ingame::floating_origin::FloatingOrigin;
+use crate::ingame::tricoord::*;
+
+use super::chunk_mesh::{lattice_vertex_count, ChunkLattice};
+use super::terrain::{fnv1a, TerrainConfig};
+use super::terrain_render::{MyMaterial, TerrainHandles};
+
+pub struct HeightmapPlugin;
+
+impl Plugin for HeightmapPlugin {
+    fn build(&self, app: &mut App) {
+        app
+        .init_asset::<Heightmap>()
+        .register_asset_loader(HeightmapLoader)
+        .init_resource::<HeightmapImport>()
+        .add_systems(Update, (load_terrain_heightmap, update_square_reference).chain())
+        ;
+    }
+}
+
+// a grayscale image as heights from 0 (black) to 1 (white), row by row from the top left pixel
+pub struct HeightGrid {
+    pub width: usize,
+    pub height: usize,
+    pub samples: Vec<f32>,
+    // of the pixels, the chunk cache is keyed by it so another image with the same path is not mixed up
+    pub content_hash: u64,
+}
+
+impl HeightGrid {
+    // pixels outside the image repeat the edge
+    fn pixel(&self, x: i64, y: i64) -> f32 {
+        let x = x.clamp(0, self.width as i64 - 1) as usize;
+        let y = y.clamp(0, self.height as i64 - 1) as usize;
+        self.samples[y * self.width + x]
+    }
+
+    pub fn bilinear(&self, x: f64, y: f64) -> f32 {
+        let (x_base, y_base) = (x.floor(), y.floor());
+        let (x_t, y_t) = ((x - x_base) as f32, (y - y_base) as f32);
+        let (x_base, y_base) = (x_base as i64, y_base as i64);
+        let top = self.pixel(x_base, y_base) * (1.0 - x_t) + self.pixel(x_base + 1, y_base) * x_t;
+        let bottom = self.pixel(x_base, y_base + 1) * (1.0 - x_t) + self.pixel(x_base + 1, y_base + 1) * x_t;
+        return top * (1.0 - y_t) + bottom * y_t;
+    }
+
+    // catmull rom through the 4 x 4 pixels around the point, it passes through the pixels like bilinear
+    // but keeps the slope continuous, so the trianglets do not show the pixel grid on steep terrain
+    pub fn bicubic(&self, x: f64, y: f64) -> f32 {
+        let (x_base, y_base) = (x.floor(), y.floor());
+        let (x_t, y_t) = ((x - x_base) as f32, (y - y_base) as f32);
+        let (x_base, y_base) = (x_base as i64, y_base as i64);
+        let row = |y: i64| {
+            catmull_rom([-1, 0, 1, 2].map(|offset| self.pixel(x_base + offset, y)), x_t)
+        };
+        return catmull_rom([-1, 0, 1, 2].map(|offset| row(y_base + offset)), y_t);
+    }
+}
+
+fn catmull_rom([p0, p1, p2, p3]: [f32; 4], t: f32) -> f32 {
+    0.5 * (2.0 * p1
+        + (p2 - p0) * t
+        + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t * t
+        + (3.0 * p1 - p0 - 3.0 * p2 + p3) * t * t * t)
+}
+
+#[derive(Asset, TypePath)]
+pub struct Heightmap {
+    pub grid: Arc<HeightGrid>,
+}
+
+// loads heightmaps with asset_server.load::<Heightmap>(path), the png loader of Image keeps the untyped loads
+#[derive(Default)]
+pub struct HeightmapLoader;
+
+#[derive(Debug)]
+pub enum HeightmapError {
+    Io(io::Error),
+    Image(image::ImageError),
+    Empty,
+}
+
+impl fmt::Display for HeightmapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeightmapError::Io(error) => write!(f, "could not read the heightmap: {}", error),
+            HeightmapError::Image(error) => write!(f, "could not decode the heightmap: {}", error),
+            HeightmapError::Empty => write!(f, "the heightmap has no pixels"),
+        }
+    }
+}
+
+impl std::error::Error for HeightmapError {}
+
+impl From<io::Error> for HeightmapError {
+    fn from(error: io::Error) -> Self {
+        HeightmapError::Io(error)
+    }
+}
+
+impl From<image::ImageError> for HeightmapError {
+    fn from(error: image::ImageError) -> Self {
+        HeightmapError::Image(error)
+    }
+}
+
+impl AssetLoader for HeightmapLoader {
+    type Asset = Heightmap;
+    type Settings = ();
+    type Error = HeightmapError;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext<'_>
+    ) -> Result<Heightmap, HeightmapError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        return Ok(Heightmap { grid: Arc::new(decode_heightmap(&bytes)?) });
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["heightmap.png"]
+    }
+}
+
+// 8 bit images are widened to 16 bit, so both end up on the same 0 to 1 range.
+// color images are read by their luminance
+pub fn decode_heightmap(bytes: &[u8]) -> Result<HeightGrid, HeightmapError> {
+    let luma = image::load_from_memory(bytes)?.to_luma16();
+    let (width, height) = (luma.width() as usize, luma.height() as usize);
+    if width == 0 || height == 0 {
+        return Err(HeightmapError::Empty);
+    }
+    let pixels = luma.into_raw();
+    let pixel_bytes: Vec<u8> = pixels.iter().flat_map(|pixel| pixel.to_le_bytes()).collect();
+    let samples = pixels.iter().map(|pixel| *pixel as f32 / u16::MAX as f32).collect();
+    return Ok(HeightGrid { width, height, samples, content_hash: fnv1a(&pixel_bytes) });
+}
+
+#[derive(Reflect, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HeightmapFilter {
+    #[default]
+    Bilinear,
+    Bicubic,
+}
+
+// what the terrain is outside the image
+#[derive(Reflect, Clone, Copy, Debug, PartialEq)]
+pub enum OutsideHeightmap {
+    // the procedural terrain of the seed
+    Noise,
+    // a plane at this height
+    Flat(f32),
+}
+
+// puts a heightmap image into the world in place of the procedural heights
+#[derive(Clone)]
+pub struct HeightmapTerrain {
+    // asset path of an 8 or 16 bit grayscale png
+    pub path: String,
+    // absolute x and z of the top left pixel, the image rows go along +z
+    pub origin: DVec2,
+    // world distance between two pixels
+    pub pixel_size: f64,
+    // the heights black and white end up at
+    pub height_min: f32,
+    pub height_max: f32,
+    pub filter: HeightmapFilter,
+    pub outside: OutsideHeightmap,
+    // set once the image is loaded, no chunks are generated before that
+    pub grid: Option<Arc<HeightGrid>>,
+}
+
+impl HeightmapTerrain {
+    pub fn new(path: impl Into<String>) -> Self {
+        HeightmapTerrain {
+            path: path.into(),
+            origin: DVec2::ZERO,
+            pixel_size: 1.0,
+            height_min: 0.0,
+            height_max: 100.0,
+            filter: HeightmapFilter::Bilinear,
+            outside: OutsideHeightmap::Noise,
+            grid: None,
+        }
+    }
+
+    // world size of the image along x and z, None before it is loaded
+    pub fn extent(&self) -> Option<DVec2> {
+        let grid = self.grid.as_ref()?;
+        Some(DVec2::new((grid.width - 1) as f64, (grid.height - 1) as f64) * self.pixel_size)
+    }
+
+    // the height at an absolute x and z, None outside the image or before it is loaded
+    pub fn height_at(&self, x: f64, z: f64) -> Option<f32> {
+        let grid = self.grid.as_ref()?;
+        let pixel_x = (x - self.origin.x) / self.pixel_size;
+        let pixel_y = (z - self.origin.y) / self.pixel_size;
+        if pixel_x < 0.0 || pixel_y < 0.0 || pixel_x > (grid.width - 1) as f64 || pixel_y > (grid.height - 1) as f64 {
+            return None;
+        }
+        let sample = match self.filter {
+            HeightmapFilter::Bilinear => grid.bilinear(pixel_x, pixel_y),
+            HeightmapFilter::Bicubic => grid.bicubic(pixel_x, pixel_y),
+        };
+        return Some(self.height_min + sample * (self.height_max - self.height_min));
+    }
+
+    // everything about the heightmap that changes the generated heights, for TerrainConfig::generator_hash
+    pub fn hash_bytes(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(self.path.as_bytes());
+        bytes.extend_from_slice(&self.origin.x.to_le_bytes());
+        bytes.extend_from_slice(&self.origin.y.to_le_bytes());
+        bytes.extend_from_slice(&self.pixel_size.to_le_bytes());
+        bytes.extend_from_slice(&self.height_min.to_le_bytes());
+        bytes.extend_from_slice(&self.height_max.to_le_bytes());
+        bytes.push(self.filter as u8);
+        match self.outside {
+            OutsideHeightmap::Noise => bytes.push(0),
+            OutsideHeightmap::Flat(height) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&height.to_le_bytes());
+            }
+        }
+        if let Some(grid) = &self.grid {
+            bytes.extend_from_slice(&grid.content_hash.to_le_bytes());
+        }
+    }
+}
+
+// the lattice of a chunk resampled from the heightmap. lattice points outside the image take their height
+// from the procedural lattice, which is only generated when the chunk reaches past the image
+pub fn heightmap_lattice(
+    tricoord: TriCoord<i32>,
+    odd: bool,
+    heightmap: &HeightmapTerrain,
+    procedural: impl FnOnce() -> ChunkLattice
+) -> ChunkLattice {
+    let side = CHUNK_SIDE as usize;
+    let chunk_coord = trichunk_to_coord(tricoord, 0);
+    let mut lattice = ChunkLattice { side, odd, heights: vec![0.0; lattice_vertex_count(side)] };
+
+    let mut outside = Vec::new();
+    for row in 0..=side {
+        for col in 0..=(side - row) {
+            let position = lattice.position(row, col);
+            let index = lattice.index(row, col);
+            match heightmap.height_at(chunk_coord.x + position.x as f64, chunk_coord.z + position.z as f64) {
+                Some(height) => lattice.heights[index] = height,
+                None => outside.push(index),
+            }
+        }
+    }
+    if outside.is_empty() {
+        return lattice;
+    }
+
+    let fallback = match heightmap.outside {
+        OutsideHeightmap::Noise => procedural().heights,
+        OutsideHeightmap::Flat(height) => vec![height; lattice.heights.len()],
+    };
+    for index in outside {
+        lattice.heights[index] = fallback[index];
+    }
+    return lattice;
+}
+
+// the heightmap settings being edited in the debug panel, they go into the terrain config when applied
+#[derive(Resource)]
+pub struct HeightmapImport {
+    pub settings: HeightmapTerrain,
+    // draws the heightmap with square tiling next to the image, to compare it with the trianglets
+    pub square_reference: bool,
+}
+
+impl Default for HeightmapImport {
+    fn default() -> Self {
+        HeightmapImport {
+            settings: HeightmapTerrain::new("heightmaps/heightmap.png"),
+            square_reference: false,
+        }
+    }
+}
+
+// loads the image of the configured heightmap and hands it to the terrain config.
+// a heightmap that fails to load is dropped and the terrain stays procedural
+fn load_terrain_heightmap(
+    mut terrain_config: ResMut<TerrainConfig>,
+    asset_server: Res<AssetServer>,
+    heightmaps: Res<Assets<Heightmap>>,
+    mut loading: Local<Option<Handle<Heightmap>>>
+) {
+    let Some(heightmap) = terrain_config.heightmap.as_ref().filter(|heightmap| heightmap.grid.is_none()) else {
+        *loading = None;
+        return;
+    };
+    let path = heightmap.path.clone();
+    // the path can change while the last one is still loading
+    if loading.as_ref().and_then(|handle| handle.path()).map(|asset_path| asset_path.to_string()) != Some(path.clone()) {
+        *loading = Some(asset_server.load::<Heightmap>(path.clone()));
+    }
+    let Some(handle) = loading.clone() else {
+        return;
+    };
+
+    if let Some(loaded) = heightmaps.get(&handle) {
+        info!("loaded heightmap {}: {} x {} pixels", path, loaded.grid.width, loaded.grid.height);
+        if let Some(heightmap) = terrain_config.heightmap.as_mut() {
+            heightmap.grid = Some(loaded.grid.clone());
+        }
+        *loading = None;
+    } else if let Some(LoadState::Failed(error)) = asset_server.get_load_state(&handle) {
+        warn!("could not load heightmap {}: {}", path, error);
+        terrain_config.heightmap = None;
+        *loading = None;
+    }
+}
+
+const SQUARE_REFERENCE_GAP:f64 = 32.0;
+const MAX_SQUARE_REFERENCE_CELLS:usize = 512;
+
+#[derive(Component)]
+pub struct SquareReference {
+    // the generator hash it was built for, it is rebuilt when that changes
+    generator_hash: u64,
+}
+
+fn update_square_reference(
+    heightmap_import: Res<HeightmapImport>,
+    terrain_config: Res<TerrainConfig>,
+    query: Query<(Entity, &SquareReference)>,
+    environ_assets: Option<Res<TerrainHandles>>,
+    floating_origin: Res<FloatingOrigin>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut commands: Commands
+) {
+    let heightmap = terrain_config.heightmap.as_ref().filter(|heightmap| heightmap.grid.is_some());
+    let wanted = heightmap_import.square_reference && heightmap.is_some();
+    let generator_hash = terrain_config.generator_hash();
+
+    let mut current = false;
+    for (entity, reference) in query.iter() {
+        if wanted && reference.generator_hash == generator_hash {
+            current = true;
+        } else {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+    let (Some(heightmap), Some(environ_assets)) = (heightmap, environ_assets) else {
+        return;
+    };
+    if !wanted || current {
+        return;
+    }
+
+    // next to the image along +x, so both tilings can be looked at side by side
+    let extent = heightmap.extent().unwrap_or(DVec2::ZERO);
+    let corner = DVec3::new(heightmap.origin.x + extent.x + SQUARE_REFERENCE_GAP, 0.0, heightmap.origin.y);
+    commands.spawn((
+        MaterialMeshBundle {
+            mesh: meshes.add(square_reference_mesh(heightmap)),
+            material: environ_assets.mat_hdls["my_mat"].clone().typed::<ExtendedMaterial<StandardMaterial, MyMaterial>>(),
+            transform: Transform::from_translation(floating_origin.to_local(corner)),
+            ..default()
+        },
+        SquareReference { generator_hash },
+        Name::new("SquareReference"),
+    ));
+}
+
+// the image area of the heightmap on a square grid, two triangles per cell, sampled with the same filter as the terrain.
+// positions start at the top left pixel
+fn square_reference_mesh(heightmap: &HeightmapTerrain) -> Mesh {
+    let extent = heightmap.extent().unwrap_or(DVec2::ZERO);
+    // as many vertices per area as the triangle lattice, so only the tiling differs
+    let lattice_spacing = ((TRI_SIDE * TRI_ALTITUDE) as f64).sqrt();
+    let cells_x = ((extent.x / lattice_spacing).ceil() as usize).clamp(1, MAX_SQUARE_REFERENCE_CELLS);
+    let cells_z = ((extent.y / lattice_spacing).ceil() as usize).clamp(1, MAX_SQUARE_REFERENCE_CELLS);
+    let (step_x, step_z) = (extent.x / cells_x as f64, extent.y / cells_z as f64);
+
+    let height = |i: usize, j: usize| {
+        let (x, z) = (i.min(cells_x) as f64 * step_x, j.min(cells_z) as f64 * step_z);
+        heightmap.height_at(heightmap.origin.x + x, heightmap.origin.y + z).unwrap_or(heightmap.height_min)
+    };
+
+    let mut positions = Vec::with_capacity((cells_x + 1) * (cells_z + 1));
+    let mut normals = Vec::with_capacity((cells_x + 1) * (cells_z + 1));
+    for j in 0..=cells_z {
+        for i in 0..=cells_x {
+            positions.push(Vec3::new((i as f64 * step_x) as f32, height(i, j), (j as f64 * step_z) as f32));
+            // central differences, one sided on the border
+            let dx = (height(i + 1, j) - height(i.saturating_sub(1), j)) / ((i + 1).min(cells_x) - i.saturating_sub(1)) as f32;
+            let dz = (height(i, j + 1) - height(i, j.saturating_sub(1))) / ((j + 1).min(cells_z) - j.saturating_sub(1)) as f32;
+            normals.push(Vec3::new(-dx / step_x as f32, 1.0, -dz / step_z as f32).normalize());
+        }
+    }
+
+    let vertex = |i: usize, j: usize| (j * (cells_x + 1) + i) as u32;
+    let mut indices = Vec::with_capacity(cells_x * cells_z * 6);
+    for j in 0..cells_z {
+        for i in 0..cells_x {
+            indices.extend([vertex(i, j), vertex(i, j + 1), vertex(i + 1, j)]);
+            indices.extend([vertex(i + 1, j), vertex(i, j + 1), vertex(i + 1, j + 1)]);
+        }
+    }
+
+    Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD)
+    .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+    .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
+    .with_inserted_indices(Indices::U32(indices))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{ImageBuffer, ImageFormat, Luma};
+
+    use super::*;
+
+    // heights rising by 1 per pixel along x and 10 per pixel along y, both filters give back a plane like that exactly
+    fn sloped_grid(width: usize, height: usize) -> HeightGrid {
+        let samples = (0..height).flat_map(|y| (0..width).map(move |x| x as f32 + 10.0 * y as f32)).collect();
+        HeightGrid { width, height, samples, content_hash: 0 }
+    }
+
+    fn png_bytes<P: image::PixelWithColorType>(image: ImageBuffer<P, Vec<P::Subpixel>>) -> Vec<u8>
+    where [P::Subpixel]: image::EncodableLayout {
+        let mut bytes = Cursor::new(Vec::new());
+        image.write_to(&mut bytes, ImageFormat::Png).unwrap();
+        bytes.into_inner()
+    }
+
+    #[test]
+    fn bilinear_blends_the_four_pixels_around() {
+        let grid = HeightGrid { width: 2, height: 2, samples: vec![0.0, 1.0, 2.0, 5.0], content_hash: 0 };
+        assert_eq!(grid.bilinear(0.0, 0.0), 0.0);
+        assert_eq!(grid.bilinear(1.0, 1.0), 5.0);
+        assert_eq!(grid.bilinear(0.5, 0.0), 0.5);
+        assert_eq!(grid.bilinear(0.0, 0.5), 1.0);
+        assert_eq!(grid.bilinear(0.5, 0.5), 2.0);
+        // past the edge the last pixels repeat
+        assert_eq!(grid.bilinear(3.0, 0.0), 1.0);
+    }
+
+    #[test]
+    fn bicubic_passes_through_the_pixels_and_keeps_planes_flat() {
+        let grid = sloped_grid(4, 4);
+        for y in 0..4 {
+            for x in 0..4 {
+                assert!((grid.bicubic(x as f64, y as f64) - grid.pixel(x, y)).abs() < 1e-5);
+            }
+        }
+        // the 4 x 4 pixels around the middle are all inside the image
+        assert!((grid.bicubic(1.5, 1.25) - 14.0).abs() < 1e-5);
+        assert!((grid.bilinear(1.5, 1.25) - 14.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn outside_the_image_the_fallback_is_used() {
+        let tricoord = TriCoord { a: 0, b: 0, c: 0 };
+        let chunk_coord = trichunk_to_coord(tricoord, 0);
+        let mut heightmap = HeightmapTerrain::new("test.heightmap.png");
+        heightmap.origin = DVec2::new(chunk_coord.x, chunk_coord.z);
+        heightmap.outside = OutsideHeightmap::Flat(-5.0);
+        heightmap.grid = Some(Arc::new(sloped_grid(4, 4)));
+        assert_eq!(heightmap.height_at(chunk_coord.x - 1.0, chunk_coord.z), None);
+
+        let lattice = heightmap_lattice(tricoord, tricoord.is_odd(), &heightmap, || panic!("flat does not need the noise"));
+        let (mut inside, mut outside) = (0, 0);
+        for row in 0..=lattice.side {
+            for col in 0..=(lattice.side - row) {
+                let position = lattice.position(row, col);
+                match heightmap.height_at(chunk_coord.x + position.x as f64, chunk_coord.z + position.z as f64) {
+                    Some(height) => {
+                        assert_eq!(lattice.height(row, col), height);
+                        inside += 1;
+                    }
+                    None => {
+                        assert_eq!(lattice.height(row, col), -5.0);
+                        outside += 1;
+                    }
+                }
+            }
+        }
+        assert!(inside > 0 && outside > 0);
+
+        heightmap.outside = OutsideHeightmap::Noise;
+        let procedural = || ChunkLattice { side: lattice.side, odd: lattice.odd, heights: vec![7.0; lattice.heights.len()] };
+        let noise_lattice = heightmap_lattice(tricoord, tricoord.is_odd(), &heightmap, procedural);
+        assert_eq!(noise_lattice.heights.iter().filter(|height| **height == 7.0).count(), outside);
+    }
+
+    #[test]
+    fn a_chunk_inside_the_image_does_not_generate_noise() {
+        let tricoord = TriCoord { a: 0, b: 0, c: 0 };
+        let mut heightmap = HeightmapTerrain::new("test.heightmap.png");
+        heightmap.origin = DVec2::splat(-1000.0);
+        heightmap.pixel_size = 1000.0;
+        heightmap.grid = Some(Arc::new(sloped_grid(3, 3)));
+        heightmap_lattice(tricoord, tricoord.is_odd(), &heightmap, || panic!("the chunk is inside the image"));
+    }
+
+    #[test]
+    fn eight_and_sixteen_bit_images_decode_to_the_same_range() {
+        let eight = decode_heightmap(&png_bytes(ImageBuffer::<Luma<u8>, _>::from_raw(3, 1, vec![0, 51, 255]).unwrap())).unwrap();
+        let sixteen = decode_heightmap(&png_bytes(ImageBuffer::<Luma<u16>, _>::from_raw(3, 1, vec![0, 51 * 257, u16::MAX]).unwrap())).unwrap();
+        assert_eq!((eight.width, eight.height), (3, 1));
+        assert_eq!(eight.samples, vec![0.0, 0.2, 1.0]);
+        assert_eq!(eight.samples, sixteen.samples);
+        // the hash is of the widened pixels, so it does not tell the two apart either
+        assert_eq!(eight.content_hash, sixteen.content_hash);
+
+        let fine = decode_heightmap(&png_bytes(ImageBuffer::<Luma<u16>, _>::from_raw(1, 1, vec![51 * 257 + 1]).unwrap())).unwrap();
+        assert!(fine.samples[0] > eight.samples[1]);
+        assert_ne!(fine.content_hash, eight.content_hash);
+    }
+}
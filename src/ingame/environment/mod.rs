@@ -1,9 +1,31 @@
 use std::f32::consts::PI;
 
 use bevy::{pbr::CascadeShadowConfigBuilder, prelude::*};
-use terrain::TerrainPlugin;
+use terrain::TerrainCorePlugin;
+use terrain_render::TerrainRenderPlugin;
+use super_chunk::SuperChunkPlugin;
+use chunk_members::ChunkMemberPlugin;
+use chunk_cache::ChunkCachePlugin;
+use sculpt::SculptPlugin;
+use terrain_edits::TerrainEditPlugin;
+use ground_material::GroundMaterialPlugin;
+use heightmap::HeightmapPlugin;
+use terrain_export::TerrainExportPlugin;
+use terrain_maps::TerrainMapPlugin;
 
 pub mod terrain;
+pub mod terrain_render;
+pub mod chunk_mesh;
+pub mod super_chunk;
+pub mod chunk_members;
+pub mod chunk_cache;
+pub mod chunk_format;
+pub mod sculpt;
+pub mod terrain_edits;
+pub mod ground_material;
+pub mod heightmap;
+pub mod terrain_export;
+pub mod terrain_maps;
 
 pub struct EnvironmentPlugin;
 
@@ -11,7 +33,17 @@ impl Plugin for EnvironmentPlugin {
     fn build(&self, app: &mut App) {
         app
         .add_systems(Startup, setup_ambience)
-        .add_plugins(TerrainPlugin)
+        .add_plugins(TerrainCorePlugin)
+        .add_plugins(TerrainRenderPlugin)
+        .add_plugins(SuperChunkPlugin)
+        .add_plugins(ChunkMemberPlugin)
+        .add_plugins(ChunkCachePlugin)
+        .add_plugins(SculptPlugin)
+        .add_plugins(TerrainEditPlugin)
+        .add_plugins(GroundMaterialPlugin)
+        .add_plugins(HeightmapPlugin)
+        .add_plugins(TerrainExportPlugin)
+        .add_plugins(TerrainMapPlugin)
         ;
     }
 }
@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+
+use bevy::{color::palettes::css::{ORANGE, WHITE}, math::DVec3, prelude::*, window::{CursorGrabMode, PrimaryWindow}};
+use bevy_rapier3d::prelude::{QueryFilter, RapierContext};
+use noise::{NoiseFn, Perlin};
+
+use crate::ingame::floating_origin::FloatingOrigin;
+use crate::ingame::tricoord::*;
+
+use super::chunk_mesh::{lattice_vertex_count, ChunkLattice};
+use super::ground_material::GroundMaterial;
+use super::terrain::{chunk_distance, ChunkHeights, ChunkStatus, Chunks, RemeshQueue, TerrainMesh};
+use super::terrain_edits::TerrainEdits;
+
+pub struct SculptPlugin;
+
+impl Plugin for SculptPlugin {
+    fn build(&self, app: &mut App) {
+        app
+        .init_resource::<SculptConfig>()
+        .register_type::<SculptConfig>()
+        .add_systems(Update, sculpt_terrain.run_if(run_if_sculpting))
+        ;
+    }
+}
+
+#[derive(Reflect, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Brush {
+    #[default]
+    Raise,
+    Lower,
+    // pulls every vertex towards the average of its 6 neighbors
+    Smooth,
+    // pulls the vertices towards the height where the stroke started
+    Flatten,
+    // raises and lowers by a noise sampled at the vertex
+    Noise,
+    // pulls the vertices towards target_height
+    SetHeight,
+    // paints paint_material on the trianglets under the brush, the heights stay
+    Paint,
+}
+
+pub const BRUSHES:[Brush; 7] = [Brush::Raise, Brush::Lower, Brush::Smooth, Brush::Flatten, Brush::Noise, Brush::SetHeight, Brush::Paint];
+
+#[derive(Resource, Reflect)]
+#[reflect(Resource)]
+pub struct SculptConfig {
+    // edit mode, holding the left mouse button sculpts under the cursor, or under the crosshair while the mouse is locked
+    pub active: bool,
+    pub brush: Brush,
+    pub radius: f32,
+    // height per second for raise, lower and noise, how fast the other brushes pull the vertices
+    pub strength: f32,
+    pub target_height: f32,
+    pub noise_frequency: f64,
+    pub paint_material: GroundMaterial,
+    // set by the debug panel, so clicking a slider does not sculpt the terrain behind it
+    pub pointer_over_ui: bool,
+}
+
+impl Default for SculptConfig {
+    fn default() -> Self {
+        SculptConfig {
+            active: false,
+            brush: Brush::Raise,
+            radius: 4.0,
+            strength: 4.0,
+            target_height: 40.0,
+            noise_frequency: 0.3,
+            paint_material: GroundMaterial::Path,
+            pointer_over_ui: false,
+        }
+    }
+}
+
+fn run_if_sculpting(sculpt_config: Res<SculptConfig>) -> bool {
+    sculpt_config.active
+}
+
+const SCULPT_NOISE_SEED:u32 = 11;
+// how far a ray picks the terrain
+const MAX_PICK_DISTANCE:f32 = 500.0;
+
+fn vertex_neighbors(key: VertexKey) -> [VertexKey; 6] {
+    let (x, z) = key;
+    [(x - 2, z), (x + 2, z), (x - 1, z - 1), (x + 1, z - 1), (x - 1, z + 1), (x + 1, z + 1)]
+}
+
+// the ray under the cursor, or through the middle of the screen when the cursor is locked for looking around
+fn pointer_ray(window: &Window, camera: &Camera, camera_transform: &GlobalTransform) -> Option<Ray3d> {
+    let viewport_position = if window.cursor.grab_mode == CursorGrabMode::Locked {
+        Vec2::new(window.width(), window.height()) * 0.5
+    } else {
+        window.cursor_position()?
+    };
+    camera.viewport_to_world(camera_transform, viewport_position)
+}
+
+fn sculpt_terrain(
+    mouse: Res<ButtonInput<MouseButton>>,
+    window_query: Query<&Window, With<PrimaryWindow>>,
+    camera_query: Query<(&Camera, &GlobalTransform), With<Camera3d>>,
+    rapier_context: Res<RapierContext>,
+    mut chunk_query: Query<(Entity, &TerrainMesh, &mut ChunkHeights)>,
+    chunks: Res<Chunks>,
+    sculpt_config: Res<SculptConfig>,
+    floating_origin: Res<FloatingOrigin>,
+    mut remesh_queue: ResMut<RemeshQueue>,
+    mut terrain_edits: ResMut<TerrainEdits>,
+    time: Res<Time>,
+    mut gizmos: Gizmos,
+    // the picked height when the stroke started, for the flatten brush
+    mut stroke_height: Local<Option<f32>>
+) {
+    if !mouse.pressed(MouseButton::Left) {
+        *stroke_height = None;
+        // everything since the button went down is one undo step
+        terrain_edits.end_stroke();
+    }
+    if sculpt_config.pointer_over_ui {
+        return;
+    }
+    let (Ok(window), Ok((camera, camera_transform))) = (window_query.get_single(), camera_query.get_single()) else {
+        return;
+    };
+    let Some(ray) = pointer_ray(window, camera, camera_transform) else {
+        return;
+    };
+    let Some((hit_entity, distance)) = rapier_context.cast_ray(ray.origin, *ray.direction, MAX_PICK_DISTANCE, true, QueryFilter::only_fixed()) else {
+        return;
+    };
+    if !chunk_query.contains(hit_entity) {
+        return;
+    }
+    let hit = ray.get_point(distance);
+    gizmos.circle(hit, Dir3::Y, sculpt_config.radius, if mouse.pressed(MouseButton::Left) { ORANGE } else { WHITE });
+
+    if !mouse.pressed(MouseButton::Left) {
+        return;
+    }
+    let flatten_height = *stroke_height.get_or_insert(hit.y);
+
+    let center = floating_origin.to_absolute(hit);
+    let radius = sculpt_config.radius;
+
+    if sculpt_config.brush == Brush::Paint {
+        // a trianglet is painted once its middle is under the brush
+        for (entity, terrain_mesh, heights) in chunk_query.iter() {
+            if chunk_distance(terrain_mesh.tricoord, center) > radius + CHUNK_SIDE as f32 {
+                continue;
+            }
+            let chunk_coord = trichunk_to_coord(terrain_mesh.tricoord, 0);
+            let chunk_origin = DVec3::new(chunk_coord.x, 0.0, chunk_coord.z);
+            let lattice = &heights.0;
+            let mut changed = false;
+            for (trianglet, corners) in lattice.trianglets().iter().enumerate() {
+                let middle = corners.iter().map(|(row, col)| lattice.position(*row, *col)).sum::<Vec3>() / 3.0;
+                if (chunk_origin + middle.as_dvec3()).xz().distance(center.xz()) as f32 >= radius {
+                    continue;
+                }
+                changed |= terrain_edits.paint(terrain_mesh.tricoord, trianglet as u32, sculpt_config.paint_material.id());
+            }
+            if changed {
+                remesh_queue.push(entity);
+            }
+        }
+        return;
+    }
+
+    // every vertex of the chunks under the brush by its vertex key, read before any of them changes. a border vertex
+    // is read from whichever chunk comes first, and every chunk writes the same new height for it
+    let mut samples: HashMap<VertexKey, f32> = HashMap::new();
+    let mut touched = Vec::new();
+    for (entity, terrain_mesh, heights) in chunk_query.iter() {
+        if chunk_distance(terrain_mesh.tricoord, center) > radius + CHUNK_SIDE as f32 {
+            continue;
+        }
+        let chunk_coord = trichunk_to_coord(terrain_mesh.tricoord, 0);
+        let chunk_origin = DVec3::new(chunk_coord.x, 0.0, chunk_coord.z);
+        let lattice = &heights.0;
+        for row in 0..=lattice.side {
+            for col in 0..=(lattice.side - row) {
+                let position = chunk_origin + lattice.position(row, col).as_dvec3();
+                samples.entry(vertex_key(position)).or_insert(position.y as f32);
+            }
+        }
+        touched.push(entity);
+    }
+
+    let detail_noise = Perlin::new(SCULPT_NOISE_SEED);
+    let step = sculpt_config.strength * time.delta_seconds();
+    let pull = |height: f32, target: f32, falloff: f32| height + (target - height) * (step * falloff).min(1.0);
+    // the height change of every moved vertex, for the chunks under the brush that are not spawned yet
+    let mut moved: HashMap<VertexKey, f32> = HashMap::new();
+
+    for entity in touched {
+        let Ok((_, terrain_mesh, mut heights)) = chunk_query.get_mut(entity) else {
+            continue;
+        };
+        let chunk_coord = trichunk_to_coord(terrain_mesh.tricoord, 0);
+        let chunk_origin = DVec3::new(chunk_coord.x, 0.0, chunk_coord.z);
+        let lattice = &mut heights.0;
+        let mut changed = false;
+
+        for row in 0..=lattice.side {
+            for col in 0..=(lattice.side - row) {
+                let position = chunk_origin + lattice.position(row, col).as_dvec3();
+                let offset = position.xz().distance(center.xz()) as f32;
+                if offset >= radius {
+                    continue;
+                }
+                // smooth falloff from 1 in the middle to 0 at the radius
+                let t = offset / radius;
+                let falloff = 1.0 - t * t * (3.0 - 2.0 * t);
+
+                let key = vertex_key(position);
+                let height = samples[&key];
+                let new_height = match sculpt_config.brush {
+                    Brush::Raise => height + step * falloff,
+                    Brush::Lower => height - step * falloff,
+                    Brush::Smooth => {
+                        let neighbors: Vec<f32> = vertex_neighbors(key).iter().filter_map(|neighbor| samples.get(neighbor).copied()).collect();
+                        if neighbors.is_empty() {
+                            height
+                        } else {
+                            pull(height, neighbors.iter().sum::<f32>() / neighbors.len() as f32, falloff)
+                        }
+                    }
+                    Brush::Flatten => pull(height, flatten_height, falloff),
+                    Brush::Noise => {
+                        let frequency = sculpt_config.noise_frequency;
+                        height + detail_noise.get([position.x * frequency, position.z * frequency]) as f32 * step * falloff
+                    }
+                    Brush::SetHeight => pull(height, sculpt_config.target_height, falloff),
+                    // handled above, painting leaves the heights alone
+                    Brush::Paint => height,
+                };
+
+                let index = lattice.index(row, col);
+                if lattice.heights[index] != new_height {
+                    moved.insert(key, new_height - lattice.heights[index]);
+                    terrain_edits.record(terrain_mesh.tricoord, index as u32, new_height - lattice.heights[index]);
+                    lattice.heights[index] = new_height;
+                    changed = true;
+                }
+            }
+        }
+
+        if changed {
+            remesh_queue.push(entity);
+        }
+    }
+
+    // a chunk still generating shares its border with the spawned chunks, it gets the same change there or it spawns
+    // with a crack. spawn_generated_chunks applies what its task missed
+    for (tricoord, state) in chunks.states.iter() {
+        if !matches!(state.status, ChunkStatus::Queued | ChunkStatus::Generating | ChunkStatus::Meshed) {
+            continue;
+        }
+        if chunk_distance(*tricoord, center) > radius + CHUNK_SIDE as f32 {
+            continue;
+        }
+        let chunk_coord = trichunk_to_coord(*tricoord, 0);
+        let chunk_origin = DVec3::new(chunk_coord.x, 0.0, chunk_coord.z);
+        let side = CHUNK_SIDE as usize;
+        // only the layout matters for the vertex keys, not the heights
+        let layout = ChunkLattice { side, odd: tricoord.is_odd(), heights: vec![0.0; lattice_vertex_count(side)] };
+        for row in 0..=side {
+            for col in 0..=(side - row) {
+                let position = chunk_origin + layout.position(row, col).as_dvec3();
+                if let Some(height_change) = moved.get(&vertex_key(position)) {
+                    terrain_edits.record(*tricoord, layout.index(row, col) as u32, *height_change);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use bevy::tasks::futures_lite::future;
+use bevy::tasks::{block_on, AsyncComputeTaskPool, Task};
+
+use bevy::math::{DVec2, DVec3};
+
+use crate::ingame::floating_origin::FloatingOrigin;
+use crate::ingame::tricoord::*;
+
+use super::chunk_mesh::merge_chunk_meshes;
+use super::terrain::{run_if_anchored, Chunks, TerrainConfig, TerrainMesh};
+use super::terrain_render::{insert_terrain_material, ChunkMeshRevision, SelectedTerrainMat, TerrainHandles};
+
+pub struct SuperChunkPlugin;
+
+impl Plugin for SuperChunkPlugin {
+    fn build(&self, app: &mut App) {
+        app
+        .init_resource::<SuperChunks>()
+        .add_systems(Update, (group_far_chunks, receive_super_chunk_meshes).chain().run_if(run_if_anchored))
+        ;
+    }
+}
+
+// a super chunk is a triangle this many chunks wide, holding SUPER_CHUNK_SCALE * SUPER_CHUNK_SCALE chunks
+pub const SUPER_CHUNK_SCALE:i32 = 4;
+
+#[derive(Component)]
+pub struct SuperChunkMesh {
+    pub parent: TriCoord<i32>,
+}
+
+// the chunks, their lod levels and mesh revisions a super chunk mesh was built from.
+// sculpting, painting and loading edits only bump the revision, so it is part of the key
+type SuperChunkMembers = Vec<(TriCoord<i32>, u8, u32)>;
+
+#[derive(Default)]
+struct SuperChunk {
+    merged: bool,
+    entity: Option<Entity>,
+    built_members: SuperChunkMembers,
+    building_members: Option<SuperChunkMembers>,
+    task: Option<Task<Mesh>>,
+    // wasm has no background tasks, so the mesh is merged in place and waits here
+    merged_mesh: Option<Mesh>,
+}
+
+#[derive(Resource, Default)]
+pub struct SuperChunks {
+    groups: HashMap<TriCoord<i32>, SuperChunk>,
+}
+
+impl SuperChunks {
+    // super chunks that are currently drawn, and how many chunks they stand in for
+    pub fn drawn(&self) -> (usize, usize) {
+        self.groups.values()
+        .filter(|group| group.merged && group.entity.is_some())
+        .fold((0, 0), |(groups, chunks), group| (groups + 1, chunks + group.built_members.len()))
+    }
+}
+
+// absolute, like the chunk coordinates
+fn super_chunk_origin(parent: TriCoord<i32>) -> DVec3 {
+    let parent_coord = trichunk_parent_to_coord(parent, SUPER_CHUNK_SCALE);
+    DVec3::new(parent_coord.x, 0.0, parent_coord.z)
+}
+
+// hides far chunks behind merged super chunk meshes and shows them again when the player gets close
+fn group_far_chunks(
+    mut chunk_query: Query<(Entity, &TerrainMesh, &ChunkMeshRevision, &Handle<Mesh>, &Transform, &mut Visibility)>,
+    chunks: Res<Chunks>,
+    terrain_config: Res<TerrainConfig>,
+    meshes: Res<Assets<Mesh>>,
+    mut super_chunks: ResMut<SuperChunks>,
+    floating_origin: Res<FloatingOrigin>,
+    mut commands: Commands
+) {
+    let mut members: HashMap<TriCoord<i32>, Vec<Entity>> = HashMap::new();
+    for (entity, terrain_mesh, ..) in chunk_query.iter() {
+        // chunks at the very edge of the coordinate range are simply never merged
+        let Ok(parent) = trichunk_parent(terrain_mesh.tricoord, SUPER_CHUNK_SCALE) else {
+            continue;
+        };
+        members.entry(parent).or_default().push(entity);
+    }
+
+    // super chunks without any spawned chunks left
+    super_chunks.groups.retain(|parent, group| {
+        if members.contains_key(parent) {
+            return true;
+        }
+        if let Some(entity) = group.entity {
+            commands.entity(entity).despawn();
+        }
+        false
+    });
+
+    for (parent, entities) in members {
+        let origin = super_chunk_origin(parent);
+        let distance = chunks.anchor_distance(DVec2::new(origin.x, origin.z));
+        let local_origin = floating_origin.to_local(origin);
+        let group = super_chunks.groups.entry(parent).or_default();
+
+        // same hysteresis as the lod levels, so a group does not flip between merged and split on the boundary
+        if !group.merged && distance > terrain_config.superchunk_distance + terrain_config.lod_hysteresis {
+            group.merged = true;
+        } else if group.merged && distance < terrain_config.superchunk_distance - terrain_config.lod_hysteresis {
+            group.merged = false;
+        }
+
+        if !group.merged {
+            // split back into the individual chunks
+            if let Some(entity) = group.entity.take() {
+                commands.entity(entity).despawn();
+            }
+            *group = SuperChunk::default();
+            for entity in entities {
+                if let Ok((.., mut visibility)) = chunk_query.get_mut(entity) {
+                    visibility.set_if_neq(Visibility::Inherited);
+                }
+            }
+            continue;
+        }
+
+        let mut current_members: SuperChunkMembers = Vec::with_capacity(entities.len());
+        for entity in entities.iter() {
+            let Ok((_, terrain_mesh, revision, .., mut visibility)) = chunk_query.get_mut(*entity) else {
+                continue;
+            };
+            let member = (terrain_mesh.tricoord, terrain_mesh.lod, revision.0);
+            // chunks stay visible until a super chunk mesh that includes them is spawned
+            if group.entity.is_some() && group.built_members.contains(&member) {
+                visibility.set_if_neq(Visibility::Hidden);
+            } else {
+                visibility.set_if_neq(Visibility::Inherited);
+            }
+            current_members.push(member);
+        }
+        current_members.sort_by_key(|(tricoord, lod, revision)| (tricoord.a, tricoord.b, tricoord.c, *lod, *revision));
+
+        if group.building_members.as_ref().unwrap_or(&group.built_members) == &current_members {
+            continue;
+        }
+
+        // members changed, so merge copies of their meshes in the background. a task for an older set is dropped
+        let parts: Vec<(Mesh, Vec3)> = entities.iter().filter_map(|entity| {
+            let (_, _, _, mesh_handle, transform, _) = chunk_query.get(*entity).ok()?;
+            Some((meshes.get(mesh_handle)?.clone(), transform.translation - local_origin))
+        }).collect();
+
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            group.task = Some(AsyncComputeTaskPool::get().spawn(async move {
+                merge_chunk_meshes(&parts)
+            }));
+        }
+        #[cfg(target_arch = "wasm32")]
+        {
+            group.merged_mesh = Some(merge_chunk_meshes(&parts));
+        }
+        group.building_members = Some(current_members);
+    }
+}
+
+fn receive_super_chunk_meshes(
+    mut super_chunks: ResMut<SuperChunks>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    environ_assets: Res<TerrainHandles>,
+    selected_mat: Res<SelectedTerrainMat>,
+    floating_origin: Res<FloatingOrigin>,
+    mut commands: Commands
+) {
+    for (parent, group) in super_chunks.groups.iter_mut() {
+        let merged_mesh = match (group.merged_mesh.take(), group.task.as_mut()) {
+            (Some(mesh), _) => mesh,
+            (None, Some(task)) => {
+                let Some(mesh) = block_on(future::poll_once(task)) else {
+                    continue;
+                };
+                mesh
+            }
+            (None, None) => continue,
+        };
+        group.task = None;
+        group.built_members = group.building_members.take().unwrap_or_default();
+
+        let mesh_handle = meshes.add(merged_mesh);
+        match group.entity {
+            // the old mesh asset is freed when its handle is replaced
+            Some(entity) => {
+                commands.entity(entity).insert(mesh_handle);
+            }
+            None => {
+                let mut entity_commands = commands.spawn((
+                    mesh_handle,
+                    SpatialBundle::from_transform(Transform::from_translation(floating_origin.to_local(super_chunk_origin(*parent)))),
+                    SuperChunkMesh { parent: *parent },
+                    Name::new("SuperChunkMesh"),
+                ));
+                insert_terrain_material(&mut entity_commands, &environ_assets, &selected_mat);
+                group.entity = Some(entity_commands.id());
+            }
+        }
+    }
+}
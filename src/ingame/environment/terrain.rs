@@ -1,224 +1,618 @@
-use std::{any::TypeId, collections::HashMap, thread, time::Duration};
+use std::{any::TypeId, collections::{HashMap, VecDeque}, task::Poll, thread, time::Duration};
 
-use bevy::{render::render_resource::{AsBindGroup, ShaderRef}, color::palettes::css::{BLACK, GREEN, RED, YELLOW}, pbr::{ExtendedMaterial, MaterialExtension}, prelude::*, render::{mesh::{Indices, PrimitiveTopology, VertexAttributeValues}, render_asset::RenderAssetUsages, render_resource::{Extent3d, TextureDimension, TextureFormat}, texture::{ImageSampler, ImageSamplerDescriptor}}};
-use bevy_fps_controller::controller::LogicalPlayer;
+use bevy::{color::palettes::css::{BLACK, GREEN, RED, YELLOW}, prelude::*, render::{mesh::{Indices, PrimitiveTopology, VertexAttributeValues}, render_asset::RenderAssetUsages, render_resource::{Extent3d, TextureDimension, TextureFormat}, texture::{ImageSampler, ImageSamplerDescriptor}}};
 use bevy_inspector_egui::{prelude::ReflectInspectorOptions, InspectorOptions};
 use bevy_rapier3d::prelude::{Collider, ComputedColliderShape, RigidBody};
-use noise::{core::worley::{distance_functions::euclidean, worley_2d, ReturnType}, permutationtable::PermutationTable, utils::{NoiseMap, NoiseMapBuilder, PlaneMapBuilder}, Blend, Checkerboard, Fbm, Perlin, RidgedMulti, Vector2};
+use noise::{core::worley::{distance_functions::euclidean, worley_2d, ReturnType}, permutationtable::PermutationTable, utils::{NoiseMap, NoiseMapBuilder, PlaneMapBuilder}, Blend, Checkerboard, Fbm, Perlin, RidgedMulti, Vector2, NoiseFn};
+use bevy::math::{DVec2, DVec3};
 use bevy::tasks::futures_lite::future;
 use bevy::tasks::{block_on, AsyncComputeTaskPool, Task};
 
+use crate::ingame::floating_origin::FloatingOrigin;
 use crate::ingame::tricoord::*;
 
-pub struct TerrainPlugin;
+use super::chunk_mesh::*;
+use super::chunk_cache::ChunkCache;
+use super::terrain_edits::{apply_chunk_deltas, apply_chunk_paint, ChunkPaint, TerrainEdits};
+use super::heightmap::{heightmap_lattice, HeightmapTerrain};
+use super::ground_material::{classify_ground, ChunkMaterials};
+use super::chunk_members::{park_chunk_members, ChunkMember};
 
-impl Plugin for TerrainPlugin {
+// chunk data, generation, colliders and the queries on them. it needs no window, renderer or mesh assets,
+// so it also runs under MinimalPlugins, TerrainRenderPlugin draws the chunks on top of it
+pub struct TerrainCorePlugin;
+
+impl Plugin for TerrainCorePlugin {
     fn build(&self, app: &mut App) {
         app
-        .add_plugins(MaterialPlugin::<ExtendedMaterial<StandardMaterial, MyMaterial>,>::default())
-        .add_systems(Startup, setup_terrain_assets)
-        .insert_resource(SelectedTerrainMat {
-            selected_mat: "my_mat".into()
-        })
-        .register_type::<SelectedTerrainMat>()
+        .add_systems(Startup, spawn_terrain_root)
+        // after Startup, so a config set up there counts as the one the first chunks are generated with
+        .add_systems(PostStartup, init_generator_hash)
         .init_resource::<TerrainConfig>()
         .init_resource::<Chunks>()
         .register_type::<Chunks>()
         .init_resource::<ChunkTasks>()
-        .add_systems(Update, chunks_near_player)
-        .add_systems(Update, (begin_generating_chunks, receive_generated_chunks).run_if(run_if_terrain_active) )
+        .init_resource::<TerrainStats>()
+        .register_type::<TerrainAnchor>()
+        // read by the generation, their own plugins add the systems around them when they are there
+        .init_resource::<FloatingOrigin>()
+        .init_resource::<ChunkCache>()
+        .init_resource::<TerrainEdits>()
+        .add_event::<ChunkGenerated>()
+        .add_event::<ChunkSpawned>()
+        .add_event::<ChunkUnloaded>()
+        .add_event::<ChunkModified>()
+        .add_systems(Update, chunks_near_anchors)
+        .add_systems(Update, (begin_generating_chunks, receive_generated_chunks, spawn_generated_chunks).chain().after(unload_far_chunks).run_if(run_if_terrain_active).run_if(run_if_anchored) )
+        .init_resource::<RefineTasks>()
+        .add_systems(Update, unload_far_chunks.after(chunks_near_anchors).run_if(run_if_anchored))
+        .add_systems(Update, (refine_chunks_near_player, receive_refined_chunks).chain().after(chunks_near_anchors).run_if(run_if_anchored))
+        .init_resource::<RemeshQueue>()
+        // after the refined chunks land, so a refine that started before an edit can not overwrite it
+        .add_systems(Update, remesh_queued_chunks.after(receive_refined_chunks).after(spawn_generated_chunks).run_if(run_if_anchored))
         ;
     }
 }
 
+// inserted by TerrainRenderPlugin. without it the tasks skip the render meshes and chunks only get heights and colliders
+#[derive(Resource)]
+pub struct TerrainMeshing;
+
+// a render mesh built by the core for a chunk entity, TerrainRenderPlugin moves it into the mesh assets
+#[derive(Component)]
+pub struct ChunkMeshUpdate(pub Mesh);
+
+// generation also waits for a configured heightmap to load, so no chunk is generated from the noise in its place
 fn run_if_terrain_active(terrain_config: Res<TerrainConfig>) -> bool {
-    terrain_config.active
+    terrain_config.active && terrain_config.heightmap.as_ref().map_or(true, |heightmap| heightmap.grid.is_some())
 }
 
-#[derive(Resource)]
+// without anchors there is nothing to stream around, so generating, unloading and remeshing pause
+pub fn run_if_anchored(chunks: Res<Chunks>) -> bool {
+    !chunks.anchors.is_empty()
+}
+
+// terrain is generated around every entity with this. more anchors generate the union of their chunks,
+// so other players, cinematic cameras or ai far from the player get ground under them too.
+#[derive(Component, Reflect, Clone, Copy, Default)]
+#[reflect(Component)]
+pub struct TerrainAnchor {
+    // None follows chunk_gen_radius from the terrain config
+    pub radius: Option<f32>,
+}
+
+// on anchors too far out for chunk coordinates, so they are warned about once instead of every frame
+#[derive(Component)]
+pub struct AnchorOutOfRange;
+
+// every chunk entity is a child of this, persisted chunk members wait here while their chunk is unloaded
+#[derive(Component)]
+pub struct TerrainRoot;
+
+fn spawn_terrain_root(mut commands: Commands) {
+    commands.spawn((SpatialBundle::default(), TerrainRoot, Name::new("TerrainRoot")));
+}
+
+fn init_generator_hash(mut chunks: ResMut<Chunks>, terrain_config: Res<TerrainConfig>) {
+    chunks.generator_hash = terrain_config.generator_hash();
+}
+
+// a chunk finished generating and waits to be spawned
+#[derive(Event, Clone, Copy, Debug)]
+pub struct ChunkGenerated {
+    pub tricoord: TriCoord<i32>,
+    pub lod: u8,
+}
+
+#[derive(Event, Clone, Copy, Debug)]
+pub struct ChunkSpawned {
+    pub tricoord: TriCoord<i32>,
+    pub entity: Entity,
+}
+
+// sent when the entity is despawned, it is gone by the time readers see this
+#[derive(Event, Clone, Copy, Debug)]
+pub struct ChunkUnloaded {
+    pub tricoord: TriCoord<i32>,
+    pub entity: Entity,
+}
+
+// the mesh of a spawned chunk was replaced
+#[derive(Event, Clone, Copy, Debug)]
+pub struct ChunkModified {
+    pub tricoord: TriCoord<i32>,
+    pub entity: Entity,
+    // lod changes only touch the mesh, refining and editing the heights also replace the collider
+    pub collider_changed: bool,
+}
+
+// anchor and chunk positions are absolute, so the chunk bookkeeping does not care about the floating origin
+#[derive(Reflect, Clone, Copy, Debug)]
+pub struct AnchorPoint {
+    pub position: DVec3,
+    pub radius: f32,
+}
+
+#[derive(Resource, Clone)]
 pub struct TerrainConfig {
+    pub seed:u32,
+    // keeps generated chunk heights on disk and loads them instead of generating them again, native only
+    pub chunk_cache:bool,
     pub chunk_gen_radius:f32,
+    // chunks are unloaded once they are this far outside the generation radius
+    pub unload_hysteresis:f32,
+    // how many chunk generation tasks run at the same time
+    pub max_generation_tasks:usize,
+    // 0 generates strictly nearest first, towards 1 chunks in front of the camera come before closer chunks behind it
+    pub view_priority:f32,
+    // how many finished chunks are spawned per frame, the rest wait for the next frames
+    pub max_spawns_per_frame:usize,
+    // milliseconds per frame the wasm build spends generating chunks, it has no background tasks
+    pub wasm_frame_budget_ms:f32,
     pub active:bool,
-
+    pub mesh_layout:MeshLayout,
+    // distances from the generation origin where chunks switch to the next coarser lod level
+    pub lod_distances:[f32; CHUNK_LOD_LEVELS as usize - 1],
+    // how far past a lod distance a chunk has to move before it switches, so it does not flicker on the boundary
+    pub lod_hysteresis:f32,
+    // chunks this close to the player have every trianglet split into 4, refine_depth times
+    pub refine_radius:f32,
+    pub refine_depth:u8,
+    // the high frequency noise that gives the refined vertices their own heights
+    pub detail_amplitude:f32,
+    pub detail_frequency:f64,
+    // groups of chunks further away than this are drawn as one merged super chunk mesh
+    pub superchunk_distance:f32,
+    // merges near coplanar trianglets of the render meshes and colliders, keeping the chunk borders as they are
+    pub simplify:bool,
+    pub simplify_error:f32,
+    // milliseconds per frame spent rebuilding the meshes and colliders of edited chunks
+    pub remesh_budget_ms:f32,
+    // a heightmap image in place of the procedural heights, None is procedural everywhere
+    pub heightmap:Option<HeightmapTerrain>,
 }
 
 impl Default for TerrainConfig {
     fn default() -> Self {
         TerrainConfig {
+            seed: 0,
+            chunk_cache: cfg!(not(target_arch = "wasm32")),
             chunk_gen_radius: 20.0,
-            active:true
+            unload_hysteresis: 16.0,
+            max_generation_tasks: 8,
+            view_priority: 0.5,
+            max_spawns_per_frame: 4,
+            wasm_frame_budget_ms: 8.0,
+            active:true,
+            mesh_layout: MeshLayout::Indexed,
+            lod_distances: [48.0, 96.0, 192.0],
+            lod_hysteresis: 8.0,
+            refine_radius: 24.0,
+            refine_depth: 1,
+            detail_amplitude: 0.3,
+            detail_frequency: 0.6,
+            superchunk_distance: 160.0,
+            simplify: false,
+            simplify_error: 0.25,
+            remesh_budget_ms: 4.0,
+            heightmap: None,
         }
     }
 }
 
+// bump this when the generated heights change for the same config, so caches of older builds are thrown away
+const GENERATOR_VERSION:u32 = 1;
+
+impl TerrainConfig {
+    // a hash of everything that decides the generated heights. the chunk cache is keyed by it,
+    // so changing any of it regenerates the chunks instead of loading stale ones from disk
+    pub fn generator_hash(&self) -> u64 {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&GENERATOR_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.seed.to_le_bytes());
+        bytes.extend_from_slice(&CHUNK_SIDE.to_le_bytes());
+        if let Some(heightmap) = &self.heightmap {
+            heightmap.hash_bytes(&mut bytes);
+        }
+        return fnv1a(&bytes);
+    }
+}
 
-fn chunks_near_player(
-    query: Query<&Transform, With<LogicalPlayer>>,
-    mut chunks: ResMut<Chunks>,
-    terrain_config: Res<TerrainConfig>
-) /* -> Vec<TriCoord<i16>> */ {
-    let player_transform = query.get_single().unwrap();
-
-    let gen_origin = player_transform.translation; 
-
-    let in_radius_tricoords = tricoord_vec_gen_distance(Coord {x:gen_origin.x, z:gen_origin.z}, terrain_config.chunk_gen_radius);
-
-    chunks.in_range = in_radius_tricoords.clone();
+// std's hasher is not guaranteed to stay the same between rust versions, this one is written to disk
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash:u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    return hash;
 }
 
-#[derive(Asset, AsBindGroup, TypePath, Debug, Clone)]
-pub struct MyMaterial {}
+// trianglets per chunk side halve with every lod level: 16, 8, 4, 2
+pub const CHUNK_LOD_LEVELS:u8 = 4;
 
-impl MaterialExtension for MyMaterial {
-    fn vertex_shader() -> ShaderRef {
-        "shaders/animate_shader.wgsl".into()
+// only moves away from the current level once the distance is past the boundary by the hysteresis margin
+pub fn select_chunk_lod(current: u8, distance: f32, terrain_config: &TerrainConfig) -> u8 {
+    let mut level = current;
+    while level < CHUNK_LOD_LEVELS - 1 && distance > terrain_config.lod_distances[level as usize] + terrain_config.lod_hysteresis {
+        level += 1;
+    }
+    while level > 0 && distance < terrain_config.lod_distances[level as usize - 1] - terrain_config.lod_hysteresis {
+        level -= 1;
     }
+    return level;
+}
 
-    // fn fragment_shader() -> ShaderRef {
-    //     "shaders/animate_shader.wgsl".into()
-    // }
+pub fn chunk_distance(tricoord: TriCoord<i32>, origin: DVec3) -> f32 {
+    let chunk_coord = trichunk_to_coord(tricoord, 0);
+    DVec2::new(chunk_coord.x, chunk_coord.z).distance(origin.xz()) as f32
 }
 
-#[derive(Reflect, Resource, InspectorOptions)]
-#[reflect(Resource, InspectorOptions)]
-pub struct SelectedTerrainMat {
-    pub selected_mat: String
+// lower comes first. the distance to the nearest anchor is shrunk for chunks in the view direction and grown for chunks behind it
+fn chunk_priority(tricoord: TriCoord<i32>, chunks: &Chunks, terrain_config: &TerrainConfig) -> f32 {
+    let chunk_coord = trichunk_to_coord(tricoord, 0);
+    let view_dir = chunks.view_dir.xz().normalize_or_zero();
+    chunks.anchors.iter().map(|anchor| {
+        let offset = (DVec2::new(chunk_coord.x, chunk_coord.z) - anchor.position.xz()).as_vec2();
+        let facing = offset.normalize_or_zero().dot(view_dir);
+        offset.length() * (1.0 - terrain_config.view_priority.clamp(0.0, 1.0) * facing)
+    })
+    .fold(f32::INFINITY, f32::min)
 }
 
-#[derive(Resource)]
-pub struct TerrainHandles {
-    pub mat_hdls: HashMap<String, UntypedHandle>,
-    mesh_hdls: HashMap<String, Handle<Mesh>>,
-    height_map_hdls: HashMap<Coord<i16>, UntypedHandle>
-}
-fn setup_terrain_assets(
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut standard_materials: ResMut<Assets<StandardMaterial>>,
-    mut mymat_materials: ResMut<Assets<ExtendedMaterial<StandardMaterial, MyMaterial>>>,
+// queued chunks in range, in the order they should be generated in
+fn generation_queue(chunks: &Chunks, terrain_config: &TerrainConfig) -> Vec<TriCoord<i32>> {
+    let mut queue: Vec<(f32, TriCoord<i32>)> = chunks.states.iter()
+    .filter(|(_, state)| state.status == ChunkStatus::Queued && state.in_range)
+    .map(|(tricoord, _)| (chunk_priority(*tricoord, chunks, terrain_config), *tricoord))
+    .collect();
+    queue.sort_by(|a, b| a.0.total_cmp(&b.0));
+    return queue.into_iter().map(|(_, tricoord)| tricoord).collect();
+}
+
+
+fn chunks_near_anchors(
+    anchor_query: Query<(Entity, &GlobalTransform, &TerrainAnchor, Has<AnchorOutOfRange>)>,
+    camera_query: Query<&GlobalTransform, With<Camera3d>>,
+    mut chunks: ResMut<Chunks>,
+    terrain_config: Res<TerrainConfig>,
+    floating_origin: Res<FloatingOrigin>,
+    time: Res<Time>,
     mut commands: Commands
 ) {
-    let shiny_material = standard_materials.add(
-        StandardMaterial {
-            // base_color: Srgba::hex("#6dbe4b").unwrap().into(),
-            base_color: Color::srgb(0.5, 0.5, 0.5),
-            metallic: 1.0,
-            perceptual_roughness: 0.0,
-            reflectance: 1.0,
-            ..default()
-    });
+    chunks.anchors = anchor_query.iter().map(|(_, transform, anchor, _)| AnchorPoint {
+        position: floating_origin.to_absolute(transform.translation()),
+        radius: anchor.radius.unwrap_or(terrain_config.chunk_gen_radius),
+    }).collect();
+    // chunks are left as they are until an anchor shows up again
+    if chunks.anchors.is_empty() {
+        return;
+    }
 
-    let my_material = mymat_materials.add(
-        ExtendedMaterial {
-            base: StandardMaterial {
-                // base_color: Srgba::hex("#6dbe4b").unwrap().into(),
-                // base_color: Color::srgb(1.0, 1.0, 1.0),
-                metallic: 0.0,
-                perceptual_roughness: 1.0,
-                reflectance: 0.0,
-                ..default()
-            },
-            extension: MyMaterial {  }
+    let camera = camera_query.iter().next().map(|camera_transform| (camera_transform.translation(), camera_transform.forward().as_vec3()));
+    chunks.view_dir = camera.map(|(_, forward)| forward).unwrap_or(Vec3::ZERO);
+    // the refined detail goes around the anchor the camera is at
+    let camera_position = floating_origin.to_absolute(camera.map(|(position, _)| position).unwrap_or(Vec3::ZERO));
+    chunks.gen_origin = chunks.anchors.iter()
+    .map(|anchor| anchor.position)
+    .min_by(|a, b| a.distance_squared(camera_position).total_cmp(&b.distance_squared(camera_position)))
+    .unwrap();
+
+    let mut in_radius_tricoords: Vec<TriCoord<i32>> = Vec::new();
+    // the query walks the anchors in the same order as above
+    for ((entity, .., warned), anchor) in anchor_query.iter().zip(chunks.anchors.iter()) {
+        match tricoord_vec_gen_distance(Coord {x:anchor.position.x, z:anchor.position.z}, anchor.radius) {
+            Ok(anchor_tricoords) => {
+                in_radius_tricoords.extend(anchor_tricoords);
+                if warned {
+                    commands.entity(entity).remove::<AnchorOutOfRange>();
+                }
+            }
+            Err(error) => {
+                if !warned {
+                    warn!("no terrain around the anchor at {:.01} {:.01}: {}", anchor.position.x, anchor.position.z, error);
+                    commands.entity(entity).insert(AnchorOutOfRange);
+                }
+            }
         }
-    );
-
-    // let mut mesh = Plane3d::default().mesh().size(16., 16.).build();
-
-    // if let Some(VertexAttributeValues::Float32x3(positions)) =
-    //     mesh.attribute(Mesh::ATTRIBUTE_POSITION)
-    // {
-    //     let colors: Vec<[f32; 4]> = positions
-    //         .iter()
-    //         .map(|[r, g, b]| [(1. - *r) / 2., (1. - *g) / 2., (1. - *b) / 2., 1.])
-    //         .collect();
-    //     // mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
-    // }
-
-    // let mesh_handle = meshes.add(mesh);
-
-    let terrain_hdls = TerrainHandles {
-        mat_hdls: HashMap::from([("shiny".into(), shiny_material.untyped()), ("my_mat".into(), my_material.untyped())]),
-        mesh_hdls: HashMap::from([/*("chunk_plane".into(), mesh_handle)*/]),
-        height_map_hdls: HashMap::new()
-    };
+    }
 
-    commands.insert_resource(terrain_hdls);
+    for state in chunks.states.values_mut() {
+        state.in_range = false;
+    }
+    for tricoord in in_radius_tricoords {
+        let state = chunks.states.entry(tricoord).or_insert_with(|| ChunkState::queued(time.elapsed_seconds()));
+        state.in_range = true;
+        // coming back to a chunk that is being unloaded, its entity is already gone
+        if state.status == ChunkStatus::Unloading {
+            *state = ChunkState::queued(time.elapsed_seconds());
+            state.in_range = true;
+        }
+    }
+}
+
+
+// the life of a chunk, from being in range to being despawned again
+#[derive(Reflect, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChunkStatus {
+    // in range, waiting for a generation task
+    Queued,
+    Generating,
+    // the data is back from its task, waiting to be spawned
+    Meshed,
+    Spawned,
+    // out of range, the entity is being despawned
+    Unloading,
+}
+
+#[derive(Reflect, Clone, Debug)]
+pub struct ChunkState {
+    pub status: ChunkStatus,
+    pub entity: Option<Entity>,
+    pub lod: u8,
+    // inside the radius of an anchor as of the last chunks_near_anchors
+    pub in_range: bool,
+    // seconds since startup
+    pub queued_at: f32,
+    pub generating_at: Option<f32>,
+    pub spawned_at: Option<f32>,
+    // of the mesh the chunk is drawn with right now, zero while it has none
+    pub vertices: VertexCounts,
 }
 
+impl ChunkState {
+    fn queued(now: f32) -> Self {
+        ChunkState { status: ChunkStatus::Queued, entity: None, lod: 0, in_range: false, queued_at: now, generating_at: None, spawned_at: None, vertices: VertexCounts::default() }
+    }
+
+    // how long the generation task of the chunk took, up to it being spawned
+    pub fn generation_seconds(&self) -> Option<f32> {
+        Some(self.spawned_at? - self.generating_at?)
+    }
+}
 
 #[derive(Reflect, Resource, InspectorOptions)]
 #[reflect(Resource, InspectorOptions, no_field_bounds)]
 pub struct Chunks {
-    pub in_range: Vec<TriCoord<i16>>,
-    pub generating: Vec<TriCoord<i16>>,
-    pub generated: Vec<TriCoord<i16>>
+    pub states: HashMap<TriCoord<i32>, ChunkState>,
+    pub anchors: Vec<AnchorPoint>,
+    // the anchor nearest to the camera, absolute
+    pub gen_origin: DVec3,
+    // where the camera looks, chunks in front of it are generated first
+    pub view_dir: Vec3,
+    // the TerrainConfig::generator_hash the chunks were generated with, they are all generated again when it changes
+    pub generator_hash: u64,
 }
 impl Default for Chunks {
     fn default() -> Self {
         Self {
-            in_range: Vec::new(),
-            generating: Vec::new(),
-            generated: Vec::new()
+            states: HashMap::new(),
+            anchors: Vec::new(),
+            gen_origin: DVec3::ZERO,
+            view_dir: Vec3::ZERO,
+            generator_hash: 0,
         }
     }
 }
 
+impl Chunks {
+    pub fn count(&self, status: ChunkStatus) -> usize {
+        self.states.values().filter(|state| state.status == status).count()
+    }
+
+    // horizontal distance from an absolute point to the nearest anchor, infinite without anchors
+    pub fn anchor_distance(&self, point: DVec2) -> f32 {
+        self.anchors.iter().map(|anchor| anchor.position.xz().distance(point) as f32).fold(f32::INFINITY, f32::min)
+    }
+
+    pub fn chunk_anchor_distance(&self, tricoord: TriCoord<i32>) -> f32 {
+        let chunk_coord = trichunk_to_coord(tricoord, 0);
+        self.anchor_distance(DVec2::new(chunk_coord.x, chunk_coord.z))
+    }
+
+    // the lod a chunk is generated at, moving on from the lod it had so a chunk regenerated near a boundary
+    // does not come back at a level update_chunk_lods would switch right away
+    pub fn generation_lod(&self, tricoord: TriCoord<i32>, terrain_config: &TerrainConfig) -> u8 {
+        let current = self.states.get(&tricoord).map_or(0, |state| state.lod);
+        return select_chunk_lod(current, self.chunk_anchor_distance(tricoord), terrain_config);
+    }
+}
+
+// whether the chunk is within margin of the radius of any anchor
+fn chunk_near_anchors(anchors: &[AnchorPoint], tricoord: TriCoord<i32>, margin: f32) -> bool {
+    anchors.iter().any(|anchor| chunk_distance(tricoord, anchor.position) <= anchor.radius + margin)
+}
+
+// vertices of a chunk mesh with and without simplification, skirts not counted
+#[derive(Reflect, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VertexCounts {
+    pub full: usize,
+    pub simplified: usize,
+}
+
+impl VertexCounts {
+    fn of(lattice: &ChunkLattice, lod: u8, geometry: &ChunkGeometry) -> Self {
+        VertexCounts { full: lattice_vertex_count(lattice.side >> lod), simplified: geometry.positions.len() }
+    }
+
+    pub fn reduction(&self) -> f32 {
+        if self.full == 0 {
+            return 0.0;
+        }
+        1.0 - self.simplified as f32 / self.full as f32
+    }
+}
+
+// mesh buffer memory of the spawned chunks, shown in the debug panel
+#[derive(Resource, Default)]
+pub struct TerrainStats {
+    pub mesh_bytes: usize,
+    pub meshed_chunks: usize,
+    pub unloaded_chunks: usize,
+    // summed over the spawned chunks, each ChunkState has its own
+    pub vertices: VertexCounts,
+}
+
+impl TerrainStats {
+    pub fn bytes_per_chunk(&self) -> usize {
+        if self.meshed_chunks == 0 {
+            return 0;
+        }
+        self.mesh_bytes / self.meshed_chunks
+    }
+
+    // the counts of the chunk are swapped for the ones of its new mesh
+    pub fn replace_vertices(&mut self, state: &mut ChunkState, vertices: VertexCounts) {
+        self.vertices.full = self.vertices.full - state.vertices.full + vertices.full;
+        self.vertices.simplified = self.vertices.simplified - state.vertices.simplified + vertices.simplified;
+        state.vertices = vertices;
+    }
+}
+
 struct ChunkData {
-    tricoord: TriCoord<i16>,
+    tricoord: TriCoord<i32>,
     xy_coord: Coord<f64>,
-    mesh: Mesh
+    lattice: ChunkLattice,
+    // procedural ground materials with the paint on top
+    materials: Vec<u8>,
+    lod: u8,
+    // None without TerrainMeshing
+    mesh: Option<Mesh>,
+    // built in the task too, it is the slowest part of a chunk
+    collider: Collider,
+    vertices: VertexCounts,
+    // the procedural heights before any edits, for chunks that were not in the chunk cache yet
+    fresh_lattice: Option<ChunkLattice>,
+    // the edits the task applied, strokes can reach the chunk while it generates
+    deltas: Option<HashMap<u32, f32>>,
 }
 
 #[derive(Resource)]
-struct ChunkTasks {
-    chunk_generation_tasks: HashMap<TriCoord<i16>, Task<ChunkData>>
+pub struct ChunkTasks {
+    chunk_generation_tasks: HashMap<TriCoord<i32>, Task<ChunkData>>,
+    // finished tasks waiting to be spawned
+    meshed_chunks: Vec<ChunkData>
 }
 impl Default for ChunkTasks {
     fn default() -> Self {
         Self {
-            chunk_generation_tasks: HashMap::new()
+            chunk_generation_tasks: HashMap::new(),
+            meshed_chunks: Vec::new()
         }
     }
 }
 
+impl ChunkTasks {
+    // drops the generation of a chunk, running or waiting to be spawned. dropping a task cancels it
+    fn cancel(&mut self, tricoord: TriCoord<i32>) {
+        self.chunk_generation_tasks.remove(&tricoord);
+        self.meshed_chunks.retain(|data| data.tricoord != tricoord);
+    }
+}
+
+// a chunk that is generating or waiting to be spawned goes back in the queue, so it is generated
+// with the edits and cache as they are now. returns false for chunks that are not in flight
+pub fn restart_chunk_generation(tricoord: TriCoord<i32>, chunks: &mut Chunks, chunk_tasks: &mut ChunkTasks) -> bool {
+    let Some(state) = chunks.states.get_mut(&tricoord) else {
+        return false;
+    };
+    if !matches!(state.status, ChunkStatus::Generating | ChunkStatus::Meshed) {
+        return false;
+    }
+    chunk_tasks.cancel(tricoord);
+    state.status = ChunkStatus::Queued;
+    state.generating_at = None;
+    return true;
+}
+
 #[cfg(not(target_arch = "wasm32"))]
 fn begin_generating_chunks(
     mut chunks: ResMut<Chunks>,
     mut chunk_tasks: ResMut<ChunkTasks>,
+    mut chunk_cache: ResMut<ChunkCache>,
+    terrain_edits: Res<TerrainEdits>,
+    terrain_config: Res<TerrainConfig>,
+    meshing: Option<Res<TerrainMeshing>>,
+    time: Res<Time>
 ) {
     let task_pool = AsyncComputeTaskPool::get();
-    
-    for tri_chunk in chunks.in_range.clone() {
-        if chunk_tasks.chunk_generation_tasks.contains_key(&tri_chunk) || chunks.generated.contains(&tri_chunk) {
-            continue;
+    let mut free_tasks = terrain_config.max_generation_tasks.saturating_sub(chunk_tasks.chunk_generation_tasks.len());
+
+    for tri_chunk in generation_queue(&chunks, &terrain_config) {
+        if free_tasks == 0 {
+            break;
         }
+        // region files are read in the background, a chunk stays queued until its region is in
+        let Poll::Ready(cached) = chunk_cache.load(tri_chunk) else {
+            continue;
+        };
+        free_tasks -= 1;
+        let lod = chunks.generation_lod(tri_chunk, &terrain_config);
+        let chunk_config = terrain_config.clone();
+        let deltas = terrain_edits.chunk_deltas(tri_chunk);
+        let paint = terrain_edits.chunk_paint(tri_chunk);
+        let meshing = meshing.is_some();
         let task = task_pool.spawn(async move {
-            create_chunk_data(tri_chunk)
+            create_chunk_data(tri_chunk, lod, &chunk_config, meshing, cached, deltas, paint)
         });
         // println!("started: {} {} {}", tri_chunk.a, tri_chunk.b, tri_chunk.c);
-        chunk_tasks.chunk_generation_tasks.insert(tri_chunk.clone(), task);
-        chunks.generating.push(tri_chunk);
+        chunk_tasks.chunk_generation_tasks.insert(tri_chunk, task);
+        let Some(state) = chunks.states.get_mut(&tri_chunk) else {
+            continue;
+        };
+        state.status = ChunkStatus::Generating;
+        state.lod = lod;
+        state.generating_at = Some(time.elapsed_seconds());
     }
 }
 
+// wasm has no background tasks, so chunks are generated on the main thread in queue order
+// until the frame budget is used up. at least one chunk is generated every frame so the queue always moves.
 #[cfg(target_arch = "wasm32")]
-fn begin_generating_chunks() {
+fn begin_generating_chunks(
+    mut chunks: ResMut<Chunks>,
+    mut chunk_tasks: ResMut<ChunkTasks>,
+    mut chunk_cache: ResMut<ChunkCache>,
+    terrain_edits: Res<TerrainEdits>,
+    terrain_config: Res<TerrainConfig>,
+    meshing: Option<Res<TerrainMeshing>>,
+    time: Res<Time>,
+    mut generated_events: EventWriter<ChunkGenerated>
+) {
+    let frame_start = bevy::utils::Instant::now();
 
+    for tri_chunk in generation_queue(&chunks, &terrain_config) {
+        if !chunk_tasks.meshed_chunks.is_empty() && frame_start.elapsed().as_secs_f32() * 1000.0 > terrain_config.wasm_frame_budget_ms {
+            break;
+        }
+        // the cache is always closed on wasm, so this is never Pending
+        let Poll::Ready(cached) = chunk_cache.load(tri_chunk) else {
+            continue;
+        };
+        let lod = chunks.generation_lod(tri_chunk, &terrain_config);
+        let data = create_chunk_data(tri_chunk, lod, &terrain_config, meshing.is_some(), cached, terrain_edits.chunk_deltas(tri_chunk), terrain_edits.chunk_paint(tri_chunk));
+        generated_events.send(ChunkGenerated { tricoord: tri_chunk, lod });
+        chunk_tasks.meshed_chunks.push(data);
+        let Some(state) = chunks.states.get_mut(&tri_chunk) else {
+            continue;
+        };
+        state.status = ChunkStatus::Meshed;
+        state.lod = lod;
+        state.generating_at = Some(time.elapsed_seconds());
+    }
 }
 
 #[cfg(not(target_arch = "wasm32"))]
 fn receive_generated_chunks(
     mut chunks: ResMut<Chunks>,
     mut chunk_tasks: ResMut<ChunkTasks>,
-
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-    environ_assets: Res<TerrainHandles>,
-    mut commands: Commands,
-    selected_mat: Res<SelectedTerrainMat>
+    mut chunk_cache: ResMut<ChunkCache>,
+    mut generated_events: EventWriter<ChunkGenerated>
 ) {
+    let ChunkTasks { chunk_generation_tasks, meshed_chunks } = &mut *chunk_tasks;
 
     // retain keeps the key value pair if true
-    chunk_tasks.chunk_generation_tasks.retain(|chunk_coord, task| {
+    chunk_generation_tasks.retain(|chunk_coord, task| {
         let status = block_on(future::poll_once(task));
 
         // is_none means the task is not done, so we retain it.
@@ -228,53 +622,132 @@ fn receive_generated_chunks(
         if let Some(data) = status {
             // do actions that are necessary once a chunk is finished generating
             println!("created: {} {} {}", data.tricoord.a, data.tricoord.b, data.tricoord.c);
-            
-            // convert the data into things that can be spawned
-            let terrain_mesh = meshes.add(data.mesh);
-            spawn_terrain(&data.xy_coord, terrain_mesh, &meshes, &environ_assets, &selected_mat, &mut commands, );
-
-            chunks.generating.retain(|tricoord| *tricoord != data.tricoord);
-            chunks.generated.push(data.tricoord);
+            if let Some(state) = chunks.states.get_mut(chunk_coord) {
+                state.status = ChunkStatus::Meshed;
+            }
+            if let Some(fresh_lattice) = &data.fresh_lattice {
+                chunk_cache.store(data.tricoord, fresh_lattice);
+            }
+            generated_events.send(ChunkGenerated { tricoord: data.tricoord, lod: data.lod });
+            meshed_chunks.push(data);
         }
 
         retain
-    })
+    });
 }
 
+// the chunks are already in meshed_chunks after begin_generating_chunks
 #[cfg(target_arch = "wasm32")]
-fn receive_generated_chunks(
+fn receive_generated_chunks() {
+
+}
+
+fn spawn_generated_chunks(
     mut chunks: ResMut<Chunks>,
     mut chunk_tasks: ResMut<ChunkTasks>,
-
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-    environ_assets: Res<TerrainHandles>,
     mut commands: Commands,
-    selected_mat: Res<SelectedTerrainMat>
+    terrain_config: Res<TerrainConfig>,
+    mut terrain_stats: ResMut<TerrainStats>,
+    time: Res<Time>,
+    mut spawned_events: EventWriter<ChunkSpawned>,
+    root_query: Query<Entity, With<TerrainRoot>>,
+    floating_origin: Res<FloatingOrigin>,
+    terrain_edits: Res<TerrainEdits>,
+    mut remesh_queue: ResMut<RemeshQueue>
 ) {
-    for tri_chunk in chunks.in_range.clone() {
-        if chunk_tasks.chunk_generation_tasks.contains_key(&tri_chunk) || chunks.generated.contains(&tri_chunk) {
-            continue;
+    let terrain_root = root_query.get_single().ok();
+
+    let meshed_chunks = &mut chunk_tasks.meshed_chunks;
+
+    // spawning is budgeted so frame time stays flat when a lot of chunks finish at once, nearest first
+    meshed_chunks.sort_by(|a, b| chunk_priority(a.tricoord, &chunks, &terrain_config).total_cmp(&chunk_priority(b.tricoord, &chunks, &terrain_config)));
+    let spawn_count = meshed_chunks.len().min(terrain_config.max_spawns_per_frame);
+
+    for mut data in meshed_chunks.drain(..spawn_count) {
+        let tricoord = data.tricoord;
+        let lod = data.lod;
+        // a stroke reached the chunk after its task read the edits, it spawns with them and is remeshed
+        let late_changes = terrain_edits.deltas_since(tricoord, data.deltas.as_ref());
+        for (index, height_change) in late_changes.iter() {
+            if let Some(height) = data.lattice.heights.get_mut(*index as usize) {
+                *height += height_change;
+            }
+        }
+        let entity = spawn_chunk(&data.xy_coord, data.tricoord, data.lod, data.lattice, data.materials, data.collider, &floating_origin, &mut commands);
+        if let Some(mesh) = data.mesh {
+            commands.entity(entity).insert(ChunkMeshUpdate(mesh));
+        }
+        if let Some(root) = terrain_root {
+            commands.entity(root).add_child(entity);
+        }
+        terrain_stats.meshed_chunks += 1;
+
+        let state = chunks.states.entry(tricoord).or_insert_with(|| ChunkState::queued(time.elapsed_seconds()));
+        state.status = ChunkStatus::Spawned;
+        state.entity = Some(entity);
+        state.lod = lod;
+        state.spawned_at = Some(time.elapsed_seconds());
+        terrain_stats.replace_vertices(state, data.vertices);
+        spawned_events.send(ChunkSpawned { tricoord, entity });
+        if !late_changes.is_empty() {
+            remesh_queue.push(entity);
         }
-        let data = create_chunk_data(tri_chunk);
-        let terrain_mesh = meshes.add(data.mesh);
-        spawn_terrain(&data.xy_coord, terrain_mesh, &meshes, &environ_assets, &selected_mat, &mut commands);
-        chunks.generated.push(data.tricoord);
     }
 }
 
 fn create_chunk_data(
-    tricoord: TriCoord<i16>
+    tricoord: TriCoord<i32>,
+    lod: u8,
+    terrain_config: &TerrainConfig,
+    meshing: bool,
+    cached: Option<ChunkLattice>,
+    deltas: Option<HashMap<u32, f32>>,
+    paint: Option<ChunkPaint>
 ) -> ChunkData {
     let chunk_coord = trichunk_to_coord(tricoord, 0);
-    let noise_map = generate_noise(&tricoord);
-    let terrain_mesh = generate_mesh(&tricoord, &noise_map);
-    return ChunkData {tricoord, xy_coord: chunk_coord, mesh: terrain_mesh };
+    let mut fresh_lattice = None;
+    let mut lattice = cached.unwrap_or_else(|| {
+        let lattice = generate_lattice(tricoord, terrain_config);
+        fresh_lattice = Some(lattice.clone());
+        lattice
+    });
+    // the cache holds the procedural heights, the edits go on top every time the chunk is generated
+    if let Some(deltas) = &deltas {
+        apply_chunk_deltas(&mut lattice, deltas);
+    }
+    // the ground follows the edited heights, painted trianglets keep their paint
+    let mut materials = classify_ground(&lattice);
+    if let Some(paint) = &paint {
+        apply_chunk_paint(&mut materials, paint);
+    }
+
+    let mut vertices = VertexCounts::default();
+    let mut terrain_mesh = None;
+    if meshing {
+        let geometry = chunk_geometry(&lattice, lod, terrain_config);
+        vertices = VertexCounts::of(&lattice, lod, &geometry);
+
+        let ground = GroundLayer { lattice: &lattice, materials: &materials };
+        terrain_mesh = Some(build_chunk_mesh(&geometry.with_skirt(lattice.skirt_depth()), terrain_config.mesh_layout, &ground));
+    }
+    let collider = chunk_collider(&lattice, terrain_config);
+    return ChunkData {tricoord, xy_coord: chunk_coord, lattice, materials, lod, mesh: terrain_mesh, collider, vertices, fresh_lattice, deltas };
+}
+
+// the generated heights of a chunk before any edits, from the heightmap where there is one and the noise elsewhere.
+// these are what the chunk cache stores
+pub fn generate_lattice(tricoord: TriCoord<i32>, terrain_config: &TerrainConfig) -> ChunkLattice {
+    let odd = tricoord.is_odd();
+    let procedural = || ChunkLattice::from_noise(&generate_noise(&tricoord, terrain_config.seed), odd);
+    return match &terrain_config.heightmap {
+        Some(heightmap) => heightmap_lattice(tricoord, odd, heightmap, procedural),
+        None => procedural(),
+    };
 }
 
 const BOUND_FACTOR:f64 = 0.05;
 const PIXEL_BOUND_UNIT:f64 = BOUND_FACTOR/33.0;
-fn generate_noise(chunk_tricoord: &TriCoord<i16>) -> NoiseMap {
+fn generate_noise(chunk_tricoord: &TriCoord<i32>, seed: u32) -> NoiseMap {
     let xz = trichunk_to_coord(*chunk_tricoord, 0);
     let halfsides = xz.x / CHUNK_HALFSIDE;
 
@@ -283,13 +756,10 @@ fn generate_noise(chunk_tricoord: &TriCoord<i16>) -> NoiseMap {
     
     let upper_y = chunk_tricoord.b as f64 * 32.0 * PIXEL_BOUND_UNIT;
     let lower_y = upper_y - BOUND_FACTOR; 
-    
-    println!("lower upper x: {},{}", lower_x, upper_x);
-    println!("lower upper y: {},{}", lower_y, upper_y);
 
-    let perlin = Perlin::default();
-    let ridged = RidgedMulti::<Perlin>::default();
-    let fbm = Fbm::<Perlin>::default();
+    let perlin = Perlin::new(seed);
+    let ridged = RidgedMulti::<Perlin>::new(seed);
+    let fbm = Fbm::<Perlin>::new(seed);
     let blend = Blend::new(perlin, ridged, fbm);
 
     let noise_map = PlaneMapBuilder::new(blend)
@@ -338,307 +808,357 @@ fn generate_noise(chunk_tricoord: &TriCoord<i16>) -> NoiseMap {
 //     });
 // }
 
-// make the res mut. add the height map data so it can create the mesh
-fn generate_mesh(
-    chunk_tricoord: &TriCoord<i16>,
-    noise_map: &NoiseMap
-) -> Mesh {
-    let odd:bool = chunk_tricoord.a + chunk_tricoord.b + chunk_tricoord.c != 0;
-    let vertices = generate_vertices_3s(noise_map, odd); // 768/3 = 256
-
-    let normals = generate_normals_from_trimesh(&vertices, odd);
-
-    let assignments = generate_assignments(odd);
-
-    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD)
-    .with_inserted_attribute(
-        Mesh::ATTRIBUTE_POSITION,
-        vertices
-    )
-    .with_inserted_attribute(
-        Mesh::ATTRIBUTE_NORMAL,
-        normals
-    )
-    .with_inserted_indices(Indices::U16(assignments));
-
-    // r min max = -7, 9
-    // g min max = -1.2, 4
-    // b min max = -6, 8
-    let min = 0.0;
-
-    let clamp_min = |value: f32, min| {
-        if value < min {
-            min
-        } else {
-            value
-        }
-    };
+// the surface of a chunk without skirts, simplified if that is turned on
+fn chunk_geometry(
+    lattice: &ChunkLattice,
+    lod: u8,
+    terrain_config: &TerrainConfig
+) -> ChunkGeometry {
+    // 256 trianglets at lod 0, a quarter of that for every level after
+    let lod_lattice = lattice.downsampled(1 << lod);
+    if terrain_config.simplify {
+        return ChunkGeometry::simplified(&lod_lattice, terrain_config.simplify_error);
+    }
+    return ChunkGeometry::from_lattice(&lod_lattice);
+}
 
-    if let Some(VertexAttributeValues::Float32x3(positions)) =
-        mesh.attribute(Mesh::ATTRIBUTE_POSITION)
-    {
-        let colors: Vec<[f32; 4]> = positions
-            .iter()
-            .map(|[x, y, z]| {
-                // [(1. - *r).abs(), (1. - *g).abs(), (1. - *b).abs(), 1.]
-                let mut r = clamp_min(1. - *z, min);
-                let mut g = clamp_min((1. - *x), min);
-                let mut b = clamp_min(1. + *x, min);
-                // if r + g + b > 20.0 {
-                //     g = 15.0;
-                // }
-                // println!("rgb: {} {} {}", r, g, b);
-                [r, g, b, 1.]
-                // [5.0, 5.0, 0.0, 1.]
-            } )
-            .collect();
-
-        mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+pub fn generate_mesh(
+    lattice: &ChunkLattice,
+    lod: u8,
+    terrain_config: &TerrainConfig,
+    ground: &GroundLayer
+) -> (Mesh, VertexCounts) {
+    let geometry = chunk_geometry(lattice, lod, terrain_config);
+    let vertices = VertexCounts::of(lattice, lod, &geometry);
+
+    return (build_chunk_mesh(&geometry.with_skirt(lattice.skirt_depth()), terrain_config.mesh_layout, ground), vertices);
+}
+
+// always at full resolution and without the skirts, whatever lod the mesh is drawn at
+fn chunk_collider(lattice: &ChunkLattice, terrain_config: &TerrainConfig) -> Collider {
+    let collider_geometry = chunk_geometry(lattice, 0, terrain_config);
+    return Collider::trimesh(collider_geometry.positions, collider_geometry.triangles);
+}
+
+#[derive(Component)]
+pub struct TerrainMesh {
+    pub tricoord: TriCoord<i32>,
+    pub lod: u8,
+    // the player chunk the detail of a refined chunk fades out around
+    pub refined_around: Option<TriCoord<i32>>,
+}
+
+// the full resolution heights of a spawned chunk, kept around to remesh it
+#[derive(Component)]
+pub struct ChunkHeights(pub ChunkLattice);
+
+// despawns chunks that left the generation radius, so they are generated again when the player comes back
+fn unload_far_chunks(
+    mut chunks: ResMut<Chunks>,
+    mut chunk_tasks: ResMut<ChunkTasks>,
+    mut refine_tasks: ResMut<RefineTasks>,
+    terrain_config: Res<TerrainConfig>,
+    mut terrain_stats: ResMut<TerrainStats>,
+    mut commands: Commands,
+    mut unloaded_events: EventWriter<ChunkUnloaded>,
+    children_query: Query<&Children>,
+    member_query: Query<&ChunkMember>,
+    root_query: Query<Entity, With<TerrainRoot>>
+) {
+    let terrain_root = root_query.get_single().ok();
+    // a new seed or heightmap makes every chunk stale, they are unloaded and come back from the new generator
+    let mut stale = false;
+    if terrain_config.is_changed() {
+        let generator_hash = terrain_config.generator_hash();
+        stale = chunks.generator_hash != generator_hash;
+        chunks.generator_hash = generator_hash;
     }
-    
-    return mesh; 
-}
-
-const NOISE_MIN:f32 = 0.0;
-const NOISE_MAX:f32 = 1.0;
-const HEIGHT_MIN:f32 = 0.0;
-const HEIGHT_MAX:f32 = 100.0;
-fn map_noise_to_height(value: f32) -> f32 {
-    HEIGHT_MIN + (value - NOISE_MIN) * (HEIGHT_MAX - HEIGHT_MIN) / (NOISE_MAX - NOISE_MIN)
-}
-
-fn generate_vertices_3s(noise_map: &NoiseMap, odd:bool) -> Vec<Vec3> {
-    let mut v:Vec<Vec3> = Vec::with_capacity(triangular_number_o1(CHUNK_SIDE) as usize * 3);
-
-    // convert 1-dimensional noise_map into a grid
-    let mut noise_grid:[[f64;33]; 33] = [[0.0; 33]; 33]; // row v, col ->. dont confuse with x,y coordinates
-    for (index, value) in noise_map.iter().enumerate() {
-        noise_grid[index / 33][index % 33] = *value;
-    }
-
-    //let test_x = 1;
-    //let test_y = 30;
-    //let print_test = |y:usize, x:usize, s:String| { println!("{} noise[{}][{}]: {}", s, y, x, (noise_grid[y][x] * HEIGHT_AMPLIFIER as f64)); };
-    // print_test(test_y, test_x, String::from("TEST"));
-
-    // origin offsets, so the mesh origin is in the correct center (where ALTITUDE on the z-axis and SIDE on the x-axis crosses, so not the geometric center)
-    let origin_offset_x = CHUNK_HALFSIDE * TRI_SIDE as f64;
-    // let origin_offset_z = -CHUNK_HALFSIDE * TRI_ALTITUDE as f64;
-
-    let (z_alt, z_halfalt, z_noise_augmenter, z_noise_start, origin_offset_z) = if !odd {
-        // even chunk
-        (-TRI_ALTITUDE,
-        -TRI_HALF_ALT,
-        -2,
-        32,
-        -CHUNK_HALFSIDE * TRI_ALTITUDE as f64)
-    } else {
-        // odd chunk
-        (TRI_ALTITUDE,
-        TRI_HALF_ALT,
-        2,
-        0,
-        CHUNK_HALFSIDE * TRI_ALTITUDE as f64)
-    };
+    if stale {
+        info!("terrain generator changed, generating every chunk again");
+    }
+    let Chunks { states, anchors, .. } = &mut *chunks;
 
-    // loop through each row of triangles in a chunk
-    for row_index in 0..CHUNK_SIDE { // CHUNK_SIDE
-        let x_row_offset = row_index as f32 * TRI_HALFSIDE + TRI_HALFSIDE;
-
-        // loop through each individual triangle (including odd triangles) in a row
-        let col_max = ((CHUNK_SIDE - (row_index + 1)) * 2) + 1;
-        for col_index in 0..col_max {
-            let z_col_offset = (row_index as f32 * z_alt) + z_alt/2.0;
-            // x_offset and z_offset now point to the centre of each trianglet
-
-            let x_base = -origin_offset_x as f32 + x_row_offset + col_index as f32 * TRI_HALFSIDE;
-            let z_base = -origin_offset_z as f32 + z_col_offset;
-
-            // x and z noise_base point to the left vertex of each trianglet pixel in the noise_map
-            let x_noise_base = row_index + col_index;
-            let z_noise_base = z_noise_start + z_noise_augmenter * row_index;
-
-            // println!("");
-
-            let mut print_and_push = |vec3| { 
-                // println!("{:?}",vec3); 
-                //println!("z_col_offset: {}", z_col_offset);
-                v.push(vec3); 
-            };
-
-            //println!("z_base: {}", z_base);
-
-            // println!("  x_z_noise_base: {}, {}", x_noise_base, z_noise_base);
-
-            if col_index % 2 == 0 {
-                // even trianglet
-                
-                // print_test( z_noise_base as usize, x_noise_base as usize, String::from("EVEN LEFT"));
-
-                // left vertex
-                print_and_push(Vec3 {
-                    x:x_base - TRI_HALFSIDE, 
-                    y:map_noise_to_height(noise_grid[z_noise_base as usize][x_noise_base as usize] as f32), 
-                    z:z_base - z_halfalt
-                });
-
-                // right vertex
-                print_and_push(Vec3 {
-                    x:x_base + TRI_HALFSIDE, 
-                    y:map_noise_to_height(noise_grid[z_noise_base as usize][(x_noise_base + 2) as usize] as f32), 
-                    z:z_base - z_halfalt
-                });
-
-                // print_test( (z_noise_base + z_noise_augmenter) as usize, (x_noise_base + 1) as usize, String::from("EVEN ALTI"));
-
-                // altitude vertex
-                print_and_push(Vec3 {
-                    x:x_base, 
-                    y:map_noise_to_height(noise_grid[(z_noise_base + z_noise_augmenter) as usize][(x_noise_base + 1) as usize] as f32), 
-                    z:z_base + z_halfalt
-                });
-            } else {
-                // odd trianglet
-
-                // print_test( (z_noise_base) as usize, (x_noise_base + 1) as usize, String::from("ODD  ALTI"));
-
-                // altitude vertex
-                print_and_push(Vec3 {
-                    x:x_base, 
-                    y:map_noise_to_height(noise_grid[(z_noise_base) as usize][(x_noise_base + 1) as usize] as f32), 
-                    z:z_base - z_halfalt
-                });
-                // right vertex
-                print_and_push(Vec3 {
-                    x:x_base + TRI_HALFSIDE, 
-                    y:map_noise_to_height(noise_grid[(z_noise_base + z_noise_augmenter) as usize][(x_noise_base + 2) as usize] as f32), 
-                    z:z_base + z_halfalt
-                });
-
-                // print_test( (z_noise_base + z_noise_augmenter) as usize, x_noise_base as usize, String::from("ODD  LEFT"));
-
-                // left vertex
-                print_and_push(Vec3 {
-                    x:x_base - TRI_HALFSIDE, 
-                    y:map_noise_to_height(noise_grid[(z_noise_base + z_noise_augmenter) as usize][x_noise_base as usize] as f32), 
-                    z:z_base + z_halfalt
-                });
+    states.retain(|tricoord, state| {
+        // the entities of last frame's unloaded chunks are despawned by now
+        if state.status == ChunkStatus::Unloading {
+            return false;
+        }
+        // chunks that are not spawned yet have nothing to flicker, so they are dropped as soon as they leave the radius
+        let keep = !stale && match state.status {
+            ChunkStatus::Queued | ChunkStatus::Generating | ChunkStatus::Meshed => state.in_range,
+            _ => chunk_near_anchors(anchors, *tricoord, terrain_config.unload_hysteresis),
+        };
+        if keep {
+            return true;
+        }
+        match state.status {
+            ChunkStatus::Queued => false,
+            ChunkStatus::Generating | ChunkStatus::Meshed => {
+                chunk_tasks.cancel(*tricoord);
+                false
+            }
+            ChunkStatus::Spawned => {
+                let Some(entity) = state.entity.take() else {
+                    return false;
+                };
+                // the collider goes with the entity, the render plugin removes the mesh asset on the unloaded event
+                terrain_stats.meshed_chunks -= 1;
+                terrain_stats.unloaded_chunks += 1;
+                terrain_stats.replace_vertices(state, VertexCounts::default());
+                refine_tasks.chunk_refine_tasks.remove(&entity);
+                park_chunk_members(entity, *tricoord, &children_query, &member_query, terrain_root, &mut commands);
+                commands.entity(entity).despawn_recursive();
+                unloaded_events.send(ChunkUnloaded { tricoord: *tricoord, entity });
+                state.status = ChunkStatus::Unloading;
+                true
             }
+            ChunkStatus::Unloading => false,
         }
-    }
+    });
+}
+
+pub const MAX_REFINE_DEPTH:u8 = 3;
 
-    return v;
+const DETAIL_SEED:u32 = 7;
+
+struct RefinedChunk {
+    refined_around: Option<TriCoord<i32>>,
+    lod: u8,
+    // None without TerrainMeshing
+    mesh: Option<Mesh>,
+    vertices: VertexCounts,
+    collider: Collider,
 }
 
-// takes in a flat array of vertices that are triangles and creates a flat array of normals for each vertex in flat shading
-fn generate_normals_from_trimesh(vertices: &Vec<Vec3>, odd:bool) -> Vec<Vec3> {
-    let mut v:Vec<Vec3> = Vec::with_capacity(vertices.len());
+#[derive(Resource, Default)]
+struct RefineTasks {
+    chunk_refine_tasks: HashMap<Entity, Task<RefinedChunk>>,
+    // wasm has no background tasks, so the chunks are refined in place and land here
+    refined_chunks: Vec<(Entity, RefinedChunk)>
+}
 
-    if !odd {
-        // even
-        for index in 0..vertices.len()/3 {
-            v.push(calculate_normal(vertices[index*3], vertices[index*3+1], vertices[index*3+2]));
-            v.push(calculate_normal(vertices[index*3], vertices[index*3+1], vertices[index*3+2]));
-            v.push(calculate_normal(vertices[index*3], vertices[index*3+1], vertices[index*3+2]));
-        }
-    } else {
-        // odd
-        for index in 0..vertices.len()/3 {
-            v.push(calculate_normal(vertices[index*3+1], vertices[index*3], vertices[index*3+2]));
-            v.push(calculate_normal(vertices[index*3+1], vertices[index*3], vertices[index*3+2]));
-            v.push(calculate_normal(vertices[index*3+1], vertices[index*3], vertices[index*3+2]));
-        }
-    }
+// the detail is strongest at the refine center and gone at refine_radius. the refined chunks reach
+// past the radius, so where they border unrefined chunks their vertices lie flat on the coarse trianglets.
+fn detail_fade(distance: f32, refine_radius: f32) -> f32 {
+    let t = ((distance - refine_radius * 0.5) / (refine_radius * 0.5)).clamp(0.0, 1.0);
+    1.0 - t * t * (3.0 - 2.0 * t)
+}
 
+fn refine_lattice(lattice: &ChunkLattice, chunk_origin: Vec3, refine_center: Vec3, terrain_config: &TerrainConfig) -> ChunkLattice {
+    let detail_noise = Perlin::new(DETAIL_SEED);
+    let (amplitude, frequency, radius) = (terrain_config.detail_amplitude, terrain_config.detail_frequency, terrain_config.refine_radius);
 
-    return v;
+    lattice.refined(terrain_config.refine_depth.min(MAX_REFINE_DEPTH), |local_position| {
+        let world_position = chunk_origin + local_position;
+        let fade = detail_fade(world_position.xz().distance(refine_center.xz()), radius);
+        if fade <= 0.0 {
+            return 0.0;
+        }
+        let detail = detail_noise.get([world_position.x as f64 * frequency, world_position.z as f64 * frequency]) as f32;
+        detail * amplitude * fade
+    })
 }
 
-fn calculate_normal(v0: Vec3, v1: Vec3, v2: Vec3) -> Vec3 {
-    let edge1 = v1 - v0;
-    let edge2 = v2 - v0;
+// refines the chunks around the player chunk and puts chunks that fell out of the radius back to their lod mesh
+fn refine_chunks_near_player(
+    query: Query<(Entity, &TerrainMesh, &ChunkHeights, &ChunkMaterials)>,
+    chunks: Res<Chunks>,
+    terrain_config: Res<TerrainConfig>,
+    meshing: Option<Res<TerrainMeshing>>,
+    mut refine_tasks: ResMut<RefineTasks>
+) {
+    // the detail fades around the player chunk rather than the player itself, so it only changes
+    // when the player crosses into another chunk and all refined chunks agree on it
+    let Ok(center_chunk) = coord_to_trichunk(Coord { x: chunks.gen_origin.x, z: chunks.gen_origin.z }) else {
+        return;
+    };
+    let center_coord = trichunk_to_coord(center_chunk, 0);
+    let refine_center = DVec3::new(center_coord.x, 0.0, center_coord.z);
+
+    for (entity, terrain_mesh, heights, materials) in query.iter() {
+        let in_radius = terrain_config.refine_depth > 0
+            && chunk_distance(terrain_mesh.tricoord, refine_center) < terrain_config.refine_radius + CHUNK_SIDE as f32;
+        let refined_around = if in_radius { Some(center_chunk) } else { None };
+
+        if refined_around == terrain_mesh.refined_around || refine_tasks.chunk_refine_tasks.contains_key(&entity) {
+            continue;
+        }
 
-    let normal = edge1.cross(edge2);
+        let lattice = heights.0.clone();
+        let materials = materials.0.clone();
+        // the detail noise is sampled at absolute positions so it stays the same when the world is recentered
+        let chunk_coord = trichunk_to_coord(terrain_mesh.tricoord, 0);
+        let chunk_origin = Vec3::new(chunk_coord.x as f32, 0.0, chunk_coord.z as f32);
+        let lod = if in_radius { 0 } else { select_chunk_lod(terrain_mesh.lod, chunks.chunk_anchor_distance(terrain_mesh.tricoord), &terrain_config) };
+        let refine_config = terrain_config.clone();
+        let meshing = meshing.is_some();
+
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let task = AsyncComputeTaskPool::get().spawn(async move {
+                build_refined_chunk(lattice, &materials, chunk_origin, refine_center.as_vec3(), refined_around, lod, &refine_config, meshing)
+            });
+            refine_tasks.chunk_refine_tasks.insert(entity, task);
+        }
+        #[cfg(target_arch = "wasm32")]
+        {
+            let refined = build_refined_chunk(lattice, &materials, chunk_origin, refine_center.as_vec3(), refined_around, lod, &refine_config, meshing);
+            refine_tasks.refined_chunks.push((entity, refined));
+        }
+    }
+}
 
-    normal.normalize()
+fn build_refined_chunk(
+    lattice: ChunkLattice,
+    materials: &[u8],
+    chunk_origin: Vec3,
+    refine_center: Vec3,
+    refined_around: Option<TriCoord<i32>>,
+    lod: u8,
+    terrain_config: &TerrainConfig,
+    meshing: bool
+) -> RefinedChunk {
+    // the materials stay per trianglet of the unrefined lattice, the refined ones look them up by position
+    let ground = GroundLayer { lattice: &lattice, materials };
+    let refined = refined_around.map(|_| refine_lattice(&lattice, chunk_origin, refine_center, terrain_config));
+    let mesh_lattice = refined.as_ref().unwrap_or(&lattice);
+    let (mut mesh, mut vertices) = (None, VertexCounts::default());
+    if meshing {
+        let (lod_mesh, lod_vertices) = generate_mesh(mesh_lattice, lod, terrain_config, &ground);
+        (mesh, vertices) = (Some(lod_mesh), lod_vertices);
+    }
+    let collider = chunk_collider(mesh_lattice, terrain_config);
+    return RefinedChunk { refined_around, lod, mesh, vertices, collider };
 }
 
-fn generate_assignments(odd: bool) -> Vec<u16> {
-    let size = (CHUNK_SIDE.pow(2) * 3) as usize;
-    let mut v: Vec<u16> = Vec::with_capacity(size);
-    if !odd {
-        // even chunk
-        for index in 0..(size) {
-            v.push(index as u16);
+fn receive_refined_chunks(
+    mut query: Query<&mut TerrainMesh>,
+    mut chunks: ResMut<Chunks>,
+    mut refine_tasks: ResMut<RefineTasks>,
+    mut terrain_stats: ResMut<TerrainStats>,
+    mut commands: Commands,
+    mut modified_events: EventWriter<ChunkModified>
+) {
+    let mut refined_chunks = std::mem::take(&mut refine_tasks.refined_chunks);
+    refine_tasks.chunk_refine_tasks.retain(|entity, task| {
+        let Some(refined) = block_on(future::poll_once(task)) else {
+            return true;
+        };
+        refined_chunks.push((*entity, refined));
+        false
+    });
+
+    for (entity, refined) in refined_chunks {
+        let Ok(mut terrain_mesh) = query.get_mut(entity) else {
+            continue;
+        };
+        if let Some(mesh) = refined.mesh {
+            commands.entity(entity).insert(ChunkMeshUpdate(mesh));
         }
-    } else {
-        // odd chunk
-        for index in 0..size/3 {
-            v.push((index * 3 + 1) as u16);
-            v.push((index * 3) as u16);
-            v.push((index * 3 + 2) as u16);
+        terrain_mesh.lod = refined.lod;
+        terrain_mesh.refined_around = refined.refined_around;
+        if let Some(state) = chunks.states.get_mut(&terrain_mesh.tricoord) {
+            state.lod = refined.lod;
+            terrain_stats.replace_vertices(state, refined.vertices);
         }
+        // walking has to match what is drawn
+        commands.entity(entity).insert(refined.collider);
+        modified_events.send(ChunkModified { tricoord: terrain_mesh.tricoord, entity, collider_changed: true });
     }
+}
 
+// spawned chunks whose ChunkHeights were edited, waiting for their mesh and collider to be rebuilt
+#[derive(Resource, Default)]
+pub struct RemeshQueue {
+    chunks: VecDeque<Entity>,
+}
 
-    return v;
+impl RemeshQueue {
+    pub fn push(&mut self, entity: Entity) {
+        if !self.chunks.contains(&entity) {
+            self.chunks.push_back(entity);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
 }
 
-#[derive(Component)]
-pub struct TerrainMesh {}
-
-fn spawn_terrain(
-    chunk_coord: &Coord<f64>, 
-    terrain_mesh: Handle<Mesh>,
-    meshes: &Assets<Mesh>,
-    environ_assets: &TerrainHandles,
-    selected_mat: &SelectedTerrainMat,
-    commands: &mut Commands
+// rebuilds edited chunks oldest first until the frame budget is used up, at least one per frame.
+// the lod and refinement of the chunk stay as they are, the ground materials follow the new heights and paint
+fn remesh_queued_chunks(
+    mut query: Query<(&TerrainMesh, &ChunkHeights, &mut ChunkMaterials)>,
+    mut remesh_queue: ResMut<RemeshQueue>,
+    terrain_edits: Res<TerrainEdits>,
+    mut refine_tasks: ResMut<RefineTasks>,
+    mut chunks: ResMut<Chunks>,
+    mut terrain_stats: ResMut<TerrainStats>,
+    terrain_config: Res<TerrainConfig>,
+    meshing: Option<Res<TerrainMeshing>>,
+    mut commands: Commands,
+    mut modified_events: EventWriter<ChunkModified>
 ) {
-    let middle_x = chunk_coord.x;
-    let middle_y = chunk_coord.z;
+    let frame_start = bevy::utils::Instant::now();
+
+    while let Some(entity) = remesh_queue.chunks.pop_front() {
+        let Ok((terrain_mesh, heights, mut materials)) = query.get_mut(entity) else {
+            continue;
+        };
+        // a refine still running was started from the old heights, dropping it cancels it
+        refine_tasks.chunk_refine_tasks.remove(&entity);
+
+        let chunk_coord = trichunk_to_coord(terrain_mesh.tricoord, 0);
+        let chunk_origin = Vec3::new(chunk_coord.x as f32, 0.0, chunk_coord.z as f32);
+        let refine_center = terrain_mesh.refined_around.map(|center_chunk| {
+            let center_coord = trichunk_to_coord(center_chunk, 0);
+            Vec3::new(center_coord.x as f32, 0.0, center_coord.z as f32)
+        })
+        .unwrap_or(Vec3::ZERO);
+        materials.0 = classify_ground(&heights.0);
+        terrain_edits.paint_materials(terrain_mesh.tricoord, &mut materials.0);
+        let rebuilt = build_refined_chunk(heights.0.clone(), &materials.0, chunk_origin, refine_center, terrain_mesh.refined_around, terrain_mesh.lod, &terrain_config, meshing.is_some());
 
-    // terrain transform from ChunkCoord
+        if let Some(mesh) = rebuilt.mesh {
+            commands.entity(entity).insert(ChunkMeshUpdate(mesh));
+        }
+        if let Some(state) = chunks.states.get_mut(&terrain_mesh.tricoord) {
+            terrain_stats.replace_vertices(state, rebuilt.vertices);
+        }
+        commands.entity(entity).insert(rebuilt.collider);
+        modified_events.send(ChunkModified { tricoord: terrain_mesh.tricoord, entity, collider_changed: true });
+
+        if frame_start.elapsed().as_secs_f32() * 1000.0 > terrain_config.remesh_budget_ms {
+            break;
+        }
+    }
+}
+
+// the chunk entity without anything to draw it, the render plugin adds the mesh and material
+fn spawn_chunk(
+    chunk_coord: &Coord<f64>,
+    tricoord: TriCoord<i32>,
+    lod: u8,
+    lattice: ChunkLattice,
+    materials: Vec<u8>,
+    terrain_collider: Collider,
+    floating_origin: &FloatingOrigin,
+    commands: &mut Commands
+) -> Entity {
+    // terrain transform from ChunkCoord, moved into the local world in f64 before it becomes f32
     let chunk_transform = Transform {
-        translation: Vec3::new(middle_x as f32, 0., middle_y as f32),
+        translation: floating_origin.to_local(DVec3::new(chunk_coord.x, 0., chunk_coord.z)),
         ..default()
     };
-    // terrain collider
-    let terrain_collider = Collider::from_bevy_mesh(meshes.get(&terrain_mesh).unwrap(), &ComputedColliderShape::TriMesh).unwrap();
-
-    // println!("selected mat: {}", selected_mat.selected_mat);
-    // println!("selected id: {:?}", environ_assets.mat_hdls[&selected_mat.selected_mat].clone().type_id());
-    // println!("shiny id: {:?}", environ_assets.mat_hdls["shiny"].clone().type_id());
-
-    if selected_mat.selected_mat == "shiny" {
-        // spawn terrain
-        commands.spawn((
-            PbrBundle {
-                mesh: terrain_mesh.clone(),
-                material: environ_assets.mat_hdls["shiny"].clone().typed_unchecked(), // materials.add(Color::srgb(1., 1., 1.)) ,
-                transform: chunk_transform,
-                ..default()
-            },
-            terrain_collider,
-            RigidBody::Fixed,
-            TerrainMesh {},
-        ))
-        .insert(Name::new("TerrainMesh"));
-    } else if selected_mat.selected_mat == "my_mat" {
-            // spawn terrain
-    commands.spawn((
-        MaterialMeshBundle {
-            mesh: terrain_mesh.clone(),
-            material: environ_assets.mat_hdls.get("my_mat").unwrap().clone().typed::<ExtendedMaterial<StandardMaterial, MyMaterial>>(), // materials.add(Color::srgb(1., 1., 1.)) ,
-            transform: chunk_transform,
-            ..default()
-        },
+
+    return commands.spawn((
+        SpatialBundle::from_transform(chunk_transform),
         terrain_collider,
         RigidBody::Fixed,
-        TerrainMesh {},
+        TerrainMesh { tricoord, lod, refined_around: None },
+        ChunkHeights(lattice),
+        ChunkMaterials(materials),
     ))
-    .insert(Name::new("TerrainMesh"));
-    }
-
-
-}
\ No newline at end of file
+    .insert(Name::new("TerrainMesh"))
+    .id();
+}
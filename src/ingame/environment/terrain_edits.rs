@@ -0,0 +1,532 @@
+use std::{collections::HashMap, fs, io, path::Path};
+
+use bevy::prelude::*;
+
+use crate::ingame::tricoord::*;
+
+use super::chunk_format::ByteReader;
+use super::chunk_mesh::ChunkLattice;
+use super::terrain::{restart_chunk_generation, ChunkHeights, ChunkStatus, ChunkTasks, Chunks, RemeshQueue, TerrainConfig};
+
+pub struct TerrainEditPlugin;
+
+impl Plugin for TerrainEditPlugin {
+    fn build(&self, app: &mut App) {
+        app
+        .init_resource::<TerrainEdits>()
+        .add_systems(Update, (undo_redo_keys, apply_pending_edits).chain())
+        ;
+    }
+}
+
+pub const TERRAIN_EDITS_PATH:&str = "saves/terrain_edits.bin";
+
+const EDITS_MAGIC:&[u8; 4] = b"TRED";
+const EDITS_VERSION:u16 = 2;
+// more strokes than this and the oldest can not be undone anymore
+const MAX_UNDO:usize = 128;
+
+// the height a vertex of a chunk moved by, indexed like ChunkLattice::heights at full resolution
+type ChunkDeltas = HashMap<u32, f32>;
+// the painted ground material of a trianglet of a chunk, indexed like ChunkLattice::trianglets
+pub type ChunkPaint = HashMap<u32, u8>;
+
+// one undoable edit, usually a whole brush stroke. the delta of every vertex it touched before and after it,
+// and the paint of every trianglet before and after it, None being the procedural material
+#[derive(Default, Debug)]
+struct EditCommand {
+    vertices: HashMap<(TriCoord<i32>, u32), (f32, f32)>,
+    trianglets: HashMap<(TriCoord<i32>, u32), (Option<u8>, Option<u8>)>,
+}
+
+impl EditCommand {
+    fn is_empty(&self) -> bool {
+        self.vertices.is_empty() && self.trianglets.is_empty()
+    }
+}
+
+// everything edited on top of the procedural heights, sparse per chunk. the seed plus these deltas
+// reproduce an edited world, chunks that are generated again get their deltas back before they are meshed.
+// chunks loaded from the chunk cache start from quantized heights, so there they land within the cache precision.
+#[derive(Resource, Default)]
+pub struct TerrainEdits {
+    deltas: HashMap<TriCoord<i32>, ChunkDeltas>,
+    // painted ground materials on top of the procedural ones
+    paint: HashMap<TriCoord<i32>, ChunkPaint>,
+    // the stroke being painted, it becomes one undo step once it ends
+    stroke: Option<EditCommand>,
+    undo: Vec<EditCommand>,
+    redo: Vec<EditCommand>,
+    // height changes from undo, redo and load that the spawned chunks have not gotten yet
+    pending: Vec<(TriCoord<i32>, u32, f32)>,
+    // chunks whose paint changed from undo, redo and load, their materials are rebuilt with the mesh
+    pending_paint: Vec<TriCoord<i32>>,
+}
+
+impl TerrainEdits {
+    // a vertex of a chunk moved by height_change. a spawned chunk is already changed, one that is still generating
+    // gets it when it spawns
+    pub fn record(&mut self, tricoord: TriCoord<i32>, index: u32, height_change: f32) {
+        let delta = self.deltas.entry(tricoord).or_default().entry(index).or_insert(0.0);
+        let before = *delta;
+        *delta += height_change;
+        let after = *delta;
+
+        let stroke = self.stroke.get_or_insert_with(EditCommand::default);
+        stroke.vertices.entry((tricoord, index)).or_insert((before, after)).1 = after;
+        self.redo.clear();
+    }
+
+    // a trianglet of a spawned chunk was painted, returns false when it already had that material painted
+    pub fn paint(&mut self, tricoord: TriCoord<i32>, trianglet: u32, material: u8) -> bool {
+        let before = self.paint.get(&tricoord).and_then(|chunk_paint| chunk_paint.get(&trianglet)).copied();
+        if before == Some(material) {
+            return false;
+        }
+        self.paint.entry(tricoord).or_default().insert(trianglet, material);
+
+        let stroke = self.stroke.get_or_insert_with(EditCommand::default);
+        stroke.trianglets.entry((tricoord, trianglet)).or_insert((before, None)).1 = Some(material);
+        self.redo.clear();
+        return true;
+    }
+
+    pub fn end_stroke(&mut self) {
+        let Some(stroke) = self.stroke.take() else {
+            return;
+        };
+        if stroke.is_empty() {
+            return;
+        }
+        self.undo.push(stroke);
+        if self.undo.len() > MAX_UNDO {
+            self.undo.remove(0);
+        }
+    }
+
+    pub fn undo(&mut self) {
+        self.end_stroke();
+        let Some(command) = self.undo.pop() else {
+            return;
+        };
+        for ((tricoord, index), (before, after)) in command.vertices.iter() {
+            self.set_delta(*tricoord, *index, *before, *after);
+        }
+        for ((tricoord, trianglet), (before, _)) in command.trianglets.iter() {
+            self.set_paint(*tricoord, *trianglet, *before);
+        }
+        self.redo.push(command);
+    }
+
+    pub fn redo(&mut self) {
+        self.end_stroke();
+        let Some(command) = self.redo.pop() else {
+            return;
+        };
+        for ((tricoord, index), (before, after)) in command.vertices.iter() {
+            self.set_delta(*tricoord, *index, *after, *before);
+        }
+        for ((tricoord, trianglet), (_, after)) in command.trianglets.iter() {
+            self.set_paint(*tricoord, *trianglet, *after);
+        }
+        self.undo.push(command);
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty() || self.stroke.is_some()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    fn set_delta(&mut self, tricoord: TriCoord<i32>, index: u32, delta: f32, current: f32) {
+        let chunk_deltas = self.deltas.entry(tricoord).or_default();
+        if delta == 0.0 {
+            chunk_deltas.remove(&index);
+        } else {
+            chunk_deltas.insert(index, delta);
+        }
+        if chunk_deltas.is_empty() {
+            self.deltas.remove(&tricoord);
+        }
+        self.pending.push((tricoord, index, delta - current));
+    }
+
+    fn set_paint(&mut self, tricoord: TriCoord<i32>, trianglet: u32, material: Option<u8>) {
+        let chunk_paint = self.paint.entry(tricoord).or_default();
+        match material {
+            Some(material) => chunk_paint.insert(trianglet, material),
+            None => chunk_paint.remove(&trianglet),
+        };
+        if chunk_paint.is_empty() {
+            self.paint.remove(&tricoord);
+        }
+        self.pending_paint.push(tricoord);
+    }
+
+    pub fn chunk_deltas(&self, tricoord: TriCoord<i32>) -> Option<ChunkDeltas> {
+        self.deltas.get(&tricoord).cloned()
+    }
+
+    // the height changes recorded for a chunk since its generation task read generated_with
+    pub fn deltas_since(&self, tricoord: TriCoord<i32>, generated_with: Option<&ChunkDeltas>) -> Vec<(u32, f32)> {
+        let Some(chunk_deltas) = self.deltas.get(&tricoord) else {
+            return Vec::new();
+        };
+        return chunk_deltas.iter()
+            .map(|(index, delta)| (*index, delta - generated_with.and_then(|before| before.get(index)).copied().unwrap_or(0.0)))
+            .filter(|(_, height_change)| *height_change != 0.0)
+            .collect();
+    }
+
+    pub fn chunk_paint(&self, tricoord: TriCoord<i32>) -> Option<ChunkPaint> {
+        self.paint.get(&tricoord).cloned()
+    }
+
+    // the procedural materials of a chunk with its paint on top
+    pub fn paint_materials(&self, tricoord: TriCoord<i32>, materials: &mut [u8]) {
+        if let Some(chunk_paint) = self.paint.get(&tricoord) {
+            apply_chunk_paint(materials, chunk_paint);
+        }
+    }
+
+    pub fn painted_trianglets(&self) -> usize {
+        self.paint.values().map(|chunk_paint| chunk_paint.len()).sum()
+    }
+
+    pub fn edited_chunks(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn edited_vertices(&self) -> usize {
+        self.deltas.values().map(|chunk_deltas| chunk_deltas.len()).sum()
+    }
+
+    // file, little endian:
+    // magic, version u16, config hash u64, chunk count u32,
+    // then per chunk a b c as i32, vertex count u32 and per vertex its lattice index u32 and delta f32,
+    // then the painted chunk count u32 and per chunk a b c as i32, trianglet count u32 and per trianglet
+    // its index u32 and ground material u8. version 1 files end before the paint
+    pub fn save(&self, path: &Path, terrain_config: &TerrainConfig) -> io::Result<()> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(EDITS_MAGIC);
+        bytes.extend_from_slice(&EDITS_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&terrain_config.generator_hash().to_le_bytes());
+        bytes.extend_from_slice(&(self.deltas.len() as u32).to_le_bytes());
+        for (tricoord, chunk_deltas) in self.deltas.iter() {
+            bytes.extend_from_slice(&tricoord.a.to_le_bytes());
+            bytes.extend_from_slice(&tricoord.b.to_le_bytes());
+            bytes.extend_from_slice(&tricoord.c.to_le_bytes());
+            bytes.extend_from_slice(&(chunk_deltas.len() as u32).to_le_bytes());
+            for (index, delta) in chunk_deltas.iter() {
+                bytes.extend_from_slice(&index.to_le_bytes());
+                bytes.extend_from_slice(&delta.to_le_bytes());
+            }
+        }
+        bytes.extend_from_slice(&(self.paint.len() as u32).to_le_bytes());
+        for (tricoord, chunk_paint) in self.paint.iter() {
+            bytes.extend_from_slice(&tricoord.a.to_le_bytes());
+            bytes.extend_from_slice(&tricoord.b.to_le_bytes());
+            bytes.extend_from_slice(&tricoord.c.to_le_bytes());
+            bytes.extend_from_slice(&(chunk_paint.len() as u32).to_le_bytes());
+            for (trianglet, material) in chunk_paint.iter() {
+                bytes.extend_from_slice(&trianglet.to_le_bytes());
+                bytes.push(*material);
+            }
+        }
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        return fs::write(path, bytes);
+    }
+
+    // replaces the edits with the ones in the file. they only make sense on the terrain they were made on,
+    // so a file from another seed or generator config is refused. the undo history is cleared
+    pub fn load(&mut self, path: &Path, terrain_config: &TerrainConfig) -> io::Result<()> {
+        let bytes = fs::read(path)?;
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+        let mut reader = ByteReader::new(&bytes);
+        if reader.take(4) != Some(EDITS_MAGIC.as_slice()) {
+            return Err(invalid("not a terrain edits file"));
+        }
+        let version = reader.u16().ok_or_else(|| invalid("truncated"))?;
+        if version == 0 || version > EDITS_VERSION {
+            return Err(invalid("unsupported terrain edits version"));
+        }
+        if reader.u64() != Some(terrain_config.generator_hash()) {
+            return Err(invalid("the edits were made on another seed or generator config"));
+        }
+
+        let read_chunk = |reader: &mut ByteReader| -> Option<(TriCoord<i32>, ChunkDeltas)> {
+            let tricoord = TriCoord { a: reader.i32()?, b: reader.i32()?, c: reader.i32()? };
+            let vertex_count = reader.u32()?;
+            let mut chunk_deltas = ChunkDeltas::new();
+            for _ in 0..vertex_count {
+                chunk_deltas.insert(reader.u32()?, reader.f32()?);
+            }
+            Some((tricoord, chunk_deltas))
+        };
+        let mut deltas: HashMap<TriCoord<i32>, ChunkDeltas> = HashMap::new();
+        let chunk_count = reader.u32().ok_or_else(|| invalid("truncated"))?;
+        for _ in 0..chunk_count {
+            let (tricoord, chunk_deltas) = read_chunk(&mut reader).ok_or_else(|| invalid("truncated"))?;
+            deltas.insert(tricoord, chunk_deltas);
+        }
+
+        let read_paint = |reader: &mut ByteReader| -> Option<(TriCoord<i32>, ChunkPaint)> {
+            let tricoord = TriCoord { a: reader.i32()?, b: reader.i32()?, c: reader.i32()? };
+            let trianglet_count = reader.u32()?;
+            let mut chunk_paint = ChunkPaint::new();
+            for _ in 0..trianglet_count {
+                chunk_paint.insert(reader.u32()?, reader.u8()?);
+            }
+            Some((tricoord, chunk_paint))
+        };
+        let mut paint: HashMap<TriCoord<i32>, ChunkPaint> = HashMap::new();
+        if version >= 2 {
+            let chunk_count = reader.u32().ok_or_else(|| invalid("truncated"))?;
+            for _ in 0..chunk_count {
+                let (tricoord, chunk_paint) = read_paint(&mut reader).ok_or_else(|| invalid("truncated"))?;
+                paint.insert(tricoord, chunk_paint);
+            }
+        }
+
+        // the spawned chunks move from the old deltas to the new ones
+        for (tricoord, chunk_deltas) in self.deltas.iter() {
+            for (index, delta) in chunk_deltas.iter() {
+                self.pending.push((*tricoord, *index, -delta));
+            }
+        }
+        for (tricoord, chunk_deltas) in deltas.iter() {
+            for (index, delta) in chunk_deltas.iter() {
+                self.pending.push((*tricoord, *index, *delta));
+            }
+        }
+        self.deltas = deltas;
+        self.pending_paint.extend(self.paint.keys().chain(paint.keys()).copied());
+        self.paint = paint;
+        self.stroke = None;
+        self.undo.clear();
+        self.redo.clear();
+        return Ok(());
+    }
+}
+
+// puts the edits of a chunk on its procedural heights
+pub fn apply_chunk_deltas(lattice: &mut ChunkLattice, chunk_deltas: &ChunkDeltas) {
+    for (index, delta) in chunk_deltas.iter() {
+        if let Some(height) = lattice.heights.get_mut(*index as usize) {
+            *height += delta;
+        }
+    }
+}
+
+// puts the painted trianglets of a chunk on its procedural materials
+pub fn apply_chunk_paint(materials: &mut [u8], chunk_paint: &ChunkPaint) {
+    for (trianglet, material) in chunk_paint.iter() {
+        if let Some(current) = materials.get_mut(*trianglet as usize) {
+            *current = *material;
+        }
+    }
+}
+
+fn undo_redo_keys(
+    key: Res<ButtonInput<KeyCode>>,
+    mut terrain_edits: ResMut<TerrainEdits>
+) {
+    let ctrl = key.pressed(KeyCode::ControlLeft) || key.pressed(KeyCode::ControlRight);
+    let shift = key.pressed(KeyCode::ShiftLeft) || key.pressed(KeyCode::ShiftRight);
+    if !ctrl {
+        return;
+    }
+    if key.just_pressed(KeyCode::KeyY) || (key.just_pressed(KeyCode::KeyZ) && shift) {
+        terrain_edits.redo();
+    } else if key.just_pressed(KeyCode::KeyZ) {
+        terrain_edits.undo();
+    }
+}
+
+// moves the spawned chunks to their new deltas. queued chunks pick them up when they are generated,
+// chunks already generating got a copy of the old ones and are generated again
+fn apply_pending_edits(
+    mut terrain_edits: ResMut<TerrainEdits>,
+    mut query: Query<&mut ChunkHeights>,
+    mut chunks: ResMut<Chunks>,
+    mut chunk_tasks: ResMut<ChunkTasks>,
+    mut remesh_queue: ResMut<RemeshQueue>
+) {
+    if terrain_edits.pending.is_empty() && terrain_edits.pending_paint.is_empty() {
+        return;
+    }
+    // the materials are rebuilt from the paint when the chunk is remeshed
+    for tricoord in std::mem::take(&mut terrain_edits.pending_paint) {
+        if restart_chunk_generation(tricoord, &mut chunks, &mut chunk_tasks) {
+            continue;
+        }
+        if let Some(entity) = chunks.states.get(&tricoord)
+        .filter(|state| state.status == ChunkStatus::Spawned)
+        .and_then(|state| state.entity) {
+            remesh_queue.push(entity);
+        }
+    }
+    for (tricoord, index, height_change) in std::mem::take(&mut terrain_edits.pending) {
+        if restart_chunk_generation(tricoord, &mut chunks, &mut chunk_tasks) {
+            continue;
+        }
+        let Some(entity) = chunks.states.get(&tricoord)
+        .filter(|state| state.status == ChunkStatus::Spawned)
+        .and_then(|state| state.entity) else {
+            continue;
+        };
+        let Ok(mut heights) = query.get_mut(entity) else {
+            continue;
+        };
+        if let Some(height) = heights.0.heights.get_mut(index as usize) {
+            *height += height_change;
+            remesh_queue.push(entity);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    const CHUNK:TriCoord<i32> = TriCoord { a: 3, b: -2, c: 0 };
+    const NEIGHBOR:TriCoord<i32> = TriCoord { a: 3, b: -2, c: -1 };
+
+    // two strokes over two chunks, the second one going over vertices of the first
+    fn test_edits() -> TerrainEdits {
+        let mut edits = TerrainEdits::default();
+        edits.record(CHUNK, 10, 0.5);
+        edits.record(CHUNK, 11, 1.25);
+        edits.record(NEIGHBOR, 4, -0.75);
+        edits.paint(CHUNK, 7, 4);
+        edits.end_stroke();
+        edits.record(CHUNK, 11, -0.5);
+        edits.record(CHUNK, 12, 2.0);
+        edits.paint(CHUNK, 7, 2);
+        edits.paint(NEIGHBOR, 1, 3);
+        edits.end_stroke();
+        edits
+    }
+
+    fn test_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("terrain_edits_{}_{}.bin", name, std::process::id()))
+    }
+
+    // what a spawned chunk ends up with: the deltas recorded so far plus every pending change
+    fn spawned_heights(edits: &mut TerrainEdits, heights: &mut HashMap<(TriCoord<i32>, u32), f32>) {
+        for (tricoord, index, height_change) in std::mem::take(&mut edits.pending) {
+            *heights.entry((tricoord, index)).or_insert(0.0) += height_change;
+        }
+    }
+
+    fn assert_heights_match(heights: &HashMap<(TriCoord<i32>, u32), f32>, edits: &TerrainEdits) {
+        for ((tricoord, index), height) in heights.iter() {
+            let delta = edits.deltas.get(tricoord).and_then(|chunk_deltas| chunk_deltas.get(index)).copied().unwrap_or(0.0);
+            assert!((height - delta).abs() < 1e-5, "vertex {} of {:?} is at {} instead of {}", index, tricoord, height, delta);
+        }
+    }
+
+    #[test]
+    fn undo_then_redo_gives_the_same_edits() {
+        let mut edits = test_edits();
+        let (deltas, paint) = (edits.deltas.clone(), edits.paint.clone());
+
+        edits.undo();
+        edits.undo();
+        assert!(edits.deltas.is_empty());
+        assert!(edits.paint.is_empty());
+        assert!(!edits.can_undo());
+
+        edits.redo();
+        edits.redo();
+        assert_eq!(edits.deltas, deltas);
+        assert_eq!(edits.paint, paint);
+        assert!(!edits.can_redo());
+    }
+
+    #[test]
+    fn undo_moves_spawned_chunks_back() {
+        let mut edits = test_edits();
+        // the spawned chunks already have the recorded strokes
+        let mut heights: HashMap<(TriCoord<i32>, u32), f32> = edits.deltas.iter()
+        .flat_map(|(tricoord, chunk_deltas)| chunk_deltas.iter().map(|(index, delta)| ((*tricoord, *index), *delta)))
+        .collect();
+
+        edits.undo();
+        spawned_heights(&mut edits, &mut heights);
+        assert_heights_match(&heights, &edits);
+        assert_eq!(edits.deltas[&CHUNK][&11], 1.25);
+        assert_eq!(edits.paint[&CHUNK][&7], 4);
+
+        edits.redo();
+        spawned_heights(&mut edits, &mut heights);
+        assert_heights_match(&heights, &edits);
+    }
+
+    #[test]
+    fn save_and_load_give_the_same_edits() {
+        let terrain_config = TerrainConfig::default();
+        let mut edits = test_edits();
+        // an undone stroke is not part of the saved edits
+        edits.undo();
+        let path = test_path("round_trip");
+        edits.save(&path, &terrain_config).unwrap();
+
+        let mut loaded = TerrainEdits::default();
+        loaded.load(&path, &terrain_config).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(loaded.deltas, edits.deltas);
+        assert_eq!(loaded.paint, edits.paint);
+        assert!(!loaded.can_undo());
+        assert!(!loaded.can_redo());
+        // chunks spawned before the load move to the loaded deltas
+        let mut heights = HashMap::new();
+        spawned_heights(&mut loaded, &mut heights);
+        assert_heights_match(&heights, &loaded);
+    }
+
+    #[test]
+    fn load_replaces_the_current_edits() {
+        let terrain_config = TerrainConfig::default();
+        let mut saved = TerrainEdits::default();
+        saved.record(NEIGHBOR, 9, 3.0);
+        saved.end_stroke();
+        let path = test_path("replace");
+        saved.save(&path, &terrain_config).unwrap();
+
+        let mut edits = test_edits();
+        let mut heights: HashMap<(TriCoord<i32>, u32), f32> = edits.deltas.iter()
+        .flat_map(|(tricoord, chunk_deltas)| chunk_deltas.iter().map(|(index, delta)| ((*tricoord, *index), *delta)))
+        .collect();
+        edits.load(&path, &terrain_config).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(edits.deltas, saved.deltas);
+        assert_eq!(edits.paint, saved.paint);
+        spawned_heights(&mut edits, &mut heights);
+        assert_heights_match(&heights, &edits);
+    }
+
+    #[test]
+    fn load_refuses_another_seed() {
+        let path = test_path("seed");
+        test_edits().save(&path, &TerrainConfig::default()).unwrap();
+
+        let other_seed = TerrainConfig { seed: 1, ..default() };
+        let mut edits = TerrainEdits::default();
+        let error = edits.load(&path, &other_seed).unwrap_err();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(edits.deltas.is_empty());
+    }
+}
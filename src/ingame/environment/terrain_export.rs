@@ -0,0 +1,450 @@
+use std::{collections::HashMap, fmt::Write as _, fs, io, path::{Path, PathBuf}};
+
+use bevy::{math::DVec3, prelude::*};
+
+use crate::ingame::tricoord::*;
+
+use super::chunk_mesh::{ChunkGeometry, ChunkLattice};
+use super::ground_material::{ChunkMaterials, GroundMaterial};
+use super::terrain::{ChunkHeights, ChunkStatus, Chunks};
+
+pub struct TerrainExportPlugin;
+
+impl Plugin for TerrainExportPlugin {
+    fn build(&self, app: &mut App) {
+        app
+        .init_resource::<TerrainExportConfig>()
+        .add_event::<ExportTerrain>()
+        .add_systems(Update, export_terrain)
+        ;
+    }
+}
+
+#[derive(Reflect, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExportFormat {
+    // binary gltf, one file with the buffers inside
+    #[default]
+    Glb,
+    Obj,
+}
+
+impl ExportFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Glb => "glb",
+            ExportFormat::Obj => "obj",
+        }
+    }
+}
+
+// which chunks go into an export
+#[derive(Clone, Debug)]
+pub enum ExportRegion {
+    Chunks(Vec<TriCoord<i32>>),
+    // every chunk within radius of an absolute position
+    Around { center: DVec3, radius: f32 },
+}
+
+impl ExportRegion {
+    pub fn tricoords(&self) -> Vec<TriCoord<i32>> {
+        match self {
+            ExportRegion::Chunks(tricoords) => tricoords.clone(),
+            ExportRegion::Around { center, radius } => {
+                tricoord_vec_gen_distance(Coord { x: center.x, z: center.z }, *radius).unwrap_or_default()
+            }
+        }
+    }
+}
+
+// the export settings of the debug panel
+#[derive(Resource)]
+pub struct TerrainExportConfig {
+    pub format: ExportFormat,
+    pub radius: f32,
+    // one submesh per chunk, gltf primitives or obj groups, all sharing the welded vertices
+    pub chunk_submeshes: bool,
+}
+
+impl Default for TerrainExportConfig {
+    fn default() -> Self {
+        TerrainExportConfig { format: ExportFormat::Glb, radius: 64.0, chunk_submeshes: false }
+    }
+}
+
+// writes the spawned chunks of the region to a file in the export directory
+#[derive(Event, Clone, Debug)]
+pub struct ExportTerrain {
+    pub region: ExportRegion,
+    pub format: ExportFormat,
+    pub chunk_submeshes: bool,
+}
+
+pub struct ExportChunk<'a> {
+    pub tricoord: TriCoord<i32>,
+    pub lattice: &'a ChunkLattice,
+    // per trianglet, the vertex colors come from them
+    pub materials: &'a [u8],
+}
+
+// the chunks of a region as one mesh. the vertices on chunk borders are welded, so every vertex is
+// there once and the normals are smooth across the borders
+pub struct RegionMesh {
+    // relative to the origin the mesh was built around, so they stay precise far from zero
+    pub positions: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub colors: Vec<[f32; 4]>,
+    pub submeshes: Vec<(TriCoord<i32>, Vec<[u32; 3]>)>,
+}
+
+// at full resolution, without lod, skirts or refinement detail
+pub fn build_region_mesh(chunks: &[ExportChunk], origin: DVec3) -> RegionMesh {
+    let mut welded: HashMap<VertexKey, u32> = HashMap::new();
+    let mut positions = Vec::new();
+    let mut color_sums: Vec<[f32; 4]> = Vec::new();
+    let mut submeshes = Vec::with_capacity(chunks.len());
+
+    for chunk in chunks {
+        let chunk_coord = trichunk_to_coord(chunk.tricoord, 0);
+        let chunk_origin = DVec3::new(chunk_coord.x, 0.0, chunk_coord.z);
+        let geometry = ChunkGeometry::from_lattice(chunk.lattice);
+
+        let vertices: Vec<u32> = geometry.positions.iter().map(|position| {
+            let absolute = chunk_origin + position.as_dvec3();
+            *welded.entry(vertex_key(absolute)).or_insert_with(|| {
+                positions.push((absolute - origin).as_vec3());
+                color_sums.push([0.0; 4]);
+                (positions.len() - 1) as u32
+            })
+        })
+        .collect();
+
+        let mut triangles = Vec::with_capacity(geometry.triangles.len());
+        // from_lattice walks the trianglets in the same order as the materials
+        for (trianglet, triangle) in geometry.triangles.iter().enumerate() {
+            let welded_triangle = triangle.map(|index| vertices[index as usize]);
+            let material = GroundMaterial::from_id(chunk.materials.get(trianglet).copied().unwrap_or(0));
+            for vertex in welded_triangle {
+                let color_sum = &mut color_sums[vertex as usize];
+                for (sum, channel) in color_sum.iter_mut().zip(material.color()) {
+                    *sum += channel;
+                }
+            }
+            triangles.push(welded_triangle);
+        }
+        submeshes.push((chunk.tricoord, triangles));
+    }
+
+    // area weighted, the cross product is twice the area
+    let mut normals = vec![Vec3::ZERO; positions.len()];
+    for (_, triangles) in submeshes.iter() {
+        for triangle in triangles {
+            let [v0, v1, v2] = triangle.map(|index| positions[index as usize]);
+            let face = (v1 - v0).cross(v2 - v0);
+            for index in triangle {
+                normals[*index as usize] += face;
+            }
+        }
+    }
+    let normals = normals.into_iter().map(|normal| normal.normalize_or(Vec3::Y)).collect();
+    // the average color of the trianglets around a vertex, the alpha sums up the count
+    let colors = color_sums.into_iter().map(|[r, g, b, count]| {
+        let count = count.max(1.0);
+        [r / count, g / count, b / count, 1.0]
+    })
+    .collect();
+
+    return RegionMesh { positions, normals, colors, submeshes };
+}
+
+// positions, normals and colors in one buffer view each, then the indices of every primitive one after another.
+// without submeshes all triangles go into one primitive
+pub fn encode_glb(mesh: &RegionMesh, chunk_submeshes: bool) -> Vec<u8> {
+    let primitives: Vec<(Option<TriCoord<i32>>, Vec<u32>)> = if chunk_submeshes {
+        mesh.submeshes.iter().map(|(tricoord, triangles)| (Some(*tricoord), triangles.iter().flatten().copied().collect())).collect()
+    } else {
+        vec![(None, mesh.submeshes.iter().flat_map(|(_, triangles)| triangles.iter().flatten().copied()).collect())]
+    };
+
+    let mut binary: Vec<u8> = Vec::new();
+    let mut views: Vec<(usize, usize, u32)> = Vec::new();
+    let mut push_view = |binary: &mut Vec<u8>, bytes: Vec<u8>, target: u32| {
+        views.push((binary.len(), bytes.len(), target));
+        binary.extend(bytes);
+        views.len() - 1
+    };
+    const ARRAY_BUFFER:u32 = 34962;
+    const ELEMENT_ARRAY_BUFFER:u32 = 34963;
+    let floats = |values: &mut dyn Iterator<Item = f32>| values.flat_map(f32::to_le_bytes).collect::<Vec<u8>>();
+
+    let position_view = push_view(&mut binary, floats(&mut mesh.positions.iter().flat_map(|position| position.to_array())), ARRAY_BUFFER);
+    let normal_view = push_view(&mut binary, floats(&mut mesh.normals.iter().flat_map(|normal| normal.to_array())), ARRAY_BUFFER);
+    let color_view = push_view(&mut binary, floats(&mut mesh.colors.iter().flatten().copied()), ARRAY_BUFFER);
+    let index_views: Vec<usize> = primitives.iter()
+    .map(|(_, indices)| push_view(&mut binary, indices.iter().flat_map(|index| index.to_le_bytes()).collect(), ELEMENT_ARRAY_BUFFER))
+    .collect();
+
+    let (min, max) = mesh.positions.iter().fold((Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)), |(min, max), position| {
+        (min.min(*position), max.max(*position))
+    });
+    let (min, max) = if mesh.positions.is_empty() { (Vec3::ZERO, Vec3::ZERO) } else { (min, max) };
+
+    // the json is small and flat, so it is written by hand
+    let vertex_count = mesh.positions.len();
+    let mut accessors = vec![
+        format!(r#"{{"bufferView":{},"componentType":5126,"count":{},"type":"VEC3","min":[{},{},{}],"max":[{},{},{}]}}"#,
+            position_view, vertex_count, min.x, min.y, min.z, max.x, max.y, max.z),
+        format!(r#"{{"bufferView":{},"componentType":5126,"count":{},"type":"VEC3"}}"#, normal_view, vertex_count),
+        format!(r#"{{"bufferView":{},"componentType":5126,"count":{},"type":"VEC4"}}"#, color_view, vertex_count),
+    ];
+    let mut primitive_json = Vec::new();
+    for ((tricoord, indices), view) in primitives.iter().zip(index_views) {
+        accessors.push(format!(r#"{{"bufferView":{},"componentType":5125,"count":{},"type":"SCALAR"}}"#, view, indices.len()));
+        let extras = match tricoord {
+            Some(tricoord) => format!(r#","extras":{{"chunk":[{},{},{}]}}"#, tricoord.a, tricoord.b, tricoord.c),
+            None => String::new(),
+        };
+        primitive_json.push(format!(r#"{{"attributes":{{"POSITION":0,"NORMAL":1,"COLOR_0":2}},"indices":{},"mode":4{}}}"#, accessors.len() - 1, extras));
+    }
+    let view_json: Vec<String> = views.iter()
+    .map(|(offset, length, target)| format!(r#"{{"buffer":0,"byteOffset":{},"byteLength":{},"target":{}}}"#, offset, length, target))
+    .collect();
+
+    let json = format!(
+        r#"{{"asset":{{"version":"2.0","generator":"triangle terrain export"}},"scene":0,"scenes":[{{"nodes":[0]}}],"nodes":[{{"mesh":0,"name":"terrain"}}],"meshes":[{{"name":"terrain","primitives":[{}]}}],"buffers":[{{"byteLength":{}}}],"bufferViews":[{}],"accessors":[{}]}}"#,
+        primitive_json.join(","), binary.len(), view_json.join(","), accessors.join(",")
+    );
+
+    // both chunks are padded to 4 bytes, the json with spaces and the binary with zeros
+    let mut json = json.into_bytes();
+    json.resize(json.len().next_multiple_of(4), b' ');
+    binary.resize(binary.len().next_multiple_of(4), 0);
+
+    let mut glb = Vec::with_capacity(12 + 8 + json.len() + 8 + binary.len());
+    glb.extend_from_slice(b"glTF");
+    glb.extend_from_slice(&2u32.to_le_bytes());
+    glb.extend_from_slice(&((12 + 8 + json.len() + 8 + binary.len()) as u32).to_le_bytes());
+    glb.extend_from_slice(&(json.len() as u32).to_le_bytes());
+    glb.extend_from_slice(b"JSON");
+    glb.extend(json);
+    glb.extend_from_slice(&(binary.len() as u32).to_le_bytes());
+    glb.extend_from_slice(b"BIN\0");
+    glb.extend(binary);
+    return glb;
+}
+
+// vertex colors go after the position, blender and meshlab read them that way. submeshes are obj groups
+pub fn encode_obj(mesh: &RegionMesh, chunk_submeshes: bool) -> String {
+    let mut obj = String::new();
+    let _ = writeln!(obj, "# triangle terrain export, {} chunks", mesh.submeshes.len());
+    let _ = writeln!(obj, "o terrain");
+    for (position, color) in mesh.positions.iter().zip(mesh.colors.iter()) {
+        let _ = writeln!(obj, "v {} {} {} {} {} {}", position.x, position.y, position.z, color[0], color[1], color[2]);
+    }
+    for normal in mesh.normals.iter() {
+        let _ = writeln!(obj, "vn {} {} {}", normal.x, normal.y, normal.z);
+    }
+    for (tricoord, triangles) in mesh.submeshes.iter() {
+        if chunk_submeshes {
+            let _ = writeln!(obj, "g chunk_{}_{}_{}", tricoord.a, tricoord.b, tricoord.c);
+        }
+        // obj counts from 1
+        for [v0, v1, v2] in triangles.iter().map(|triangle| triangle.map(|index| index + 1)) {
+            let _ = writeln!(obj, "f {}//{} {}//{} {}//{}", v0, v0, v1, v1, v2, v2);
+        }
+    }
+    return obj;
+}
+
+pub fn write_region_mesh(path: &Path, mesh: &RegionMesh, format: ExportFormat, chunk_submeshes: bool) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    match format {
+        ExportFormat::Glb => fs::write(path, encode_glb(mesh, chunk_submeshes)),
+        ExportFormat::Obj => fs::write(path, encode_obj(mesh, chunk_submeshes)),
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn export_root() -> Option<PathBuf> {
+    Some(PathBuf::from("exports"))
+}
+
+// nowhere to write files on wasm
+#[cfg(target_arch = "wasm32")]
+pub fn export_root() -> Option<PathBuf> {
+    None
+}
+
+// a new file for every export, named after the time so older exports are kept
+pub fn export_path(name: &str, extension: &str) -> Option<PathBuf> {
+    let seconds = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    export_root().map(|root| root.join(format!("{}-{}.{}", name, seconds, extension)))
+}
+
+// only spawned chunks have their heights around, the rest of a region is left out
+pub fn spawned_export_chunks<'a>(
+    tricoords: &[TriCoord<i32>],
+    chunks: &Chunks,
+    query: &'a Query<(&ChunkHeights, &ChunkMaterials)>
+) -> Vec<ExportChunk<'a>> {
+    let mut export_chunks = Vec::new();
+    for tricoord in tricoords {
+        let Some(entity) = chunks.states.get(tricoord)
+        .filter(|state| state.status == ChunkStatus::Spawned)
+        .and_then(|state| state.entity) else {
+            continue;
+        };
+        if let Ok((heights, materials)) = query.get(entity) {
+            export_chunks.push(ExportChunk { tricoord: *tricoord, lattice: &heights.0, materials: &materials.0 });
+        }
+    }
+    return export_chunks;
+}
+
+fn export_terrain(
+    mut export_events: EventReader<ExportTerrain>,
+    chunks: Res<Chunks>,
+    query: Query<(&ChunkHeights, &ChunkMaterials)>
+) {
+    for export in export_events.read() {
+        if export_root().is_none() {
+            warn!("terrain export needs a file system, it is not available on wasm");
+            continue;
+        }
+
+        let tricoords = export.region.tricoords();
+        let export_chunks = spawned_export_chunks(&tricoords, &chunks, &query);
+        if export_chunks.is_empty() {
+            warn!("nothing to export, none of the {} chunks of the region are spawned", tricoords.len());
+            continue;
+        }
+
+        // the middle of the region ends up at zero in the file, at ground level
+        let origin = match &export.region {
+            ExportRegion::Around { center, .. } => DVec3::new(center.x, 0.0, center.z),
+            ExportRegion::Chunks(_) => {
+                let sum = export_chunks.iter().map(|chunk| {
+                    let coord = trichunk_to_coord(chunk.tricoord, 0);
+                    DVec3::new(coord.x, 0.0, coord.z)
+                })
+                .sum::<DVec3>();
+                sum / export_chunks.len() as f64
+            }
+        };
+        let mesh = build_region_mesh(&export_chunks, origin);
+
+        let Some(path) = export_path("terrain", export.format.extension()) else {
+            continue;
+        };
+        match write_region_mesh(&path, &mesh, export.format, export.chunk_submeshes) {
+            Ok(()) => info!("exported {} of {} chunks, {} vertices, to {}", export_chunks.len(), tricoords.len(), mesh.positions.len(), path.display()),
+            Err(error) => warn!("could not export the terrain to {}: {}", path.display(), error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use super::super::chunk_mesh::{lattice_vertex_count, test_lattice};
+
+    const SIDE:usize = CHUNK_SIDE as usize;
+    const CHUNK:TriCoord<i32> = TriCoord { a: 0, b: 0, c: 0 };
+    const NEIGHBORS:[TriCoord<i32>; 3] = [TriCoord { a: 1, b: 0, c: 0 }, TriCoord { a: 0, b: 1, c: 0 }, TriCoord { a: 0, b: 0, c: 1 }];
+
+    fn region_origin() -> DVec3 {
+        let origin = trichunk_to_coord(CHUNK, 0);
+        DVec3::new(origin.x, 0.0, origin.z)
+    }
+
+    // every lattice vertex of the chunk, absolute
+    fn absolute_positions(tricoord: TriCoord<i32>) -> Vec<DVec3> {
+        let lattice = test_lattice(tricoord.is_odd());
+        let chunk_coord = trichunk_to_coord(tricoord, 0);
+        let chunk_origin = DVec3::new(chunk_coord.x, 0.0, chunk_coord.z);
+        (0..=SIDE).flat_map(|row| (0..=SIDE - row).map(move |col| (row, col)))
+        .map(|(row, col)| chunk_origin + lattice.position(row, col).as_dvec3())
+        .collect()
+    }
+
+    fn region_mesh(tricoords: &[TriCoord<i32>]) -> RegionMesh {
+        let lattices: Vec<ChunkLattice> = tricoords.iter().map(|tricoord| test_lattice(tricoord.is_odd())).collect();
+        let materials = vec![0; SIDE * SIDE];
+        let chunks: Vec<ExportChunk> = tricoords.iter().zip(lattices.iter())
+        .map(|(tricoord, lattice)| ExportChunk { tricoord: *tricoord, lattice, materials: &materials })
+        .collect();
+        build_region_mesh(&chunks, region_origin())
+    }
+
+    #[test]
+    fn welded_neighbors_have_no_duplicate_vertices() {
+        for neighbor in NEIGHBORS {
+            let mesh = region_mesh(&[CHUNK, neighbor]);
+            let keys: HashSet<VertexKey> = mesh.positions.iter()
+            .map(|position| vertex_key(region_origin() + position.as_dvec3()))
+            .collect();
+            assert_eq!(keys.len(), mesh.positions.len(), "duplicate vertices next to {:?}", neighbor);
+            // the border both chunks have is only there once
+            assert_eq!(mesh.positions.len(), 2 * lattice_vertex_count(SIDE) - (SIDE + 1), "border next to {:?} is not welded", neighbor);
+        }
+    }
+
+    #[test]
+    fn border_vertices_are_the_same_vertex_in_both_chunks() {
+        for neighbor in NEIGHBORS {
+            let mesh = region_mesh(&[CHUNK, neighbor]);
+            let welded: HashMap<VertexKey, u32> = mesh.positions.iter().enumerate()
+            .map(|(index, position)| (vertex_key(region_origin() + position.as_dvec3()), index as u32))
+            .collect();
+            let used = |submesh: usize| -> HashSet<u32> { mesh.submeshes[submesh].1.iter().flatten().copied().collect() };
+            let shared: HashSet<u32> = used(0).intersection(&used(1)).copied().collect();
+            assert_eq!(shared.len(), SIDE + 1, "the chunks next to {:?} do not share their border", neighbor);
+
+            // the border vertices of either chunk are each one of the shared vertices, all of them are used.
+            // the two chunks compute them a float step apart, the welded vertex is one exact position for both
+            for tricoord in [CHUNK, neighbor] {
+                let mut border = HashSet::new();
+                for absolute in absolute_positions(tricoord) {
+                    let index = welded[&vertex_key(absolute)];
+                    if !shared.contains(&index) {
+                        continue;
+                    }
+                    assert!(mesh.positions[index as usize].distance((absolute - region_origin()).as_vec3()) < 1e-4, "border vertex of {:?} moved", tricoord);
+                    border.insert(index);
+                }
+                assert_eq!(border, shared);
+            }
+        }
+    }
+
+    #[test]
+    fn welded_border_is_closed() {
+        for neighbor in NEIGHBORS {
+            let mesh = region_mesh(&[CHUNK, neighbor]);
+            let mut edge_count: HashMap<(u32, u32), usize> = HashMap::new();
+            for (_, triangles) in mesh.submeshes.iter() {
+                for [v0, v1, v2] in triangles.iter().copied() {
+                    for (a, b) in [(v0, v1), (v1, v2), (v2, v0)] {
+                        *edge_count.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+                    }
+                }
+            }
+            assert!(edge_count.values().all(|count| *count <= 2));
+            // only the outline of the two chunks is left open, the shared side is not
+            let open_edges = edge_count.values().filter(|count| **count == 1).count();
+            assert_eq!(open_edges, 4 * SIDE, "border next to {:?} is open", neighbor);
+        }
+    }
+
+    #[test]
+    fn obj_export_has_every_vertex_once() {
+        let mesh = region_mesh(&[CHUNK, NEIGHBORS[0]]);
+        let obj = encode_obj(&mesh, true);
+        assert_eq!(obj.lines().filter(|line| line.starts_with("v ")).count(), mesh.positions.len());
+        assert_eq!(obj.lines().filter(|line| line.starts_with("g ")).count(), 2);
+        assert_eq!(obj.lines().filter(|line| line.starts_with("f ")).count(), 2 * SIDE * SIDE);
+    }
+}
@@ -0,0 +1,308 @@
+use std::{collections::HashMap, fs, path::{Path, PathBuf}};
+
+use bevy::{math::DVec2, prelude::*};
+use bevy::tasks::futures_lite::future;
+use bevy::tasks::{block_on, AsyncComputeTaskPool, Task};
+use image::{ImageBuffer, ImageResult, Luma, Rgba, RgbaImage};
+
+use crate::ingame::tricoord::*;
+
+use super::chunk_mesh::ChunkLattice;
+use super::ground_material::{ChunkMaterials, GroundMaterial};
+use super::terrain::{ChunkHeights, Chunks};
+use super::terrain_export::{export_path, export_root, spawned_export_chunks, ExportChunk, ExportRegion};
+
+pub struct TerrainMapPlugin;
+
+impl Plugin for TerrainMapPlugin {
+    fn build(&self, app: &mut App) {
+        app
+        .init_resource::<MapSettings>()
+        .init_resource::<MapRenderTasks>()
+        .add_event::<ExportMapImages>()
+        .add_systems(Update, (export_map_images, finish_map_images).chain())
+        ;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MapKind {
+    // 16 bit grayscale, height_min is black and height_max white
+    Height,
+    // gray hillshade lit from the north west
+    Relief,
+    // the ground material colors, shaded by the slope
+    Colored,
+}
+
+pub const MAP_KINDS:[MapKind; 3] = [MapKind::Height, MapKind::Relief, MapKind::Colored];
+
+impl MapKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            MapKind::Height => "height",
+            MapKind::Relief => "relief",
+            MapKind::Colored => "colored",
+        }
+    }
+}
+
+// how the map images of a region are rendered, the debug panel edits the resource
+#[derive(Resource, Clone, Debug)]
+pub struct MapSettings {
+    // world units per pixel
+    pub pixel_size: f64,
+    // the fixed height range of the height map, so the images of different seeds compare
+    pub height_min: f32,
+    pub height_max: f32,
+    // draws the chunk borders over the relief and colored maps, never over the height map
+    pub chunk_grid: bool,
+}
+
+impl Default for MapSettings {
+    fn default() -> Self {
+        MapSettings { pixel_size: 0.25, height_min: 0.0, height_max: 100.0, chunk_grid: false }
+    }
+}
+
+// images larger than this on either side get a bigger pixel size instead
+const MAX_MAP_SIDE:f64 = 8192.0;
+// direction towards the sun for the hillshade, -z is up in the images
+const SUN_AZIMUTH:f32 = std::f32::consts::FRAC_PI_4;
+const SUN_ELEVATION:f32 = std::f32::consts::FRAC_PI_4;
+const AMBIENT:f32 = 0.15;
+const GRID_COLOR:[u8; 4] = [255, 140, 0, 255];
+
+// samples the heights and materials of a set of chunks at absolute x, z positions
+pub struct RegionSampler<'a> {
+    chunks: HashMap<TriCoord<i32>, &'a ExportChunk<'a>>,
+    // the corners of the box around every chunk
+    pub min: DVec2,
+    pub max: DVec2,
+}
+
+impl<'a> RegionSampler<'a> {
+    pub fn new(export_chunks: &'a [ExportChunk<'a>]) -> Self {
+        let mut min = DVec2::splat(f64::MAX);
+        let mut max = DVec2::splat(f64::MIN);
+        for chunk in export_chunks {
+            let chunk_coord = trichunk_to_coord(chunk.tricoord, 0);
+            let side = chunk.lattice.side;
+            for (row, col) in [(0, 0), (0, side), (side, 0)] {
+                let corner = DVec2::new(chunk_coord.x, chunk_coord.z) + chunk.lattice.position(row, col).xz().as_dvec2();
+                min = min.min(corner);
+                max = max.max(corner);
+            }
+        }
+        let chunks = export_chunks.iter().map(|chunk| (chunk.tricoord, chunk)).collect();
+        return RegionSampler { chunks, min, max };
+    }
+
+    // the chunk under a position and the position relative to its origin
+    fn locate(&self, x: f64, z: f64) -> Option<(&'a ExportChunk<'a>, Vec3)> {
+        let tricoord = coord_to_trichunk(Coord { x, z }).ok()?;
+        let chunk = *self.chunks.get(&tricoord)?;
+        let chunk_coord = trichunk_to_coord(tricoord, 0);
+        return Some((chunk, Vec3::new((x - chunk_coord.x) as f32, 0.0, (z - chunk_coord.z) as f32)));
+    }
+
+    pub fn height(&self, x: f64, z: f64) -> Option<f32> {
+        let (chunk, local) = self.locate(x, z)?;
+        return Some(chunk.lattice.height_at(local));
+    }
+
+    pub fn material(&self, x: f64, z: f64) -> Option<GroundMaterial> {
+        let (chunk, local) = self.locate(x, z)?;
+        let id = chunk.materials.get(chunk.lattice.trianglet_at(local)).copied().unwrap_or(0);
+        return Some(GroundMaterial::from_id(id));
+    }
+
+    // distance to the nearest border of the chunk under a position. the chunks are equilateral,
+    // so every barycentric weight times the altitude is the distance to the opposite side
+    pub fn border_distance(&self, x: f64, z: f64) -> Option<f32> {
+        let (chunk, local) = self.locate(x, z)?;
+        let side = chunk.lattice.side;
+        let [v0, v1, v2] = [(0, 0), (0, side), (side, 0)].map(|(row, col)| chunk.lattice.position(row, col).xz());
+        let (e1, e2, p) = (v1 - v0, v2 - v0, local.xz() - v0);
+        let area = e1.perp_dot(e2);
+        let w1 = p.perp_dot(e2) / area;
+        let w2 = e1.perp_dot(p) / area;
+        return Some((1.0 - w1 - w2).min(w1).min(w2).max(0.0) * CHUNK_ALTITUDE as f32);
+    }
+
+    // the unit normal of the terrain from the heights one pixel around, edges of the region use the middle height
+    fn normal(&self, x: f64, z: f64, height: f32, pixel_size: f64) -> Vec3 {
+        let sample = |dx: f64, dz: f64| self.height(x + dx, z + dz).unwrap_or(height);
+        let dh_dx = (sample(pixel_size, 0.0) - sample(-pixel_size, 0.0)) / (2.0 * pixel_size) as f32;
+        let dh_dz = (sample(0.0, pixel_size) - sample(0.0, -pixel_size)) / (2.0 * pixel_size) as f32;
+        return Vec3::new(-dh_dx, 1.0, -dh_dz).normalize();
+    }
+}
+
+// the image size and the pixel size actually used, which grows for very large regions
+pub fn map_size(sampler: &RegionSampler, settings: &MapSettings) -> (u32, u32, f64) {
+    let extent = (sampler.max - sampler.min).max(DVec2::ZERO);
+    let pixel_size = settings.pixel_size.max(extent.max_element() / MAX_MAP_SIDE);
+    return ((extent.x / pixel_size).ceil().max(1.0) as u32, (extent.y / pixel_size).ceil().max(1.0) as u32, pixel_size);
+}
+
+// pixel (0, 0) is the minimum x, z corner, rows go towards +z
+fn pixel_position(sampler: &RegionSampler, pixel_size: f64, px: u32, py: u32) -> DVec2 {
+    sampler.min + DVec2::new(px as f64 + 0.5, py as f64 + 0.5) * pixel_size
+}
+
+fn hillshade(normal: Vec3) -> f32 {
+    let sun = Vec3::new(-SUN_AZIMUTH.sin() * SUN_ELEVATION.cos(), SUN_ELEVATION.sin(), -SUN_AZIMUTH.cos() * SUN_ELEVATION.cos());
+    return AMBIENT + (1.0 - AMBIENT) * normal.dot(sun).max(0.0);
+}
+
+// heights past the range are clamped to black and white
+fn height_pixel(height: f32, settings: &MapSettings) -> u16 {
+    let range = (settings.height_max - settings.height_min).max(f32::EPSILON);
+    let value = ((height - settings.height_min) / range).clamp(0.0, 1.0);
+    return (value * u16::MAX as f32).round() as u16;
+}
+
+// positions outside the chunks of the region are 0
+pub fn render_height_map(sampler: &RegionSampler, settings: &MapSettings) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+    let (width, height, pixel_size) = map_size(sampler, settings);
+    return ImageBuffer::from_fn(width, height, |px, py| {
+        let position = pixel_position(sampler, pixel_size, px, py);
+        Luma([sampler.height(position.x, position.y).map_or(0, |height| height_pixel(height, settings))])
+    });
+}
+
+// positions outside the chunks of the region are transparent
+pub fn render_color_map(sampler: &RegionSampler, settings: &MapSettings, kind: MapKind) -> RgbaImage {
+    let (width, height, pixel_size) = map_size(sampler, settings);
+    // the grid lines are about a pixel and a half wide at any pixel size
+    let grid_width = (pixel_size * 0.75) as f32;
+    return ImageBuffer::from_fn(width, height, |px, py| {
+        let position = pixel_position(sampler, pixel_size, px, py);
+        let Some(terrain_height) = sampler.height(position.x, position.y) else {
+            return Rgba([0, 0, 0, 0]);
+        };
+        if settings.chunk_grid && sampler.border_distance(position.x, position.y).is_some_and(|distance| distance < grid_width) {
+            return Rgba(GRID_COLOR);
+        }
+
+        let shade = hillshade(sampler.normal(position.x, position.y, terrain_height, pixel_size));
+        let color = match kind {
+            MapKind::Colored => {
+                // the palette is linear like the shader, the png is srgb
+                let [r, g, b, _] = sampler.material(position.x, position.y).unwrap_or_default().color();
+                let srgb = Color::LinearRgba(LinearRgba::new(r * shade, g * shade, b * shade, 1.0)).to_srgba();
+                [srgb.red, srgb.green, srgb.blue]
+            }
+            _ => [shade; 3],
+        };
+        let [r, g, b] = color.map(|channel| (channel.clamp(0.0, 1.0) * 255.0).round() as u8);
+        Rgba([r, g, b, 255])
+    });
+}
+
+// writes one png per kind next to each other, named stem-kind.png
+pub fn write_map_images(sampler: &RegionSampler, settings: &MapSettings, stem: &Path) -> ImageResult<Vec<PathBuf>> {
+    if let Some(dir) = stem.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut paths = Vec::with_capacity(MAP_KINDS.len());
+    for kind in MAP_KINDS {
+        let path = PathBuf::from(format!("{}-{}.png", stem.display(), kind.name()));
+        match kind {
+            MapKind::Height => render_height_map(sampler, settings).save(&path)?,
+            _ => render_color_map(sampler, settings, kind).save(&path)?,
+        }
+        paths.push(path);
+    }
+    return Ok(paths);
+}
+
+// renders the spawned chunks of the region to images in the export directory
+#[derive(Event, Clone, Debug)]
+pub struct ExportMapImages {
+    pub region: ExportRegion,
+    pub settings: MapSettings,
+}
+
+// the map images being rendered in the background, rendering a large region takes seconds
+#[derive(Resource, Default)]
+struct MapRenderTasks {
+    tasks: Vec<Task<String>>,
+}
+
+// copies the spawned chunks of the region and renders them on the async compute pool
+fn export_map_images(
+    mut export_events: EventReader<ExportMapImages>,
+    chunks: Res<Chunks>,
+    query: Query<(&ChunkHeights, &ChunkMaterials)>,
+    mut render_tasks: ResMut<MapRenderTasks>
+) {
+    for export in export_events.read() {
+        if export_root().is_none() {
+            warn!("map image export needs a file system, it is not available on wasm");
+            continue;
+        }
+
+        let tricoords = export.region.tricoords();
+        let export_chunks = spawned_export_chunks(&tricoords, &chunks, &query);
+        if export_chunks.is_empty() {
+            warn!("nothing to render, none of the {} chunks of the region are spawned", tricoords.len());
+            continue;
+        }
+        // export_path is only used for the stem, every kind adds its name
+        let Some(stem) = export_path("map", "png").map(|path| path.with_extension("")) else {
+            continue;
+        };
+
+        let owned_chunks: Vec<(TriCoord<i32>, ChunkLattice, Vec<u8>)> = export_chunks.iter()
+        .map(|chunk| (chunk.tricoord, chunk.lattice.clone(), chunk.materials.to_vec()))
+        .collect();
+        let region_chunks = tricoords.len();
+        let settings = export.settings.clone();
+        let task = AsyncComputeTaskPool::get().spawn(async move {
+            let export_chunks: Vec<ExportChunk> = owned_chunks.iter()
+            .map(|(tricoord, lattice, materials)| ExportChunk { tricoord: *tricoord, lattice, materials })
+            .collect();
+            let sampler = RegionSampler::new(&export_chunks);
+            let (width, height, _) = map_size(&sampler, &settings);
+            return match write_map_images(&sampler, &settings, &stem) {
+                Ok(paths) => format!("rendered {} of {} chunks to {} x {} images: {}", export_chunks.len(), region_chunks, width, height,
+                    paths.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", ")),
+                Err(error) => format!("could not write the map images {}: {}", stem.display(), error),
+            };
+        });
+        render_tasks.tasks.push(task);
+    }
+}
+
+fn finish_map_images(mut render_tasks: ResMut<MapRenderTasks>) {
+    render_tasks.tasks.retain_mut(|task| {
+        let Some(status) = block_on(future::poll_once(task)) else {
+            return true;
+        };
+        info!("{}", status);
+        return false;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heights_map_onto_the_whole_pixel_range() {
+        let settings = MapSettings { height_min: -20.0, height_max: 80.0, ..default() };
+        assert_eq!(height_pixel(-20.0, &settings), 0);
+        assert_eq!(height_pixel(80.0, &settings), u16::MAX);
+        assert_eq!(height_pixel(30.0, &settings), 32768);
+        assert_eq!(height_pixel(55.0, &settings), (0.75 * u16::MAX as f32).round() as u16);
+        // past the range they stay black and white
+        assert_eq!(height_pixel(-100.0, &settings), 0);
+        assert_eq!(height_pixel(1000.0, &settings), u16::MAX);
+        // a range of nothing does not divide by zero
+        let flat = MapSettings { height_min: 10.0, height_max: 10.0, ..default() };
+        assert_eq!(height_pixel(10.0, &flat), 0);
+        assert_eq!(height_pixel(11.0, &flat), u16::MAX);
+    }
+}
@@ -0,0 +1,239 @@
+use std::{collections::HashMap, mem};
+
+use bevy::{pbr::{ExtendedMaterial, MaterialExtension, MaterialExtensionKey, MaterialExtensionPipeline}, prelude::*, render::{mesh::{MeshVertexBufferLayoutRef, PrimitiveTopology}, render_asset::RenderAssetUsages, render_resource::{AsBindGroup, RenderPipelineDescriptor, ShaderRef, SpecializedMeshPipelineError}}};
+use bevy_inspector_egui::{prelude::ReflectInspectorOptions, InspectorOptions};
+
+use crate::ingame::tricoord::*;
+
+use super::chunk_mesh::{mesh_memory_bytes, GroundLayer};
+use super::ground_material::{ChunkMaterials, ATTRIBUTE_GROUND_MATERIAL, GROUND_MATERIAL_SHADER_LOCATION};
+use super::terrain::*;
+
+// the meshes and materials of the chunks TerrainCorePlugin generates, everything that needs a renderer
+pub struct TerrainRenderPlugin;
+
+impl Plugin for TerrainRenderPlugin {
+    fn build(&self, app: &mut App) {
+        app
+        .add_plugins(MaterialPlugin::<ExtendedMaterial<StandardMaterial, MyMaterial>>::default())
+        .add_systems(Startup, setup_terrain_assets)
+        .insert_resource(SelectedTerrainMat { selected_mat: "my_mat".into() })
+        .register_type::<SelectedTerrainMat>()
+        // tells the core to build render meshes at all
+        .insert_resource(TerrainMeshing)
+        .init_resource::<ChunkMeshAssets>()
+        .add_systems(Update, update_chunk_lods.run_if(run_if_anchored))
+        // PostUpdate sees the meshes of every Update system the same frame
+        .add_systems(PostUpdate, (release_unloaded_chunk_meshes, upload_chunk_meshes).chain())
+        ;
+    }
+}
+
+#[derive(Asset, AsBindGroup, TypePath, Debug, Clone)]
+pub struct MyMaterial {}
+
+impl MaterialExtension for MyMaterial {
+    fn vertex_shader() -> ShaderRef {
+        "shaders/animate_shader.wgsl".into()
+    }
+
+    fn fragment_shader() -> ShaderRef {
+        "shaders/animate_shader.wgsl".into()
+    }
+
+    // the standard material only lays out the attributes it knows, the ground material goes in behind them
+    fn specialize(
+        _pipeline: &MaterialExtensionPipeline,
+        descriptor: &mut RenderPipelineDescriptor,
+        layout: &MeshVertexBufferLayoutRef,
+        _key: MaterialExtensionKey<Self>
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        if !layout.0.contains(ATTRIBUTE_GROUND_MATERIAL) {
+            return Ok(());
+        }
+        let ground_layout = layout.0.get_layout(&[ATTRIBUTE_GROUND_MATERIAL.at_shader_location(GROUND_MATERIAL_SHADER_LOCATION)])?;
+        descriptor.vertex.buffers[0].attributes.extend(ground_layout.attributes);
+        descriptor.vertex.shader_defs.push("GROUND_MATERIAL".into());
+        if let Some(fragment) = descriptor.fragment.as_mut() {
+            fragment.shader_defs.push("GROUND_MATERIAL".into());
+        }
+        return Ok(());
+    }
+}
+
+#[derive(Reflect, Resource, InspectorOptions)]
+#[reflect(Resource, InspectorOptions)]
+pub struct SelectedTerrainMat {
+    pub selected_mat: String
+}
+
+#[derive(Resource)]
+pub struct TerrainHandles {
+    pub mat_hdls: HashMap<String, UntypedHandle>,
+    mesh_hdls: HashMap<String, Handle<Mesh>>,
+    height_map_hdls: HashMap<Coord<i32>, UntypedHandle>
+}
+fn setup_terrain_assets(
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut standard_materials: ResMut<Assets<StandardMaterial>>,
+    mut mymat_materials: ResMut<Assets<ExtendedMaterial<StandardMaterial, MyMaterial>>>,
+    mut commands: Commands
+) {
+    let shiny_material = standard_materials.add(
+        StandardMaterial {
+            // base_color: Srgba::hex("#6dbe4b").unwrap().into(),
+            base_color: Color::srgb(0.5, 0.5, 0.5),
+            metallic: 1.0,
+            perceptual_roughness: 0.0,
+            reflectance: 1.0,
+            ..default()
+    });
+
+    let my_material = mymat_materials.add(
+        ExtendedMaterial {
+            base: StandardMaterial {
+                // base_color: Srgba::hex("#6dbe4b").unwrap().into(),
+                // base_color: Color::srgb(1.0, 1.0, 1.0),
+                metallic: 0.0,
+                perceptual_roughness: 1.0,
+                reflectance: 0.0,
+                ..default()
+            },
+            extension: MyMaterial {  }
+        }
+    );
+
+    // let mut mesh = Plane3d::default().mesh().size(16., 16.).build();
+
+    // if let Some(VertexAttributeValues::Float32x3(positions)) =
+    //     mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+    // {
+    //     let colors: Vec<[f32; 4]> = positions
+    //         .iter()
+    //         .map(|[r, g, b]| [(1. - *r) / 2., (1. - *g) / 2., (1. - *b) / 2., 1.])
+    //         .collect();
+    //     // mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+    // }
+
+    // let mesh_handle = meshes.add(mesh);
+
+    let terrain_hdls = TerrainHandles {
+        mat_hdls: HashMap::from([("shiny".into(), shiny_material.untyped()), ("my_mat".into(), my_material.untyped())]),
+        mesh_hdls: HashMap::from([/*("chunk_plane".into(), mesh_handle)*/]),
+        height_map_hdls: HashMap::new()
+    };
+
+    commands.insert_resource(terrain_hdls);
+}
+
+
+// gives a terrain entity the terrain material picked in the debug panel
+pub fn insert_terrain_material(
+    entity_commands: &mut bevy::ecs::system::EntityCommands,
+    environ_assets: &TerrainHandles,
+    selected_mat: &SelectedTerrainMat
+) {
+    if selected_mat.selected_mat == "shiny" {
+        entity_commands.insert(environ_assets.mat_hdls["shiny"].clone().typed::<StandardMaterial>());
+    } else if selected_mat.selected_mat == "my_mat" {
+        entity_commands.insert(environ_assets.mat_hdls["my_mat"].clone().typed::<ExtendedMaterial<StandardMaterial, MyMaterial>>());
+    }
+}
+
+
+// remeshes spawned chunks whose distance to the generation origin moved them to another lod level.
+// the lod only changes the render mesh, so it lives here and headless chunks keep their lod
+fn update_chunk_lods(
+    mut query: Query<(Entity, &mut TerrainMesh, &ChunkHeights, &ChunkMaterials, &Handle<Mesh>, &mut ChunkMeshRevision)>,
+    mut chunks: ResMut<Chunks>,
+    terrain_config: Res<TerrainConfig>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut terrain_stats: ResMut<TerrainStats>,
+    mut modified_events: EventWriter<ChunkModified>
+) {
+    for (entity, mut terrain_mesh, heights, materials, mesh_handle, mut revision) in query.iter_mut() {
+        // refined chunks are always drawn at full resolution
+        if terrain_mesh.refined_around.is_some() {
+            continue;
+        }
+        let distance = chunks.chunk_anchor_distance(terrain_mesh.tricoord);
+        let lod = select_chunk_lod(terrain_mesh.lod, distance, &terrain_config);
+        if lod == terrain_mesh.lod {
+            continue;
+        }
+
+        let Some(mesh) = meshes.get_mut(mesh_handle) else {
+            continue;
+        };
+        let (new_mesh, vertices) = generate_mesh(&heights.0, lod, &terrain_config, &GroundLayer { lattice: &heights.0, materials: &materials.0 });
+        terrain_stats.mesh_bytes = terrain_stats.mesh_bytes + mesh_memory_bytes(&new_mesh) - mesh_memory_bytes(mesh);
+        *mesh = new_mesh;
+        revision.0 += 1;
+        terrain_mesh.lod = lod;
+        if let Some(state) = chunks.states.get_mut(&terrain_mesh.tricoord) {
+            state.lod = lod;
+            terrain_stats.replace_vertices(state, vertices);
+        }
+        modified_events.send(ChunkModified { tricoord: terrain_mesh.tricoord, entity, collider_changed: false });
+    }
+}
+
+
+// counts the times the mesh asset of a chunk was replaced, so meshes merged from it can tell they are out of date
+#[derive(Component, Default)]
+pub struct ChunkMeshRevision(pub u32);
+
+// the mesh asset of every chunk entity, so it can be removed once the entity is gone
+#[derive(Resource, Default)]
+struct ChunkMeshAssets {
+    meshes: HashMap<Entity, AssetId<Mesh>>,
+}
+
+// moves the meshes the core built into the mesh assets, new chunks also get their material here
+fn upload_chunk_meshes(
+    mut query: Query<(Entity, &mut ChunkMeshUpdate, Option<&Handle<Mesh>>, Option<&mut ChunkMeshRevision>)>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut chunk_meshes: ResMut<ChunkMeshAssets>,
+    mut terrain_stats: ResMut<TerrainStats>,
+    environ_assets: Res<TerrainHandles>,
+    selected_mat: Res<SelectedTerrainMat>,
+    mut commands: Commands
+) {
+    for (entity, mut update, mesh_handle, revision) in query.iter_mut() {
+        let mesh = mem::replace(&mut update.0, Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::default()));
+        commands.entity(entity).remove::<ChunkMeshUpdate>();
+
+        if let Some(existing) = mesh_handle.and_then(|mesh_handle| meshes.get_mut(mesh_handle)) {
+            terrain_stats.mesh_bytes = terrain_stats.mesh_bytes + mesh_memory_bytes(&mesh) - mesh_memory_bytes(existing);
+            *existing = mesh;
+            if let Some(mut revision) = revision {
+                revision.0 += 1;
+            }
+            continue;
+        }
+
+        terrain_stats.mesh_bytes += mesh_memory_bytes(&mesh);
+        let mesh_handle = meshes.add(mesh);
+        chunk_meshes.meshes.insert(entity, mesh_handle.id());
+        let mut entity_commands = commands.entity(entity);
+        entity_commands.insert((mesh_handle, ChunkMeshRevision::default()));
+        insert_terrain_material(&mut entity_commands, &environ_assets, &selected_mat);
+    }
+}
+
+// the mesh asset is removed right away instead of waiting for the handle to drop
+fn release_unloaded_chunk_meshes(
+    mut unloaded_events: EventReader<ChunkUnloaded>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut chunk_meshes: ResMut<ChunkMeshAssets>,
+    mut terrain_stats: ResMut<TerrainStats>
+) {
+    for unloaded in unloaded_events.read() {
+        let Some(mesh_id) = chunk_meshes.meshes.remove(&unloaded.entity) else {
+            continue;
+        };
+        if let Some(mesh) = meshes.remove(mesh_id) {
+            terrain_stats.mesh_bytes -= mesh_memory_bytes(&mesh);
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use bevy::math::{DVec3, Vec3A};
+use bevy::prelude::*;
+
+use crate::ingame::environment::terrain::TerrainRoot;
+use crate::ingame::tricoord::*;
+
+pub struct FloatingOriginPlugin;
+
+impl Plugin for FloatingOriginPlugin {
+    fn build(&self, app: &mut App) {
+        app
+        .init_resource::<FloatingOrigin>()
+        .register_type::<FloatingOrigin>()
+        .register_type::<AbsolutePosition>()
+        // before anything reads the transforms this frame, so nothing sees the world half shifted
+        .add_systems(First, recenter_world)
+        .add_systems(PostUpdate, update_absolute_positions.after(TransformSystem::TransformPropagate))
+        ;
+    }
+}
+
+// transforms are f32 and get jittery far from zero, so the whole world is moved back to zero around the focus
+// every now and then. the offset is where the local zero is in the f64 world.
+#[derive(Resource, Reflect)]
+#[reflect(Resource)]
+pub struct FloatingOrigin {
+    pub offset: DVec3,
+    // the world is recentered once the focus is this far from the local zero
+    pub recenter_distance: f32,
+    pub recenters: usize,
+}
+
+impl Default for FloatingOrigin {
+    fn default() -> Self {
+        FloatingOrigin {
+            offset: DVec3::ZERO,
+            recenter_distance: 1024.0,
+            recenters: 0,
+        }
+    }
+}
+
+impl FloatingOrigin {
+    pub fn to_absolute(&self, local: Vec3) -> DVec3 {
+        self.offset + local.as_dvec3()
+    }
+
+    pub fn to_local(&self, absolute: DVec3) -> Vec3 {
+        (absolute - self.offset).as_vec3()
+    }
+
+    // where a chunk sits in the local world
+    pub fn chunk_to_local(&self, tricoord: TriCoord<i32>) -> Vec3 {
+        let chunk_coord = trichunk_to_coord(tricoord, 0);
+        self.to_local(DVec3::new(chunk_coord.x, 0.0, chunk_coord.z))
+    }
+}
+
+// the world recenters around this entity, the player
+#[derive(Component, Default)]
+pub struct FloatingOriginFocus;
+
+// the f64 position of an entity that survives recentering, for gameplay and the debug panel
+#[derive(Component, Reflect, Clone, Copy, Debug)]
+#[reflect(Component)]
+pub struct AbsolutePosition {
+    pub position: DVec3,
+    pub tricoord: TriCoord<i32>,
+}
+
+impl Default for AbsolutePosition {
+    fn default() -> Self {
+        AbsolutePosition { position: DVec3::ZERO, tricoord: TriCoord { a: 0, b: 0, c: 0 } }
+    }
+}
+
+fn recenter_world(
+    focus_query: Query<&GlobalTransform, With<FloatingOriginFocus>>,
+    mut root_query: Query<(&mut Transform, Option<&mut GlobalTransform>), (Without<Parent>, Without<TerrainRoot>)>,
+    terrain_root_query: Query<&Children, With<TerrainRoot>>,
+    mut child_query: Query<(&mut Transform, Option<&mut GlobalTransform>), With<Parent>>,
+    mut floating_origin: ResMut<FloatingOrigin>
+) {
+    let Ok(focus) = focus_query.get_single() else {
+        return;
+    };
+    let focus_position = focus.translation();
+    if focus_position.xz().length() < floating_origin.recenter_distance {
+        return;
+    }
+
+    // whole chunk sides keep the numbers round, the height is never far from zero
+    let side = CHUNK_SIDE as f32;
+    let shift = Vec3::new((focus_position.x / side).round() * side, 0.0, (focus_position.z / side).round() * side);
+
+    // the global transforms of the shifted entities move along, so nothing this frame reads them half shifted.
+    // everything below them follows in the propagation at the end of the frame
+    for (mut transform, global_transform) in root_query.iter_mut() {
+        transform.translation -= shift;
+        if let Some(mut global_transform) = global_transform {
+            shift_global_transform(&mut global_transform, shift);
+        }
+    }
+    // the terrain root stays at zero, so its chunks are moved one by one instead.
+    // their translations are recomputed from f64 on spawn, so they stay exact however far the world goes
+    for children in terrain_root_query.iter() {
+        for child in children.iter() {
+            if let Ok((mut transform, global_transform)) = child_query.get_mut(*child) {
+                transform.translation -= shift;
+                if let Some(mut global_transform) = global_transform {
+                    shift_global_transform(&mut global_transform, shift);
+                }
+            }
+        }
+    }
+
+    floating_origin.offset += shift.as_dvec3();
+    floating_origin.recenters += 1;
+    debug!("recentered world by {} {}, offset is now {:.01} {:.01}", shift.x, shift.z, floating_origin.offset.x, floating_origin.offset.z);
+}
+
+fn shift_global_transform(global_transform: &mut GlobalTransform, shift: Vec3) {
+    let mut affine = global_transform.affine();
+    affine.translation -= Vec3A::from(shift);
+    *global_transform = GlobalTransform::from(affine);
+}
+
+fn update_absolute_positions(
+    mut query: Query<(&GlobalTransform, &mut AbsolutePosition)>,
+    floating_origin: Res<FloatingOrigin>
+) {
+    for (transform, mut absolute) in query.iter_mut() {
+        let position = floating_origin.to_absolute(transform.translation());
+        absolute.position = position;
+        // past the end of the chunk coordinates the last one that fit is kept
+        if let Ok(tricoord) = coord_to_trichunk(Coord { x: position.x, z: position.z }) {
+            absolute.tricoord = tricoord;
+        }
+    }
+}
@@ -1,3 +1,4 @@
 pub mod environment;
+pub mod floating_origin;
 pub mod player;
 pub mod tricoord;
\ No newline at end of file
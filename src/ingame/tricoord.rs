@@ -1,4 +1,4 @@
-use bevy::reflect::{FromReflect, Reflect};
+use bevy::{math::DVec3, reflect::{FromReflect, Reflect}};
 
 #[derive(Debug, Reflect)]
 pub struct Coord<T> {
@@ -16,6 +16,48 @@ pub struct TriCoord<T> {
 impl<T: Eq> Eq for TriCoord<T> {
 }
 
+// a chunk coordinate that does not fit the index type, returned instead of wrapping or saturating
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TriCoordOverflow;
+
+impl std::fmt::Display for TriCoordOverflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "chunk coordinate out of range")
+    }
+}
+
+impl std::error::Error for TriCoordOverflow {}
+
+impl TriCoord<i32> {
+    // a + b + c in i64, so it can not overflow. 0 for even chunks and 1 for odd ones
+    pub fn sum(self) -> i64 {
+        self.a as i64 + self.b as i64 + self.c as i64
+    }
+
+    pub fn is_odd(self) -> bool {
+        self.sum() != 0
+    }
+}
+
+impl<T: Copy> TriCoord<T> {
+    // to another index type, for example i16 to send a chunk coordinate over the network
+    pub fn try_cast<U: TryFrom<T>>(self) -> Result<TriCoord<U>, TriCoordOverflow> {
+        Ok(TriCoord {
+            a: U::try_from(self.a).map_err(|_| TriCoordOverflow)?,
+            b: U::try_from(self.b).map_err(|_| TriCoordOverflow)?,
+            c: U::try_from(self.c).map_err(|_| TriCoordOverflow)?,
+        })
+    }
+}
+
+// `as` saturates, this does not
+fn checked_index(value: f64) -> Result<i32, TriCoordOverflow> {
+    if value.is_finite() && value >= i32::MIN as f64 && value <= i32::MAX as f64 {
+        return Ok(value as i32);
+    }
+    Err(TriCoordOverflow)
+}
+
 pub const TRI_SIDE:f32 = 1.0;
 pub const TRI_HALFSIDE:f32 = TRI_SIDE/2.0;
 pub const TRI_ALTITUDE:f32 = 0.866025404;
@@ -28,10 +70,19 @@ pub const CHUNK_ALTITUDE:f64 = 13.856406461;
 pub const CHUNK_HALFALT:f64 = 6.92820323;
 pub const CHUNK_APOTHEM:f64 = 4.618802154;
 
+// a lattice vertex in the world, in half trianglet sides along x and trianglet altitudes along z.
+// the vertices on the border of two chunks are the same key
+pub type VertexKey = (i64, i64);
+
+pub fn vertex_key(position: DVec3) -> VertexKey {
+    ((position.x / TRI_HALFSIDE as f64).round() as i64, (position.z / TRI_ALTITUDE as f64).round() as i64)
+}
+
 // basically going reverse: from the triangle find how many steps to get to origin.
-pub fn trichunk_to_coord(tricoord: TriCoord<i16>, mode: u8) -> Coord<f64> {
+pub fn trichunk_to_coord(tricoord: TriCoord<i32>, mode: u8) -> Coord<f64> {
     // converts a,b,c origin of trichunk to z,x world coordinates
-    let mut temp = tricoord;
+    // in i64, the sum of two i32 coordinates does not always fit an i32
+    let mut temp = TriCoord::<i64> { a: tricoord.a as i64, b: tricoord.b as i64, c: tricoord.c as i64 };
     let mut zx_coord = Coord::<f64> {z:0.0, x:0.0};
 
     // mesh center mode
@@ -82,38 +133,42 @@ pub fn trichunk_to_coord(tricoord: TriCoord<i16>, mode: u8) -> Coord<f64> {
     return zx_coord;
 }
 
-pub fn triangular_number_o1(n: i16) -> i16 {
+pub fn triangular_number_o1(n: i32) -> i32 {
     return n * (n + 1) / 2;
 }
 
-pub fn tricoord_vec_gen_distance(point:Coord<f32>, distance:f32) -> Vec<TriCoord<i16>> {
+pub fn tricoord_vec_gen_distance(point:Coord<f64>, distance:f32) -> Result<Vec<TriCoord<i32>>, TriCoordOverflow> {
 
-    let mut v:Vec<TriCoord<i16>> = Vec::new();
+    let mut v:Vec<TriCoord<i32>> = Vec::new();
+    let distance = distance as f64;
 
     // how many half_sides it takes to get to left most point that is within the distance from the point
-    let leftmost_half_side = ((point.x - distance) / (CHUNK_SIDE as f32 / 2.0)) as i32; 
-    let rightmost_half_side = ((point.x + distance) / (CHUNK_SIDE as f32 / 2.0)) as i32 + ((CHUNK_SIDE as f32 / 2.0)) as i32; 
+    let leftmost_half_side = checked_index((point.x - distance) / CHUNK_HALFSIDE)?; 
+    let rightmost_half_side = checked_index((point.x + distance) / CHUNK_HALFSIDE + CHUNK_HALFSIDE)?; 
 
-    let botmost_altitude = ((point.z - distance) / (CHUNK_ALTITUDE as f32)) as i32; 
-    let topmost_altitude = ((point.z + distance) / (CHUNK_ALTITUDE as f32)) as i32 + (CHUNK_ALTITUDE as i32); 
+    let botmost_altitude = checked_index((point.z - distance) / CHUNK_ALTITUDE)?; 
+    let topmost_altitude = checked_index((point.z + distance) / CHUNK_ALTITUDE + CHUNK_ALTITUDE.trunc())?; 
 
     for half_side_index in leftmost_half_side..rightmost_half_side {
         for altitude_index in botmost_altitude..topmost_altitude {
-            let x = half_side_index as f32 * (CHUNK_SIDE as f32 / 2.0);
-            let z = altitude_index as f32 * (CHUNK_ALTITUDE as f32);
+            let x = half_side_index as f64 * CHUNK_HALFSIDE;
+            let z = altitude_index as f64 * CHUNK_ALTITUDE;
 
-            if f32::powi(x-point.x, 2) + f32::powi(z-point.z, 2) <= f32::powi(distance as f32, 2) {
+            if f64::powi(x-point.x, 2) + f64::powi(z-point.z, 2) <= f64::powi(distance, 2) {
                 
-                v.push(halfsides_altitude_to_tricoord(half_side_index, altitude_index));
+                v.push(halfsides_altitude_to_tricoord(half_side_index, altitude_index)?);
             }
         }
     }
 
-    return v;
+    return Ok(v);
 }
 
 // how do i convert from half_side, b to tricoord(a,b,c) ?
-pub fn halfsides_altitude_to_tricoord(halfsides:i32, altitudes:i32) -> TriCoord<i16> {
+pub fn halfsides_altitude_to_tricoord(halfsides:i32, altitudes:i32) -> Result<TriCoord<i32>, TriCoordOverflow> {
+    // the math is done in i64 so it can not overflow, the result is checked on the way back to i32
+    let (halfsides, altitudes) = (halfsides as i64, altitudes as i64);
+
     // odd is xor of halfsides and altitudes being odd.
     let odd = halfsides % 2 ^ altitudes % 2;
 
@@ -121,20 +176,20 @@ pub fn halfsides_altitude_to_tricoord(halfsides:i32, altitudes:i32) -> TriCoord<
 
     let (mut a, mut c) = if halfsides < 0 {
         // negative halfsides = positive a and negative c
-        let a = i32::abs(halfsides) % 2 + halfsides / 2 * -1;
+        let a = i64::abs(halfsides) % 2 + halfsides / 2 * -1;
         let c = halfsides / 2;
         (a,c) 
     } else {
         // positive halfsides = negative a and positive c
         let a = halfsides / 2 * -1;
-        let c = i32::abs(halfsides) % 2 + halfsides / 2;
+        let c = i64::abs(halfsides) % 2 + halfsides / 2;
         (a,c)
     };
 
-    let current_is_odd = i32::abs(halfsides) % 2;
+    let current_is_odd = i64::abs(halfsides) % 2;
     let ac_adjustment_value = if b < 0 {
         // negative altitudes
-        (i32::abs(altitudes) + (current_is_odd^1) ) / 2
+        (i64::abs(altitudes) + (current_is_odd^1) ) / 2
     } else if b > 0 {
         // positive altitudes
         (altitudes + current_is_odd ) / 2 * -1 
@@ -145,5 +200,152 @@ pub fn halfsides_altitude_to_tricoord(halfsides:i32, altitudes:i32) -> TriCoord<
     a += ac_adjustment_value;
     c += ac_adjustment_value;
 
-    return TriCoord { a:a as i16, b:b as i16, c:c as i16 };
-}
\ No newline at end of file
+    return TriCoord { a, b, c }.try_cast();
+}
+
+// finds the chunk a world position is in, exact on the chunk borders unlike rounding the halfsides
+pub fn coord_to_halfsides_altitude(point: Coord<f64>) -> Result<(i32, i32), TriCoordOverflow> {
+    // every altitude is a horizontal strip of chunks, centered on altitude * CHUNK_ALTITUDE
+    let altitudes = checked_index((point.z / CHUNK_ALTITUDE).round())?;
+    // -1 at the bottom of the strip, 1 at the top
+    let v = (point.z - altitudes as f64 * CHUNK_ALTITUDE) / CHUNK_HALFALT;
+    let u = point.x / CHUNK_HALFSIDE;
+
+    // the point is between the centers of two neighbouring chunks of opposite parity
+    let halfsides = checked_index(u.floor())?;
+    let even = (halfsides as i64 + altitudes as i64).rem_euclid(2) == 0;
+    // even chunks have their base at the top of the strip, odd chunks at the bottom
+    let half_width = if even { (1.0 + v) / 2.0 } else { (1.0 - v) / 2.0 };
+    if u - halfsides as f64 <= half_width {
+        return Ok((halfsides, altitudes));
+    }
+    return Ok((halfsides.checked_add(1).ok_or(TriCoordOverflow)?, altitudes));
+}
+
+pub fn coord_to_trichunk(point: Coord<f64>) -> Result<TriCoord<i32>, TriCoordOverflow> {
+    let (halfsides, altitudes) = coord_to_halfsides_altitude(point)?;
+    halfsides_altitude_to_tricoord(halfsides, altitudes)
+}
+
+
+// the chunk tiling scaled up around the apex of chunk (0,0,0) by a whole number lines up with the chunk borders,
+// so every big triangle holds exactly scale * scale chunks
+const PARENT_PIVOT_Z:f64 = -CHUNK_HALFALT;
+
+// the tricoord of the scale times bigger triangle a chunk is part of, counted in big triangles
+pub fn trichunk_parent(tricoord: TriCoord<i32>, scale: i32) -> Result<TriCoord<i32>, TriCoordOverflow> {
+    let center = trichunk_to_coord(tricoord, 0);
+    // the centroid is a third of the half altitude from the center towards the base, safely inside the chunk
+    let centroid_z = if tricoord.is_odd() { center.z - CHUNK_HALFALT / 3.0 } else { center.z + CHUNK_HALFALT / 3.0 };
+
+    coord_to_trichunk(Coord {
+        x: center.x / scale as f64,
+        z: PARENT_PIVOT_Z + (centroid_z - PARENT_PIVOT_Z) / scale as f64
+    })
+}
+
+// world position of a parent triangle, the scaled up equivalent of trichunk_to_coord
+pub fn trichunk_parent_to_coord(parent: TriCoord<i32>, scale: i32) -> Coord<f64> {
+    let center = trichunk_to_coord(parent, 0);
+    Coord {
+        x: center.x * scale as f64,
+        z: PARENT_PIVOT_Z + (center.z - PARENT_PIVOT_Z) * scale as f64
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    const SUPER_SCALE:i32 = 4;
+
+    // a point safely inside the chunk, a third of the half altitude from the center towards the base
+    fn centroid(tricoord: TriCoord<i32>) -> Coord<f64> {
+        let center = trichunk_to_coord(tricoord, 0);
+        let z = if tricoord.is_odd() { center.z - CHUNK_HALFALT / 3.0 } else { center.z + CHUNK_HALFALT / 3.0 };
+        Coord { x: center.x, z }
+    }
+
+    #[test]
+    fn try_cast_keeps_coordinates_that_fit() {
+        let tricoord = TriCoord { a: -32768, b: 32767, c: 1 };
+        assert_eq!(tricoord.try_cast::<i16>(), Ok(TriCoord { a: i16::MIN, b: i16::MAX, c: 1 }));
+        assert_eq!(TriCoord::<i16> { a: -3, b: 2, c: 1 }.try_cast::<i32>(), Ok(TriCoord { a: -3, b: 2, c: 1 }));
+    }
+
+    #[test]
+    fn try_cast_refuses_coordinates_that_do_not_fit() {
+        for tricoord in [TriCoord { a: 32768, b: 0, c: 0 }, TriCoord { a: 0, b: -32769, c: 0 }, TriCoord { a: 0, b: 0, c: i32::MAX }] {
+            assert_eq!(tricoord.try_cast::<i16>(), Err(TriCoordOverflow));
+        }
+        assert_eq!(TriCoord { a: -1, b: 0, c: 0 }.try_cast::<u32>(), Err(TriCoordOverflow));
+    }
+
+    #[test]
+    fn checked_index_refuses_what_as_would_saturate() {
+        assert_eq!(checked_index(-12.0), Ok(-12));
+        assert_eq!(checked_index(i32::MAX as f64), Ok(i32::MAX));
+        assert_eq!(checked_index(i32::MIN as f64), Ok(i32::MIN));
+        for value in [i32::MAX as f64 + 1.0, i32::MIN as f64 - 1.0, f64::INFINITY, f64::NEG_INFINITY, f64::NAN] {
+            assert_eq!(checked_index(value), Err(TriCoordOverflow), "{}", value);
+        }
+    }
+
+    #[test]
+    fn coord_to_trichunk_round_trips() {
+        for halfsides in -40..40 {
+            for altitudes in -40..40 {
+                let tricoord = halfsides_altitude_to_tricoord(halfsides, altitudes).unwrap();
+                assert!(tricoord.sum() == 0 || tricoord.sum() == 1);
+                assert_eq!(coord_to_trichunk(centroid(tricoord)), Ok(tricoord));
+            }
+        }
+    }
+
+    #[test]
+    fn coord_to_trichunk_round_trips_far_out() {
+        // a few million chunks away, where an i16 coordinate would have wrapped long ago
+        for (halfsides, altitudes) in [(9_000_001, -4_000_000), (-9_000_000, 4_000_001), (1 << 30, 1 << 29)] {
+            let tricoord = halfsides_altitude_to_tricoord(halfsides, altitudes).unwrap();
+            assert_eq!(coord_to_trichunk(centroid(tricoord)), Ok(tricoord));
+        }
+    }
+
+    #[test]
+    fn parents_hold_scale_squared_chunks() {
+        let mut members: HashMap<TriCoord<i32>, usize> = HashMap::new();
+        for halfsides in -40..40 {
+            for altitudes in -40..40 {
+                let tricoord = halfsides_altitude_to_tricoord(halfsides, altitudes).unwrap();
+                *members.entry(trichunk_parent(tricoord, SUPER_SCALE).unwrap()).or_default() += 1;
+            }
+        }
+        // the parents at the edge of the sampled area are only partly inside it
+        let full = members.values().filter(|count| **count == (SUPER_SCALE * SUPER_SCALE) as usize).count();
+        assert!(full > 0);
+        assert!(members.values().all(|count| *count <= (SUPER_SCALE * SUPER_SCALE) as usize));
+    }
+
+    #[test]
+    fn out_of_range_coordinates_overflow() {
+        // c comes out at 2^31
+        assert_eq!(halfsides_altitude_to_tricoord(i32::MAX, i32::MIN), Err(TriCoordOverflow));
+        assert_eq!(coord_to_trichunk(Coord { x: 1e12, z: 0.0 }), Err(TriCoordOverflow));
+        assert_eq!(coord_to_trichunk(Coord { x: 0.0, z: f64::NAN }), Err(TriCoordOverflow));
+        assert_eq!(tricoord_vec_gen_distance(Coord { x: 1e12, z: 0.0 }, 20.0), Err(TriCoordOverflow));
+        assert_eq!(tricoord_vec_gen_distance(Coord { x: 0.0, z: -1e13 }, 20.0), Err(TriCoordOverflow));
+        // a parent is never further out than its chunk, so the parents of the outermost chunks still fit
+        assert!(trichunk_parent(TriCoord { a: i32::MIN + 1, b: i32::MAX, c: 0 }, SUPER_SCALE).is_ok());
+    }
+
+    #[test]
+    fn sums_do_not_overflow() {
+        let tricoord = TriCoord { a: i32::MAX, b: i32::MAX, c: i32::MIN };
+        assert_eq!(tricoord.sum(), i32::MAX as i64 - 1);
+        assert!(tricoord.is_odd());
+        assert!(!TriCoord { a: i32::MIN, b: i32::MAX, c: 1 }.is_odd());
+    }
+}
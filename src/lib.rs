@@ -0,0 +1,4 @@
+// the game and the tools share everything but their entry points, the game is src/main.rs and
+// the headless world generator is src/bin/worldgen.rs
+pub mod ingame;
+pub mod debug;
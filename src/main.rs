@@ -3,8 +3,7 @@
 
 use bevy::asset::AssetMetaCheck;
 
-mod ingame;
-mod debug;
+use bevy_game::{debug, ingame};
 
 use std::f32::consts::TAU;
 
@@ -16,7 +15,8 @@ use bevy_rapier3d::prelude::*;
 
 use bevy_fps_controller::controller::*;
 
-use ingame::{environment::EnvironmentPlugin};
+use ingame::environment::{ground_material::GroundContact, terrain::TerrainAnchor, EnvironmentPlugin};
+use ingame::floating_origin::{AbsolutePosition, FloatingOriginFocus, FloatingOriginPlugin};
 use debug::DebugPlugin;
 
 use bevy_shader_utils::ShaderUtilsPlugin;
@@ -47,6 +47,7 @@ fn main() {
         .add_plugins(DebugPlugin)
 
         .add_plugins(EnvironmentPlugin)
+        .add_plugins(FloatingOriginPlugin)
         .add_plugins(RapierPhysicsPlugin::<NoUserData>::default())
 
         .add_plugins(FpsControllerPlugin)
@@ -103,6 +104,7 @@ fn setup(
     .insert(CameraConfig {
         height_offset: -0.5
     })
+    .insert((TerrainAnchor::default(), FloatingOriginFocus, AbsolutePosition::default(), GroundContact::default()))
     .insert(Name::new("LogicalPlayer"))
     .id();
 